@@ -1,7 +1,10 @@
 use std::io::Read;
+use std::time::UNIX_EPOCH;
 
-use courier_ql::exec::{Executor, StepParsedOutput};
-use courier_ql::{Plan, StepBody};
+use courier_ql::exec::{
+    Direction, Error, Executor, GraphQLPathSegment, ParsedBody, Plugins, StepParsedOutput,
+};
+use courier_ql::Plan;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
@@ -13,15 +16,120 @@ async fn main() -> Result<()> {
 
     handle.read_to_end(&mut buffer)?;
 
-    let text = String::from_utf8(buffer)?;
+    let text = String::from_utf8(buffer).map_err(|e| {
+        format!(
+            "the plan isn't UTF-8 at byte {}: write other bytes in an escaped, base64, hex or file body",
+            e.utf8_error().valid_up_to()
+        )
+    })?;
     {
         let plan = Plan::parse(&text)?;
         let mut executor = Executor::new(&plan);
-        for step in &plan.steps {
-            println!("executing step {}...", step.name.unwrap_or("unnamed"));
-            let output = executor.next().await?;
+        // Plugins are the executables in the directories listed in COURIER_PLUGIN_PATH.
+        if let Some(path) = std::env::var_os("COURIER_PLUGIN_PATH") {
+            let plugins = Plugins::discover(std::env::split_paths(&path))?;
+            for (path, reason) in plugins.skipped() {
+                eprintln!("skipped plugin {}: {}", path.display(), reason);
+            }
+            executor.set_plugins(plugins);
+        }
+        let mut failed = 0;
+        while !executor.is_done() {
+            if let Some(step) = executor.current_step() {
+                println!("executing step {}...", step.name.unwrap_or("unnamed"));
+            }
+            let output = match executor.next().await {
+                Ok(output) => output,
+                Err(e) => match e.downcast_ref::<Error>() {
+                    // Keep going so one run reports every failed expectation.
+                    Some(Error::Expectation(_)) => {
+                        println!("{}", e);
+                        failed += 1;
+                        continue;
+                    }
+                    // The plan ended with expectations that held.
+                    Some(Error::Done) => break,
+                    _ => return Err(e),
+                },
+            };
             println!("> {}", String::from_utf8_lossy(&output.raw_request));
             println!("< {}", String::from_utf8_lossy(&output.raw_response));
+            println!("duration: {:?}", output.duration);
+            if let Some(tls) = output.tls {
+                println!("tls:");
+                println!("    version: {}", tls.version);
+                println!("    cipher suite: {}", tls.cipher_suite);
+                println!(
+                    "    alpn: {}",
+                    tls.alpn
+                        .map(|alpn| String::from_utf8_lossy(&alpn).into_owned())
+                        .unwrap_or("<none>".to_string())
+                );
+                println!("    sni: {}", tls.sni.unwrap_or("<none>".to_string()));
+                println!("    resumed: {}", tls.resumed);
+                println!("    server certificates:");
+                for cert in tls.server_certificates {
+                    for line in cert.pem().lines() {
+                        println!("        {}", line);
+                    }
+                }
+            }
+            if let Some(http2) = output.http2 {
+                println!("http2 frames:");
+                let sent = http2.sent.into_iter().map(|frame| ('>', frame));
+                let received = http2.received.into_iter().map(|frame| ('<', frame));
+                for (direction, frame) in sent.chain(received) {
+                    println!(
+                        "    {} {} stream={} flags={:#04x} length={}",
+                        direction,
+                        frame.kind,
+                        frame.stream_id,
+                        frame.flags,
+                        frame.payload.len()
+                    );
+                }
+            }
+            if let Some(connection) = output.connection {
+                println!(
+                    "connection: {} request {} ({} -> {}){}",
+                    connection.name,
+                    connection.sequence,
+                    connection.local_addr,
+                    connection.peer_addr,
+                    if connection.pipelined {
+                        ", pipelined"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            if let Some(quic) = output.quic {
+                println!("quic:");
+                println!("    remote address: {}", quic.remote_address);
+                println!(
+                    "    alpn: {}",
+                    quic.alpn
+                        .map(|alpn| String::from_utf8_lossy(&alpn).into_owned())
+                        .unwrap_or("<none>".to_string())
+                );
+                println!("    sni: {}", quic.sni.unwrap_or("<none>".to_string()));
+                println!("    rtt: {:?}", quic.rtt);
+                println!(
+                    "    server certificates: {}",
+                    quic.server_certificates.len()
+                );
+                println!("    streams:");
+                for stream in quic.streams {
+                    println!(
+                        "        {} {} {}: > {} bytes, < {} bytes",
+                        stream.id,
+                        stream.initiator,
+                        if stream.bidirectional { "bidi" } else { "uni" },
+                        stream.sent.len(),
+                        stream.received.len()
+                    );
+                }
+            }
             match output.parsed {
                 StepParsedOutput::HTTP(parsed) => {
                     println!("version: {}", parsed.version);
@@ -32,12 +140,174 @@ async fn main() -> Result<()> {
                             "    {}: {}",
                             k.map(|h| h.as_str().to_owned())
                                 .unwrap_or("<missing>".to_string()),
-                            v.to_str().unwrap()
+                            String::from_utf8_lossy(v.as_bytes())
                         );
                     }
+                    if !parsed.chunks.is_empty() {
+                        println!("chunks:");
+                        for chunk in &parsed.chunks {
+                            println!(
+                                "    {:X}{}{}: {} bytes",
+                                chunk.size,
+                                if chunk.extensions.is_empty() { "" } else { ";" },
+                                String::from_utf8_lossy(&chunk.extensions),
+                                chunk.data.len()
+                            );
+                        }
+                    }
+                    if !parsed.trailers.is_empty() {
+                        println!("trailers:");
+                        for (k, v) in &parsed.trailers {
+                            println!("    {}: {}", k, String::from_utf8_lossy(v.as_bytes()));
+                        }
+                    }
+                    if let Some(e) = parsed.decode_error {
+                        println!("body not decoded: {}", e);
+                    }
+                    if let Some(raw_body) = parsed.raw_body {
+                        println!(
+                            "decoded body: {} bytes from {}",
+                            parsed.body.len(),
+                            raw_body.len()
+                        );
+                    }
+                    match parsed.parsed_body {
+                        Some(ParsedBody::JSON(json)) => println!("json: {}", json),
+                        Some(ParsedBody::Form(fields)) => {
+                            println!("form:");
+                            for (k, v) in fields {
+                                println!("    {}={}", k, v);
+                            }
+                        }
+                        Some(ParsedBody::Multipart(parts)) => {
+                            println!("parts:");
+                            for part in parts {
+                                println!(
+                                    "    {} bytes, {}",
+                                    part.body.len(),
+                                    part.headers
+                                        .get("content-disposition")
+                                        .and_then(|v| v.to_str().ok())
+                                        .unwrap_or("<no content-disposition>")
+                                );
+                            }
+                        }
+                        Some(ParsedBody::Protobuf(message)) => {
+                            println!("protobuf: {}", serde_json::to_string(&message)?);
+                        }
+                        None => {}
+                    }
+                }
+                StepParsedOutput::TCP(parsed) => {
+                    println!("read until: {}", parsed.end);
+                }
+                StepParsedOutput::UDP(parsed) => {
+                    println!("datagrams:");
+                    for datagram in parsed.datagrams {
+                        let direction = match datagram.direction {
+                            Direction::Sent => '>',
+                            Direction::Received => '<',
+                        };
+                        let time = datagram
+                            .time
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_secs_f64();
+                        println!(
+                            "    {} {} at {:.6}: {}",
+                            direction,
+                            datagram.peer,
+                            time,
+                            String::from_utf8_lossy(&datagram.payload)
+                        );
+                    }
+                    println!("read until: {}", parsed.end);
+                }
+                StepParsedOutput::WebSocket(parsed) => {
+                    println!("upgrade status: {}", parsed.upgrade.status);
+                    println!("websocket frames:");
+                    let sent = parsed.sent.into_iter().map(|frame| ('>', frame));
+                    let received = parsed.received.into_iter().map(|frame| ('<', frame));
+                    for (direction, frame) in sent.chain(received) {
+                        let mask = frame
+                            .mask
+                            .map(|mask| format!("{:08x}", u32::from_be_bytes(mask)))
+                            .unwrap_or("<none>".to_string());
+                        println!(
+                            "    {} {} fin={} mask={}: {}",
+                            direction,
+                            frame.opcode,
+                            frame.fin,
+                            mask,
+                            String::from_utf8_lossy(&frame.payload)
+                        );
+                    }
+                    if let Some(end) = parsed.end {
+                        println!("read until: {}", end);
+                    }
+                }
+                StepParsedOutput::GRPC(parsed) => {
+                    println!("status: {}", parsed.http.status);
+                    println!(
+                        "grpc-status: {}",
+                        parsed
+                            .status
+                            .map(|status| status.to_string())
+                            .unwrap_or("<missing>".to_string())
+                    );
+                    if let Some(message) = parsed.message {
+                        println!("grpc-message: {}", message);
+                    }
+                    println!("messages:");
+                    for message in parsed.messages {
+                        println!("    {}", serde_json::to_string(&message)?);
+                    }
+                    println!("trailers:");
+                    for (k, v) in parsed.trailers.iter() {
+                        println!("    {}: {}", k, String::from_utf8_lossy(v.as_bytes()));
+                    }
+                }
+                StepParsedOutput::QUIC(end) => {
+                    println!("read until: {}", end);
+                }
+                StepParsedOutput::GraphQL(parsed) => {
+                    println!("status: {}", parsed.http.status);
+                    if let Some(data) = parsed.data {
+                        println!("data: {}", data);
+                    }
+                    if !parsed.errors.is_empty() {
+                        println!("errors:");
+                    }
+                    for error in parsed.errors {
+                        let locations: Vec<_> = error
+                            .locations
+                            .iter()
+                            .map(|l| format!("{}:{}", l.line, l.column))
+                            .collect();
+                        let path: Vec<_> = error
+                            .path
+                            .iter()
+                            .map(|segment| match segment {
+                                GraphQLPathSegment::Field(field) => field.clone(),
+                                GraphQLPathSegment::Index(i) => i.to_string(),
+                            })
+                            .collect();
+                        println!(
+                            "    {} (at {}, path {})",
+                            error.message,
+                            locations.join(", "),
+                            path.join(".")
+                        );
+                    }
+                }
+                StepParsedOutput::Pending => {
+                    println!("response: pending until a later step on the connection reads it");
                 }
             }
         }
+        if failed > 0 {
+            return Err(format!("{} expectation(s) failed", failed).into());
+        }
     }
     Ok(())
 }
@@ -0,0 +1,3 @@
+module courier-example
+
+go 1.18
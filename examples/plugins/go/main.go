@@ -0,0 +1,81 @@
+// An example plugin providing `shout(text)` and `status(step)`. Build it with `go build` and put
+// the binary in a plugin directory.
+package main
+
+import (
+	"bufio"
+	"encoding/json"
+	"fmt"
+	"os"
+	"strings"
+)
+
+type request struct {
+	Method   string                     `json:"method"`
+	Function string                     `json:"function"`
+	Args     []any                      `json:"args"`
+	Steps    map[string]json.RawMessage `json:"steps"`
+}
+
+type step struct {
+	Response *struct {
+		Status int `json:"status"`
+	} `json:"response"`
+}
+
+var functions = map[string]func(steps map[string]json.RawMessage, args []any) (any, error){
+	"shout": func(steps map[string]json.RawMessage, args []any) (any, error) {
+		if len(args) != 1 {
+			return nil, fmt.Errorf("expected 1 argument")
+		}
+		text, ok := args[0].(string)
+		if !ok {
+			return nil, fmt.Errorf("expected a string")
+		}
+		return strings.ToUpper(text) + "!", nil
+	},
+	"status": func(steps map[string]json.RawMessage, args []any) (any, error) {
+		if len(args) != 1 {
+			return nil, fmt.Errorf("expected 1 argument")
+		}
+		name, _ := args[0].(string)
+		var s step
+		if raw, ok := steps[name]; !ok || json.Unmarshal(raw, &s) != nil || s.Response == nil {
+			return nil, nil
+		}
+		return s.Response.Status, nil
+	},
+}
+
+func main() {
+	scanner := bufio.NewScanner(os.Stdin)
+	// Requests hold the outputs of earlier steps, so they can be long.
+	scanner.Buffer(nil, 1<<30)
+	encoder := json.NewEncoder(os.Stdout)
+	for scanner.Scan() {
+		var req request
+		if err := json.Unmarshal(scanner.Bytes(), &req); err != nil {
+			encoder.Encode(map[string]any{"error": err.Error()})
+			continue
+		}
+		if req.Method == "functions" {
+			names := make([]string, 0, len(functions))
+			for name := range functions {
+				names = append(names, name)
+			}
+			encoder.Encode(map[string]any{"functions": names})
+			continue
+		}
+		function, ok := functions[req.Function]
+		if !ok {
+			encoder.Encode(map[string]any{"error": "unknown function"})
+			continue
+		}
+		result, err := function(req.Steps, req.Args)
+		if err != nil {
+			encoder.Encode(map[string]any{"error": err.Error()})
+		} else {
+			encoder.Encode(map[string]any{"result": result})
+		}
+	}
+}
@@ -0,0 +1,25 @@
+#!/usr/bin/env node
+// An example plugin providing `shout(text)` and `status(step)`.
+
+const readline = require("readline");
+
+const functions = {
+  shout: (steps, text) => `${text.toUpperCase()}!`,
+  status: (steps, step) => steps[step]?.response?.status ?? null,
+};
+
+readline.createInterface({ input: process.stdin }).on("line", (line) => {
+  const request = JSON.parse(line);
+  let response;
+  if (request.method === "functions") {
+    response = { functions: Object.keys(functions) };
+  } else {
+    try {
+      const result = functions[request.function](request.steps, ...request.args);
+      response = { result };
+    } catch (e) {
+      response = { error: String(e) };
+    }
+  }
+  process.stdout.write(JSON.stringify(response) + "\n");
+});
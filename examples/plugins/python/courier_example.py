@@ -0,0 +1,29 @@
+#!/usr/bin/env python3
+"""An example plugin providing `shout(text)` and `status(step)`."""
+
+import json
+import sys
+
+
+def shout(steps, text):
+    return text.upper() + "!"
+
+
+def status(steps, step):
+    response = steps.get(step, {}).get("response")
+    return response["status"] if response else None
+
+
+FUNCTIONS = {"shout": shout, "status": status}
+
+for line in sys.stdin:
+    request = json.loads(line)
+    if request["method"] == "functions":
+        response = {"functions": list(FUNCTIONS)}
+    else:
+        try:
+            function = FUNCTIONS[request["function"]]
+            response = {"result": function(request["steps"], *request["args"])}
+        except Exception as e:
+            response = {"error": str(e)}
+    print(json.dumps(response), flush=True)
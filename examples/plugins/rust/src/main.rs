@@ -0,0 +1,37 @@
+//! An example plugin providing `shout(text)` and `status(step)`. Build it with `cargo build` and
+//! put the binary in a plugin directory.
+
+use std::io::{BufRead, Write};
+
+use serde_json::{json, Value};
+
+fn call(function: &str, args: &[Value], steps: &Value) -> Result<Value, String> {
+    match (function, args) {
+        ("shout", [Value::String(text)]) => Ok(json!(format!("{}!", text.to_uppercase()))),
+        ("status", [Value::String(step)]) => Ok(steps[step]["response"]["status"].clone()),
+        ("shout" | "status", _) => Err("expected a string".to_owned()),
+        _ => Err("unknown function".to_owned()),
+    }
+}
+
+fn main() {
+    let mut stdout = std::io::stdout().lock();
+    for line in std::io::stdin().lock().lines() {
+        let request: Value = serde_json::from_str(&line.unwrap()).unwrap();
+        let response = if request["method"] == "functions" {
+            json!({"functions": ["shout", "status"]})
+        } else {
+            let args = request["args"].as_array().map_or(&[][..], Vec::as_slice);
+            match call(
+                request["function"].as_str().unwrap_or_default(),
+                args,
+                &request["steps"],
+            ) {
+                Ok(result) => json!({ "result": result }),
+                Err(error) => json!({ "error": error }),
+            }
+        };
+        writeln!(stdout, "{}", response).unwrap();
+        stdout.flush().unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+use std::time::Duration;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_till1},
+    character::complete::{char, line_ending, not_line_ending, space0},
+    combinator::{all_consuming, map, opt, rest},
+    multi::many_till,
+    sequence::{delimited, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+use crate::http::header;
+use crate::tls::option_error;
+use crate::util::{duration, options};
+use crate::{Payload, PayloadFormat, Template};
+
+/// How a request body is written in the plan, chosen with the `body` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    /// Bytes written as text, or in one of the binary formats.
+    Payload(PayloadFormat),
+    JSON,
+    Form,
+    Multipart,
+    Protobuf,
+    Chunked,
+}
+
+impl Default for BodyFormat {
+    fn default() -> Self {
+        Self::Payload(PayloadFormat::Raw)
+    }
+}
+
+impl BodyFormat {
+    pub fn parse(input: &str) -> Option<Self> {
+        if let Some(format) = PayloadFormat::parse(input) {
+            return Some(Self::Payload(format));
+        }
+        match input {
+            "json" => Some(Self::JSON),
+            "form" => Some(Self::Form),
+            "multipart" => Some(Self::Multipart),
+            "protobuf" => Some(Self::Protobuf),
+            "chunked" => Some(Self::Chunked),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HTTPBody<'a> {
+    Payload(Payload<'a>),
+    /// A JSON document, which is checked and pretty-printed before sending.
+    JSON(Template<'a>),
+    /// Fields written one per line as `key=value`, sent URL-encoded.
+    Form(Vec<(&'a str, Template<'a>)>),
+    Multipart(Vec<MultipartPart<'a>>),
+    /// A message in the protobuf text format, sent in the binary encoding.
+    Protobuf(Template<'a>),
+    /// Chunks sent with the chunked transfer coding.
+    Chunked(ChunkedBody<'a>),
+}
+
+impl Default for HTTPBody<'_> {
+    fn default() -> Self {
+        Self::Payload(Payload::default())
+    }
+}
+
+impl<'a> HTTPBody<'a> {
+    pub fn parse(input: &'a str, format: BodyFormat) -> IResult<&'a str, Self> {
+        let body = match format {
+            BodyFormat::Payload(format) => Self::Payload(Payload::parse(input, format)?),
+            BodyFormat::JSON => {
+                let template = Template::parse(input)?.1;
+                if !template.validate_literal(|literal| {
+                    serde_json::from_str::<serde_json::Value>(literal).is_ok()
+                }) {
+                    return Err(invalid(input));
+                }
+                Self::JSON(template)
+            }
+            BodyFormat::Form => Self::Form(
+                input
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(|line| {
+                        let (_, (key, val)) = all_consuming(separated_pair(
+                            take_till1(|c| c == '='),
+                            tag("="),
+                            rest,
+                        ))(line)?;
+                        Ok((key, Template::parse(val)?.1))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            BodyFormat::Multipart => Self::Multipart(
+                split_parts(input)?
+                    .into_iter()
+                    .map(|part| Ok(all_consuming(MultipartPart::parse)(part)?.1))
+                    .collect::<Result<_, _>>()?,
+            ),
+            BodyFormat::Protobuf => Self::Protobuf(Template::parse(input)?.1),
+            BodyFormat::Chunked => Self::Chunked(ChunkedBody::parse(input)?),
+        };
+        Ok(("", body))
+    }
+}
+
+/// A part of a multipart body, written as a `part <name>` line followed by its headers, a blank
+/// line and its content. Content made of a single `< path` line is read from that file.
+#[derive(Debug, PartialEq)]
+pub struct MultipartPart<'a> {
+    pub name: &'a str,
+    /// Headers for the part. A `Content-Disposition` header is generated unless one is set here.
+    pub headers: Vec<(&'a str, Template<'a>)>,
+    pub content: PartContent<'a>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PartContent<'a> {
+    Text(Template<'a>),
+    /// The path of a file to send, whose name is used as the part's filename.
+    File(Template<'a>),
+}
+
+impl<'a> MultipartPart<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        let (input, name) =
+            delimited(tag("part "), preceded(space0, not_line_ending), line_ending)(input)?;
+        let (content, (headers, _)) =
+            many_till(terminated(header, line_ending), line_ending)(input)?;
+        let content = match content.strip_prefix("< ") {
+            Some(path) if !path.contains('\n') => PartContent::File(Template::parse(path)?.1),
+            _ => PartContent::Text(Template::parse(content)?.1),
+        };
+        Ok((
+            "",
+            MultipartPart {
+                name: name.trim_end(),
+                headers: headers
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, Template::parse(v)?.1)))
+                    .collect::<Result<_, _>>()?,
+                content,
+            },
+        ))
+    }
+}
+
+/// A body written one chunk per line as `chunk <data>`, with any trailers as
+/// `trailer <name>: <value>` lines. Chunks can take options in parentheses after `chunk`.
+#[derive(Debug, PartialEq)]
+pub struct ChunkedBody<'a> {
+    pub chunks: Vec<Chunk<'a>>,
+    pub trailers: Vec<(&'a str, Template<'a>)>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Chunk<'a> {
+    /// The chunk's data, which may use the same escapes as `udp` datagrams. A chunk without data
+    /// is a last chunk, which is added after the others if the body doesn't end with one.
+    pub data: Template<'a>,
+    /// Sent as the chunk size instead of the data's length in hex, set with the `size` option.
+    pub size: Option<&'a str>,
+    /// Sent after the size and a `;`, set with the `ext` option.
+    pub extensions: Option<&'a str>,
+    /// How long to wait before sending the chunk, set with the `delay` option.
+    pub delay: Option<Duration>,
+}
+
+enum ChunkLine<'a> {
+    Chunk(Chunk<'a>),
+    Trailer(&'a str, &'a str),
+}
+
+impl<'a> ChunkedBody<'a> {
+    pub fn parse(input: &'a str) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut chunks = Vec::new();
+        let mut trailers = Vec::new();
+        for line in input.lines().filter(|line| !line.is_empty()) {
+            match all_consuming(chunk_line)(line)?.1 {
+                ChunkLine::Chunk(chunk) => chunks.push(chunk),
+                ChunkLine::Trailer(key, val) => trailers.push((key, Template::parse(val)?.1)),
+            }
+        }
+        Ok(Self { chunks, trailers })
+    }
+}
+
+fn chunk_line(input: &str) -> IResult<&str, ChunkLine<'_>> {
+    alt((
+        map(preceded(tag("trailer "), header), |(key, val)| {
+            ChunkLine::Trailer(key, val)
+        }),
+        |input| {
+            let (input, (_, options, data)) =
+                tuple((tag("chunk"), opt(options), opt(preceded(char(' '), rest))))(input)?;
+            let mut chunk = Chunk {
+                data: Template::parse_escaped(data.unwrap_or_default())?.1,
+                ..Chunk::default()
+            };
+            for (key, val) in options.unwrap_or_default() {
+                match key {
+                    "size" => chunk.size = Some(val),
+                    "ext" => chunk.extensions = Some(val),
+                    "delay" => chunk.delay = Some(duration(val).ok_or_else(|| option_error(val))?),
+                    _ => return Err(option_error(key)),
+                }
+            }
+            Ok((input, ChunkLine::Chunk(chunk)))
+        },
+    ))(input)
+}
+
+fn invalid(input: &str) -> nom::Err<nom::error::Error<&str>> {
+    nom::Err::Error(nom::error::Error {
+        input,
+        code: nom::error::ErrorKind::Verify,
+    })
+}
+
+/// Split a multipart body at each line starting with `part `. The line ending before each `part`
+/// line separates the parts, so it isn't included in the content.
+fn split_parts(input: &str) -> Result<Vec<&str>, nom::Err<nom::error::Error<&str>>> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    for line in input.split_inclusive('\n') {
+        if line.starts_with("part ") {
+            starts.push(offset);
+        }
+        offset += line.len();
+    }
+    // Nothing but blank lines may come before the first part.
+    match starts.first() {
+        Some(&start) if input[..start].trim().is_empty() => {}
+        None if input.trim().is_empty() => {}
+        _ => return Err(invalid(input)),
+    }
+    Ok(starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let part = &input[start..starts.get(i + 1).copied().unwrap_or(input.len())];
+            if i + 1 < starts.len() {
+                part.strip_suffix("\r\n")
+                    .or_else(|| part.strip_suffix('\n'))
+                    .unwrap_or(part)
+            } else {
+                part
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_body_test() {
+        assert_eq!(
+            HTTPBody::parse("{\"id\": ${login.response.body}}", BodyFormat::JSON),
+            Ok((
+                "",
+                HTTPBody::JSON(
+                    Template::parse("{\"id\": ${login.response.body}}")
+                        .unwrap()
+                        .1
+                )
+            ))
+        );
+        assert!(HTTPBody::parse("{\"id\": }", BodyFormat::JSON).is_err());
+    }
+
+    #[test]
+    fn form_body_test() {
+        assert_eq!(
+            HTTPBody::parse(
+                "name=ann lee\n\ntoken=${login.response.body}\nempty=",
+                BodyFormat::Form
+            ),
+            Ok((
+                "",
+                HTTPBody::Form(vec![
+                    ("name", Template::literal("ann lee")),
+                    (
+                        "token",
+                        Template::parse("${login.response.body}").unwrap().1
+                    ),
+                    ("empty", Template::default()),
+                ])
+            ))
+        );
+        assert!(HTTPBody::parse("name", BodyFormat::Form).is_err());
+        assert!(HTTPBody::parse("=value", BodyFormat::Form).is_err());
+    }
+
+    #[test]
+    fn multipart_body_test() {
+        assert_eq!(
+            HTTPBody::parse(
+                "\npart title\n\nhello\nworld\npart photo\nContent-Type: image/png\n\n< ./photo.png\npart empty\n\n",
+                BodyFormat::Multipart
+            ),
+            Ok((
+                "",
+                HTTPBody::Multipart(vec![
+                    MultipartPart {
+                        name: "title",
+                        headers: Vec::new(),
+                        content: PartContent::Text(Template::literal("hello\nworld")),
+                    },
+                    MultipartPart {
+                        name: "photo",
+                        headers: vec![("Content-Type", Template::literal("image/png"))],
+                        content: PartContent::File(Template::literal("./photo.png")),
+                    },
+                    MultipartPart {
+                        name: "empty",
+                        headers: Vec::new(),
+                        content: PartContent::Text(Template::default()),
+                    },
+                ])
+            ))
+        );
+        assert!(HTTPBody::parse("stray\npart a\n\n", BodyFormat::Multipart).is_err());
+        // Parts need a blank line after their headers.
+        assert!(HTTPBody::parse("part a\nhello", BodyFormat::Multipart).is_err());
+    }
+
+    #[test]
+    fn chunked_body_test() {
+        assert_eq!(
+            HTTPBody::parse(
+                "chunk hello\\r\n\nchunk(size=ff, ext=a=1, delay=1s) ${x}\nchunk\ntrailer X-Sum: 5",
+                BodyFormat::Chunked
+            ),
+            Ok((
+                "",
+                HTTPBody::Chunked(ChunkedBody {
+                    chunks: vec![
+                        Chunk {
+                            data: Template::parse_escaped("hello\\r").unwrap().1,
+                            ..Chunk::default()
+                        },
+                        Chunk {
+                            data: Template::parse_escaped("${x}").unwrap().1,
+                            size: Some("ff"),
+                            extensions: Some("a=1"),
+                            delay: Some(Duration::from_secs(1)),
+                        },
+                        Chunk::default(),
+                    ],
+                    trailers: vec![("X-Sum", Template::literal("5"))],
+                })
+            ))
+        );
+        assert!(HTTPBody::parse("chunk(speed=1) a", BodyFormat::Chunked).is_err());
+        assert!(HTTPBody::parse("chunk(delay=soon) a", BodyFormat::Chunked).is_err());
+        assert!(HTTPBody::parse("chunks a", BodyFormat::Chunked).is_err());
+    }
+}
@@ -0,0 +1,551 @@
+use std::path::Path;
+use std::time::Duration;
+
+use hyper::header::{HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::HeaderMap;
+use prost::Message;
+use prost_reflect::{DynamicMessage, MessageDescriptor};
+use ring::rand::SecureRandom;
+
+use super::graphql::percent_encode;
+use super::grpc::{load_descriptors, percent_decode};
+use super::{expr, StepInputs};
+use crate::{
+    decode_base64, decode_hex, Charset, ChunkedBody, HTTPBody, HTTPOptions, PartContent, Payload,
+};
+
+/// A response body read according to its `Content-Type`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParsedBody {
+    JSON(serde_json::Value),
+    /// The fields of a URL-encoded form, in order.
+    Form(Vec<(String, String)>),
+    Multipart(Vec<BodyPart>),
+    /// A message decoded as the step's `response_message` type.
+    Protobuf(DynamicMessage),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyPart {
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+/// A serialized request body.
+#[derive(Debug, Default)]
+pub(super) struct RenderedBody {
+    pub bytes: Vec<u8>,
+    /// The `Content-Type` the body should be sent with.
+    pub content_type: Option<String>,
+    /// For bodies written as chunks, which are already in the chunked encoding, where to pause
+    /// while sending: each pause is an offset into the bytes and how long to wait before sending
+    /// them.
+    pub chunked: Option<Vec<(usize, Duration)>>,
+}
+
+impl RenderedBody {
+    fn new(bytes: Vec<u8>, content_type: Option<&str>) -> Self {
+        Self {
+            bytes,
+            content_type: content_type.map(str::to_owned),
+            chunked: None,
+        }
+    }
+}
+
+/// Serialize a request body. Multipart bodies use the boundary from the step's own
+/// `Content-Type` header if it has one.
+pub(super) fn render(
+    body: &HTTPBody,
+    options: &HTTPOptions,
+    headers: &HeaderMap,
+    inputs: &StepInputs,
+) -> Result<RenderedBody, Box<dyn std::error::Error + Send + Sync>> {
+    match body {
+        HTTPBody::Payload(payload) => Ok(RenderedBody::new(
+            render_payload(payload, options.charset, inputs)?,
+            None,
+        )),
+        HTTPBody::JSON(template) => {
+            let json: serde_json::Value =
+                serde_json::from_slice(&expr::render(template, inputs.previous)?)?;
+            Ok(RenderedBody::new(
+                serde_json::to_vec_pretty(&json)?,
+                Some("application/json"),
+            ))
+        }
+        HTTPBody::Form(fields) => {
+            let mut out = String::new();
+            for (i, (key, val)) in fields.iter().enumerate() {
+                if i > 0 {
+                    out.push('&');
+                }
+                let val = String::from_utf8(expr::render(val, inputs.previous)?)?;
+                out.push_str(&percent_encode(key));
+                out.push('=');
+                out.push_str(&percent_encode(&val));
+            }
+            Ok(RenderedBody::new(
+                out.into_bytes(),
+                Some("application/x-www-form-urlencoded"),
+            ))
+        }
+        HTTPBody::Multipart(parts) => {
+            let boundary = match content_type_parameter(headers, "boundary") {
+                Some(boundary) => boundary.to_owned(),
+                None => generate_boundary()?,
+            };
+            let mut out = Vec::new();
+            for part in parts {
+                out.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+                let (content, filename) = match &part.content {
+                    PartContent::Text(template) => (expr::render(template, inputs.previous)?, None),
+                    PartContent::File(path) => {
+                        let path = String::from_utf8(expr::render(path, inputs.previous)?)?;
+                        let filename = Path::new(&path)
+                            .file_name()
+                            .map(|name| name.to_string_lossy().into_owned());
+                        (std::fs::read(&path)?, filename)
+                    }
+                };
+                let has_disposition = part
+                    .headers
+                    .iter()
+                    .any(|(k, _)| CONTENT_DISPOSITION.as_str().eq_ignore_ascii_case(k));
+                if !has_disposition {
+                    out.extend_from_slice(
+                        format!("Content-Disposition: form-data; name=\"{}\"", part.name)
+                            .as_bytes(),
+                    );
+                    if let Some(filename) = filename {
+                        out.extend_from_slice(format!("; filename=\"{}\"", filename).as_bytes());
+                    }
+                    out.extend_from_slice(b"\r\n");
+                }
+                for (key, val) in &part.headers {
+                    out.extend_from_slice(key.as_bytes());
+                    out.extend_from_slice(b": ");
+                    out.extend(expr::render(val, inputs.previous)?);
+                    out.extend_from_slice(b"\r\n");
+                }
+                out.extend_from_slice(b"\r\n");
+                out.extend(content);
+                out.extend_from_slice(b"\r\n");
+            }
+            out.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+            Ok(RenderedBody::new(
+                out,
+                Some(&format!("multipart/form-data; boundary={}", boundary)),
+            ))
+        }
+        HTTPBody::Protobuf(template) => {
+            let text = String::from_utf8(expr::render(template, inputs.previous)?)?;
+            let descriptor = message_descriptor(options, options.message.unwrap_or_default())?;
+            let message = DynamicMessage::parse_text_format(descriptor, &text)?;
+            Ok(RenderedBody::new(
+                message.encode_to_vec(),
+                Some("application/x-protobuf"),
+            ))
+        }
+        HTTPBody::Chunked(body) => render_chunked(body, inputs),
+    }
+}
+
+/// Encode a body written as chunks. Each chunk's data is followed by a line ending, except for a
+/// last chunk at the end of the body, which is followed by the trailers and a blank line.
+fn render_chunked(
+    body: &ChunkedBody,
+    inputs: &StepInputs,
+) -> Result<RenderedBody, Box<dyn std::error::Error + Send + Sync>> {
+    let mut out = Vec::new();
+    let mut pauses = Vec::new();
+    let mut ended = false;
+    for (i, chunk) in body.chunks.iter().enumerate() {
+        let data = expr::render_escaped(&chunk.data, inputs.previous)?;
+        if let Some(delay) = chunk.delay {
+            pauses.push((out.len(), delay));
+        }
+        match chunk.size {
+            Some(size) => out.extend_from_slice(size.as_bytes()),
+            None => out.extend_from_slice(format!("{:X}", data.len()).as_bytes()),
+        }
+        if let Some(extensions) = chunk.extensions {
+            out.push(b';');
+            out.extend_from_slice(extensions.as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        ended = data.is_empty() && i == body.chunks.len() - 1;
+        if !ended {
+            out.extend(data);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    if !ended {
+        out.extend_from_slice(b"0\r\n");
+    }
+    for (key, val) in &body.trailers {
+        out.extend_from_slice(key.as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend(expr::render(val, inputs.previous)?);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    Ok(RenderedBody {
+        bytes: out,
+        content_type: None,
+        chunked: Some(pauses),
+    })
+}
+
+/// Get the bytes of a payload, converting raw text to the charset if one is given.
+pub(super) fn render_payload(
+    payload: &Payload,
+    charset: Option<Charset>,
+    inputs: &StepInputs,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let text = |template| -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(String::from_utf8(expr::render(template, inputs.previous)?)?)
+    };
+    match payload {
+        Payload::Raw(template) => match charset {
+            Some(charset) => Ok(charset
+                .encode(&text(template)?)
+                .ok_or("the body has characters the charset can't represent")?),
+            None => Ok(expr::render(template, inputs.previous)?),
+        },
+        Payload::Escaped(template) => Ok(expr::render_escaped(template, inputs.previous)?),
+        Payload::Base64(template) => Ok(decode_base64(&text(template)?).ok_or("invalid base64")?),
+        Payload::Hex(template) => Ok(decode_hex(&text(template)?).ok_or("invalid hex")?),
+        Payload::File(template) => Ok(std::fs::read(text(template)?)?),
+    }
+}
+
+/// Read a response body as the step's `response_message` type if it set one, or else by the
+/// media type in its `Content-Type`. Bodies in other formats, or that don't parse, get no view.
+pub(super) fn parse(
+    headers: &HeaderMap,
+    body: &[u8],
+    options: &HTTPOptions,
+) -> Result<Option<ParsedBody>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(message) = options.response_message {
+        let descriptor = message_descriptor(options, message)?;
+        return Ok(DynamicMessage::decode(descriptor, body)
+            .ok()
+            .map(ParsedBody::Protobuf));
+    }
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return Ok(None);
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    Ok(match media_type.as_str() {
+        "application/json" => serde_json::from_slice(body).ok().map(ParsedBody::JSON),
+        t if t.ends_with("+json") => serde_json::from_slice(body).ok().map(ParsedBody::JSON),
+        "application/x-www-form-urlencoded" => Some(ParsedBody::Form(parse_form(body))),
+        t if t.starts_with("multipart/") => content_type_parameter(headers, "boundary")
+            .and_then(|boundary| parse_multipart(body, boundary))
+            .map(ParsedBody::Multipart),
+        _ => None,
+    })
+}
+
+fn message_descriptor(
+    options: &HTTPOptions,
+    message: &str,
+) -> Result<MessageDescriptor, Box<dyn std::error::Error + Send + Sync>> {
+    load_descriptors(&options.descriptors)?
+        .get_message_by_name(message)
+        .ok_or_else(|| format!("no message named {} in the descriptors", message).into())
+}
+
+/// Get a parameter of a `Content-Type` header, like the `boundary` of a multipart body.
+fn content_type_parameter<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, val) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| val.trim().trim_matches('"'))
+    })
+}
+
+fn generate_boundary() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut bytes = [0; 12];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "couldn't generate a multipart boundary")?;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("courier-{}", hex))
+}
+
+fn parse_form(body: &[u8]) -> Vec<(String, String)> {
+    // Spaces may be written as `+`, so those are swapped back before decoding escapes.
+    let decode = |s: &[u8]| {
+        let s: Vec<u8> = s
+            .iter()
+            .map(|&b| if b == b'+' { b' ' } else { b })
+            .collect();
+        percent_decode(&s)
+    };
+    body.split(|&b| b == b'&')
+        .filter(|field| !field.is_empty())
+        .map(|field| match field.iter().position(|&b| b == b'=') {
+            Some(i) => (decode(&field[..i]), decode(&field[i + 1..])),
+            None => (decode(field), String::new()),
+        })
+        .collect()
+}
+
+/// Split a multipart body into its parts, skipping any preamble and epilogue. Returns `None` if
+/// the body isn't well formed.
+fn parse_multipart(body: &[u8], boundary: &str) -> Option<Vec<BodyPart>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let close = [b"\r\n".as_slice(), &delimiter].concat();
+    let mut rest = &body[find(body, &delimiter)? + delimiter.len()..];
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Some(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n")?;
+        let end = find(rest, &close)?;
+        parts.push(parse_part(&rest[..end])?);
+        rest = &rest[end + close.len()..];
+    }
+}
+
+fn parse_part(part: &[u8]) -> Option<BodyPart> {
+    let (head, body) = match part.strip_prefix(b"\r\n") {
+        Some(body) => (&b""[..], body),
+        None => {
+            let end = find(part, b"\r\n\r\n")?;
+            (&part[..end], &part[end + 4..])
+        }
+    };
+    let mut headers = HeaderMap::new();
+    for line in head.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let colon = line.iter().position(|&b| b == b':')?;
+        headers.append(
+            HeaderName::from_bytes(&line[..colon]).ok()?,
+            HeaderValue::from_bytes(line[colon + 1..].trim_ascii()).ok()?,
+        );
+    }
+    Some(BodyPart {
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+pub(super) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::exec::testutil::echo_server;
+    use crate::exec::{Executor, HTTPOutput, StepOutput, StepParsedOutput};
+    use crate::{Plan, Template};
+
+    const PROTO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/greeter.proto");
+
+    /// Send a POST to a server that echoes it back, returning the step output and the response.
+    async fn echo(options: &str, headers: &str, body: &str) -> (StepOutput, HTTPOutput) {
+        let port = echo_server().await;
+        let plan = format!(
+            "http{options} echo EOF\nPOST http://127.0.0.1:{port}/\n{headers}\n{body}\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let out = Executor::new(&plan).next().await.unwrap();
+        let StepParsedOutput::HTTP(parsed) = out.parsed.clone() else {
+            panic!("expected an HTTP response");
+        };
+        (out, parsed)
+    }
+
+    fn render(out: StepOutput, text: &str) -> Vec<u8> {
+        let previous = expr::Scope {
+            outputs: HashMap::from([("echo", out)]),
+            ..Default::default()
+        };
+        expr::render(&Template::parse(text).unwrap().1, &previous).unwrap()
+    }
+
+    #[tokio::test]
+    async fn json_body_test() {
+        let (out, parsed) = echo("(body=json)", "", "{\"a\": [1, 2]}").await;
+        assert!(find(&out.raw_request, b"content-type: application/json").is_some());
+        assert!(out
+            .raw_request
+            .ends_with(b"\r\n\r\n{\n  \"a\": [\n    1,\n    2\n  ]\n}"));
+        assert_eq!(
+            parsed.parsed_body,
+            Some(ParsedBody::JSON(json!({ "a": [1, 2] })))
+        );
+        assert_eq!(render(out, "${echo.response.parsed.a[1]}"), b"2");
+
+        // The plan's own Content-Type is kept.
+        let (out, parsed) = echo("(body=json)", "Content-Type: text/plain\n", "[]").await;
+        assert!(find(&out.raw_request, b"Content-Type: text/plain").is_some());
+        assert!(find(&out.raw_request, b"application/json").is_none());
+        assert_eq!(parsed.parsed_body, None);
+    }
+
+    #[tokio::test]
+    async fn form_body_test() {
+        let (out, parsed) = echo("(body=form)", "", "name=ann lee\ntoken=a&b").await;
+        assert!(out
+            .raw_request
+            .ends_with(b"\r\n\r\nname=ann%20lee&token=a%26b"));
+        assert_eq!(
+            parsed.parsed_body,
+            Some(ParsedBody::Form(vec![
+                ("name".to_owned(), "ann lee".to_owned()),
+                ("token".to_owned(), "a&b".to_owned()),
+            ]))
+        );
+        assert_eq!(render(out, "${echo.response.parsed.name}"), b"ann lee");
+    }
+
+    #[tokio::test]
+    async fn multipart_body_test() {
+        let path = std::env::temp_dir().join("courier-test-multipart.txt");
+        std::fs::write(&path, "file contents").unwrap();
+        let body = format!(
+            "part title\n\nhello\npart upload\nContent-Type: text/plain\n\n< {}",
+            path.display()
+        );
+        let (out, parsed) = echo("(body=multipart)", "", &body).await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(find(
+            &out.raw_request,
+            b"content-type: multipart/form-data; boundary=courier-"
+        )
+        .is_some());
+        let Some(ParsedBody::Multipart(parts)) = &parsed.parsed_body else {
+            panic!("expected a multipart body");
+        };
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[0].headers[CONTENT_DISPOSITION],
+            "form-data; name=\"title\""
+        );
+        assert_eq!(parts[0].body, b"hello");
+        assert_eq!(
+            parts[1].headers[CONTENT_DISPOSITION],
+            "form-data; name=\"upload\"; filename=\"courier-test-multipart.txt\""
+        );
+        assert_eq!(parts[1].headers[CONTENT_TYPE], "text/plain");
+        assert_eq!(parts[1].body, b"file contents");
+        assert_eq!(
+            render(out, "${echo.response.parsed[1].body}"),
+            b"file contents"
+        );
+
+        // A boundary in the plan's Content-Type is used instead of a generated one.
+        let (out, _) = echo(
+            "(body=multipart)",
+            "Content-Type: multipart/mixed; boundary=xyz\n",
+            "part a\nContent-Disposition: inline\n\nb",
+        )
+        .await;
+        assert!(out
+            .raw_request
+            .ends_with(b"\r\n\r\n--xyz\r\nContent-Disposition: inline\r\n\r\nb\r\n--xyz--\r\n"));
+    }
+
+    #[tokio::test]
+    async fn protobuf_body_test() {
+        let options = format!(
+            "(body=protobuf, message=test.HelloRequest, response_message=test.HelloRequest, proto=\"{}\")",
+            PROTO
+        );
+        let (out, parsed) = echo(&options, "", "name: \"world\" times: 2").await;
+        assert!(find(&out.raw_request, b"content-type: application/x-protobuf").is_some());
+        assert!(out.raw_request.ends_with(b"\r\n\r\n\x0a\x05world\x10\x02"));
+        let Some(ParsedBody::Protobuf(message)) = &parsed.parsed_body else {
+            panic!("expected a protobuf body");
+        };
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            json!({ "name": "world", "times": 2 })
+        );
+        assert_eq!(render(out, "${echo.response.parsed.name}"), b"world");
+
+        // A response that isn't the message, like an error page, gets no view.
+        let options = format!("(response_message=test.HelloRequest, proto=\"{}\")", PROTO);
+        let (_, parsed) = echo(&options, "", "<html>Internal Server Error</html>").await;
+        assert_eq!(parsed.body, b"<html>Internal Server Error</html>");
+        assert_eq!(parsed.parsed_body, None);
+    }
+
+    #[tokio::test]
+    async fn payload_body_test() {
+        let (_, parsed) = echo("(body=escaped)", "", "a\\x00\\xff\\r\\n").await;
+        assert_eq!(parsed.body, b"a\x00\xff\r\n");
+        let (_, parsed) = echo("(body=base64)", "", "aGVs\nbG8=").await;
+        assert_eq!(parsed.body, b"hello");
+        let (_, parsed) = echo("(body=hex)", "", "00 ff\n7f").await;
+        assert_eq!(parsed.body, [0x00, 0xff, 0x7f]);
+
+        let path = std::env::temp_dir().join("courier-test-payload.bin");
+        std::fs::write(&path, [0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let (_, parsed) = echo("(body=file)", "", path.to_str().unwrap()).await;
+        assert_eq!(parsed.body, [0xde, 0xad, 0xbe, 0xef]);
+
+        let (out, parsed) = echo("(charset=utf-16le)", "", "hé").await;
+        assert_eq!(parsed.body, [0x68, 0, 0xe9, 0]);
+        assert!(find(&out.raw_request, b"content-length: 4").is_some());
+        let (_, parsed) = echo("(charset=gb18030)", "", "中").await;
+        assert_eq!(parsed.body, [0xd6, 0xd0]);
+    }
+
+    #[tokio::test]
+    async fn chunked_body_test() {
+        let start = std::time::Instant::now();
+        let (out, parsed) = echo(
+            "(body=chunked)",
+            "",
+            "chunk(ext=a=1) hel\nchunk(delay=100ms) lo\\r\\n\ntrailer X-Sum: 7",
+        )
+        .await;
+        assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+        assert!(out.raw_request.ends_with(
+            b"\r\ntransfer-encoding: chunked\r\n\r\n3;a=1\r\nhel\r\n4\r\nlo\r\n\r\n0\r\nX-Sum: 7\r\n\r\n"
+        ));
+        assert!(find(&out.raw_request, b"content-length").is_none());
+        assert_eq!(parsed.body, b"hello\r\n");
+
+        // Sizes are sent as written, and a trailing empty chunk ends the body itself.
+        let (out, _) = echo("(body=chunked)", "", "chunk(size=02) ok\nchunk(ext=end)").await;
+        assert!(out
+            .raw_request
+            .ends_with(b"\r\n\r\n02\r\nok\r\n0;end\r\n\r\n"));
+    }
+
+    #[test]
+    fn parse_multipart_test() {
+        let body =
+            b"preamble\r\n--b\r\n\r\nno headers\r\n--b\r\nX-A: 1\r\n\r\nline\r\nline\r\n--b--\r\n";
+        let parts = parse_multipart(body, "b").unwrap();
+        assert_eq!(parts[0].headers, HeaderMap::new());
+        assert_eq!(parts[0].body, b"no headers");
+        assert_eq!(parts[1].headers["x-a"], "1");
+        assert_eq!(parts[1].body, b"line\r\nline");
+
+        assert_eq!(parse_multipart(b"--b\r\nunterminated", "b"), None);
+        assert_eq!(parse_multipart(b"no boundary", "b"), None);
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use super::raw::{read_response, write_request};
+use super::tls::ClientConfigs;
+use super::{tls, HTTPOutput, StepOutput, StepParsedOutput, TLSOutput};
+use crate::{ConnectionOptions, TLSOptions};
+
+/// Which named connection a step's request was sent over.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionOutput {
+    pub name: String,
+    /// How many requests were sent over the connection before this one. The step that opens a
+    /// connection sends request 0.
+    pub sequence: usize,
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    /// Whether the request was sent without waiting for its response.
+    pub pipelined: bool,
+}
+
+/// The connections opened by steps with the `connection` option, by name.
+#[derive(Default)]
+pub(super) struct Connections {
+    open: HashMap<String, Connection>,
+    /// Responses to pipelined requests waiting to be given to the steps that sent them.
+    answered: Vec<StepOutput>,
+}
+
+/// Where to open a connection if the step's connection isn't open yet.
+pub(super) struct Target<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub tls: Option<&'a TLSOptions<'a>>,
+    pub alpn: &'a [u8],
+}
+
+/// A stream HTTP/1 requests are written to, with or without TLS.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    tls: Option<TLSOutput>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    sent: usize,
+    /// Bytes received after the last response that was read.
+    received: Vec<u8>,
+    closed: bool,
+    /// Whether the server said it won't answer any more requests.
+    done: bool,
+    /// Pipelined requests whose responses haven't been read yet, oldest first.
+    pending: Vec<Pending>,
+}
+
+struct Pending {
+    output: StepOutput,
+    head_request: bool,
+}
+
+impl Connections {
+    /// Send a request over a named connection, opening the connection first if it isn't open or
+    /// the server closed it. Unless the request is pipelined, the responses to earlier pipelined
+    /// requests are read before its own.
+    pub async fn send(
+        &mut self,
+        options: &ConnectionOptions<'_>,
+        target: Target<'_>,
+        request: &[u8],
+        pauses: &[(usize, Duration)],
+        head_request: bool,
+        configs: &ClientConfigs,
+    ) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+        let reopen = self
+            .open
+            .get(options.name)
+            .is_none_or(|conn| (conn.closed || conn.done) && conn.pending.is_empty());
+        if reopen {
+            let conn = Connection::open(target, configs).await?;
+            self.open.insert(options.name.to_owned(), conn);
+        }
+        let conn = self
+            .open
+            .get_mut(options.name)
+            .expect("connection was opened");
+
+        let mut out = StepOutput {
+            raw_request: request.to_vec(),
+            raw_response: Vec::new(),
+            tls: conn.tls.clone(),
+            http2: None,
+            quic: None,
+            connection: Some(ConnectionOutput {
+                name: options.name.to_owned(),
+                sequence: conn.sent,
+                local_addr: conn.local_addr,
+                peer_addr: conn.peer_addr,
+                pipelined: options.pipeline,
+            }),
+            duration: Duration::ZERO,
+            parsed: StepParsedOutput::Pending,
+        };
+        conn.sent += 1;
+        let written = write_request(&mut conn.stream, request, pauses).await;
+        if let Err(e) = written {
+            conn.closed = true;
+            conn.pending.clear();
+            return Err(e.into());
+        }
+        if options.pipeline {
+            conn.pending.push(Pending {
+                output: out.clone(),
+                head_request,
+            });
+            return Ok(out);
+        }
+
+        for mut pending in std::mem::take(&mut conn.pending) {
+            let (response, raw) = conn.read(pending.head_request).await?;
+            pending.output.raw_response = raw;
+            pending.output.parsed = StepParsedOutput::HTTP(response);
+            self.answered.push(pending.output);
+        }
+        let (response, raw) = conn.read(head_request).await?;
+        out.raw_response = raw;
+        // Nothing can ask for bytes left over once the connection is finished, so keep them with
+        // the last response.
+        if conn.closed || conn.done {
+            out.raw_response.append(&mut conn.received);
+        }
+        out.parsed = StepParsedOutput::HTTP(response);
+        Ok(out)
+    }
+
+    /// Take the responses read for pipelined requests. Each output's connection is the same as
+    /// in the output of the step that sent the request.
+    pub fn answered(&mut self) -> Vec<StepOutput> {
+        std::mem::take(&mut self.answered)
+    }
+}
+
+impl Connection {
+    async fn open(
+        target: Target<'_>,
+        configs: &ClientConfigs,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let stream = TcpStream::connect(format!("{}:{}", target.host, target.port)).await?;
+        let local_addr = stream.local_addr()?;
+        let peer_addr = stream.peer_addr()?;
+        let (stream, tls): (Box<dyn Stream>, _) = match target.tls {
+            Some(options) => {
+                let (stream, tls) =
+                    tls::handshake(stream, target.host, &[target.alpn], options, configs).await?;
+                (Box::new(stream), Some(tls))
+            }
+            None => (Box::new(stream), None),
+        };
+        Ok(Connection {
+            stream,
+            tls,
+            local_addr,
+            peer_addr,
+            sent: 0,
+            received: Vec::new(),
+            closed: false,
+            done: false,
+            pending: Vec::new(),
+        })
+    }
+
+    async fn read(
+        &mut self,
+        head_request: bool,
+    ) -> Result<(HTTPOutput, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+        let result = read_response(
+            &mut self.stream,
+            &mut self.received,
+            &mut self.closed,
+            head_request,
+        )
+        .await;
+        match &result {
+            // The server won't answer any more requests, whether or not it has closed the
+            // connection yet.
+            Ok((response, _)) => {
+                self.done |= response
+                    .headers
+                    .get_all(hyper::header::CONNECTION)
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .any(|option| option.trim().eq_ignore_ascii_case("close"));
+            }
+            // The rest of the responses can't be told apart after a bad one.
+            Err(_) => {
+                self.closed = true;
+                self.pending.clear();
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::exec::testutil::http_server;
+    use crate::exec::{Executor, StepOutput, StepParsedOutput};
+    use crate::Plan;
+
+    fn body(out: &StepOutput) -> &[u8] {
+        let StepParsedOutput::HTTP(http) = &out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        &http.body
+    }
+
+    #[tokio::test]
+    async fn connection_test() {
+        let port = http_server().await;
+        let plan = format!(
+            "http(connection=a) EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            http(connection=a, pipeline=true) login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            http(connection=a, pipeline=true) EOF\nGET http://127.0.0.1:{port}/\nAuthorization: b\n\n\nEOF\n\
+            http(connection=a) EOF\nGET http://127.0.0.1:{port}/\nAuthorization: c\n\n\nEOF\n\
+            http(connection=b) EOF\nGET http://127.0.0.1:{port}/\nAuthorization: ${{login.response.body}}\n\n\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+
+        let first = executor.next().await.unwrap();
+        let first_conn = first.connection.clone().unwrap();
+        assert_eq!(first_conn.name, "a");
+        assert_eq!(first_conn.sequence, 0);
+        assert_eq!(body(&first), b"token123");
+
+        // Pipelined requests return before their responses are read.
+        for sequence in [1, 2] {
+            let out = executor.next().await.unwrap();
+            assert_eq!(out.parsed, StepParsedOutput::Pending);
+            let conn = out.connection.unwrap();
+            assert_eq!(conn.sequence, sequence);
+            assert_eq!(conn.local_addr, first_conn.local_addr);
+            assert!(conn.pipelined);
+        }
+        let fourth = executor.next().await.unwrap();
+        assert_eq!(fourth.connection.as_ref().unwrap().sequence, 3);
+        assert_eq!(body(&fourth), b"c");
+        assert!(fourth.raw_response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(fourth.raw_response.ends_with(b"\r\n\r\nc"));
+
+        // The pipelined step's output got its response, and another name opens another connection.
+        let fifth = executor.next().await.unwrap();
+        let fifth_conn = fifth.connection.clone().unwrap();
+        assert_eq!(fifth_conn.sequence, 0);
+        assert_ne!(fifth_conn.local_addr, first_conn.local_addr);
+        assert_eq!(body(&fifth), b"token123");
+        let login = &executor.scope.outputs["login"];
+        assert_eq!(body(login), b"token123");
+        assert_eq!(login.connection.as_ref().unwrap().sequence, 1);
+        assert!(login.raw_response.ends_with(b"\r\n\r\ntoken123"));
+    }
+
+    #[tokio::test]
+    async fn desync_test() {
+        // The server answers the first request with two responses.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let mut received = Vec::new();
+            while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\naHTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            while stream.read(&mut buf).await.unwrap_or(0) > 0 {}
+        });
+        let step = format!(
+            "http(raw=true, body=escaped, connection=a) EOF\n\
+            GET / HTTP/1.1\\r\nHost: 127.0.0.1:{port}\\r\n\\r\n\nEOF\n"
+        );
+        let plan = step.repeat(2);
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        let out = executor.next().await.unwrap();
+        assert_eq!(body(&out), b"a");
+        assert_eq!(
+            out.raw_response,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na"
+        );
+
+        // The extra response is read as the answer to the next request.
+        let out = executor.next().await.unwrap();
+        let StepParsedOutput::HTTP(http) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(http.status, StatusCode::NOT_FOUND);
+        assert_eq!(out.connection.unwrap().sequence, 1);
+    }
+}
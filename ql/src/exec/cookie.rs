@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use hyper::header::SET_COOKIE;
+use hyper::{HeaderMap, Uri};
+
+use super::{ConnectionOutput, StepInputs, StepOutput, StepParsedOutput};
+
+/// The cookies set by responses, sent along with later requests to where they apply, as
+/// described by RFC 6265. Domains aren't checked against the public suffix list, so a response
+/// can set cookies for a whole top-level domain.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+    /// Where pipelined requests whose responses haven't been read yet were sent, and whether
+    /// they were sent securely.
+    pending: HashMap<ConnectionOutput, (Uri, bool)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    /// The lowercase host the cookie is sent to, along with its subdomains unless `host_only`.
+    pub domain: String,
+    /// Whether the cookie had no `Domain` attribute, and is only sent to the host that set it.
+    pub host_only: bool,
+    pub path: String,
+    /// When the cookie expires, or `None` if it lasts as long as the jar.
+    pub expires: Option<SystemTime>,
+    /// Whether the cookie is only sent over TLS.
+    pub secure: bool,
+}
+
+impl CookieJar {
+    /// The cookies in the jar that haven't expired, oldest first.
+    pub fn cookies(&self) -> impl Iterator<Item = &Cookie> {
+        let now = SystemTime::now();
+        self.cookies
+            .iter()
+            .filter(move |cookie| !cookie.expired(now))
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+
+    /// Store a cookie from the value of a `Set-Cookie` header in a response from `url`, which was
+    /// sent over TLS if `secure`. Cookies that don't apply to the URL are ignored, and a cookie
+    /// replaces one with the same name, domain and path.
+    pub fn store(&mut self, url: &Uri, secure: bool, set_cookie: &str) {
+        let Some(host) = url.host().map(str::to_ascii_lowercase) else {
+            return;
+        };
+        let mut attributes = set_cookie.split(';');
+        let Some((name, value)) = attributes.next().and_then(|pair| pair.split_once('=')) else {
+            return;
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return;
+        }
+        let mut cookie = Cookie {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url.path()).to_owned(),
+            expires: None,
+            secure: false,
+        };
+        // Max-Age takes precedence over Expires.
+        let mut max_age = None;
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value
+                        .strip_prefix('.')
+                        .unwrap_or(value)
+                        .to_ascii_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+                "max-age" => {
+                    if let Ok(seconds) = value.parse::<i64>() {
+                        max_age = Some(seconds);
+                    }
+                }
+                "expires" => {
+                    if let Ok(expires) = httpdate::parse_http_date(value) {
+                        cookie.expires = Some(expires);
+                    }
+                }
+                "secure" => cookie.secure = true,
+                _ => {}
+            }
+        }
+        if let Some(seconds) = max_age {
+            cookie.expires = match u64::try_from(seconds) {
+                Ok(seconds) if seconds > 0 => {
+                    SystemTime::now().checked_add(Duration::from_secs(seconds))
+                }
+                _ => Some(SystemTime::UNIX_EPOCH),
+            };
+        }
+        // Only secure origins can set secure cookies.
+        if cookie.secure && !secure {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let existing = self.cookies.iter().position(|old| {
+            old.name == cookie.name && old.domain == cookie.domain && old.path == cookie.path
+        });
+        match existing {
+            // Setting a cookie that has already expired removes it.
+            Some(i) if cookie.expired(now) => {
+                self.cookies.remove(i);
+            }
+            // A replaced cookie keeps its place, so cookies are still sent oldest first.
+            Some(i) => self.cookies[i] = cookie,
+            None if cookie.expired(now) => {}
+            None => self.cookies.push(cookie),
+        }
+        self.cookies.retain(|cookie| !cookie.expired(now));
+    }
+
+    /// The value of the `Cookie` header for a request to `url`, sent over TLS if `secure`, or
+    /// `None` if no cookies apply. Cookies with longer paths go first.
+    pub fn header(&self, url: &Uri, secure: bool) -> Option<String> {
+        let mut cookies: Vec<_> = self.matching(url, secure).collect();
+        if cookies.is_empty() {
+            return None;
+        }
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        Some(
+            cookies
+                .iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; "),
+        )
+    }
+
+    /// The cookies that apply to a request to `url`, sent over TLS if `secure`.
+    pub fn matching<'j>(&'j self, url: &Uri, secure: bool) -> impl Iterator<Item = &'j Cookie> {
+        let host = url.host().unwrap_or_default().to_ascii_lowercase();
+        let path = match url.path() {
+            "" => "/".to_owned(),
+            path => path.to_owned(),
+        };
+        self.cookies().filter(move |cookie| {
+            (if cookie.host_only {
+                host == cookie.domain
+            } else {
+                domain_matches(&host, &cookie.domain)
+            }) && path_matches(&path, &cookie.path)
+                && (secure || !cookie.secure)
+        })
+    }
+
+    /// Store the cookies set by an HTTP response.
+    fn store_all(&mut self, url: &Uri, secure: bool, headers: &HeaderMap) {
+        for value in headers.get_all(SET_COOKIE) {
+            if let Ok(value) = value.to_str() {
+                self.store(url, secure, value);
+            }
+        }
+    }
+}
+
+impl Cookie {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// The `Cookie` header for a step's request, if the executor has a cookie jar.
+pub(super) fn header(inputs: &StepInputs, url: &Uri, secure: bool) -> Option<String> {
+    let jar = inputs.previous.cookies.as_ref()?;
+    let jar = jar.lock().unwrap_or_else(|e| e.into_inner());
+    jar.header(url, secure)
+}
+
+/// Store the cookies set by the response to a step's request, if the executor has a cookie jar.
+/// The responses to pipelined requests are stored once they're read.
+pub(super) fn store(inputs: &StepInputs, url: &Uri, secure: bool, out: &StepOutput) {
+    let Some(jar) = &inputs.previous.cookies else {
+        return;
+    };
+    let mut jar = jar.lock().unwrap_or_else(|e| e.into_inner());
+    match (&out.parsed, &out.connection) {
+        (StepParsedOutput::Pending, Some(connection)) => {
+            jar.pending
+                .insert(connection.clone(), (url.clone(), secure));
+        }
+        (parsed, _) => {
+            if let Some(headers) = response_headers(parsed) {
+                jar.store_all(url, secure, headers);
+            }
+        }
+    }
+}
+
+/// Store the cookies set by the response to a pipelined request.
+pub(super) fn store_pipelined(jar: &mut CookieJar, out: &StepOutput) {
+    let Some((url, secure)) = out
+        .connection
+        .as_ref()
+        .and_then(|connection| jar.pending.remove(connection))
+    else {
+        return;
+    };
+    if let Some(headers) = response_headers(&out.parsed) {
+        jar.store_all(&url, secure, headers);
+    }
+}
+
+fn response_headers(parsed: &StepParsedOutput) -> Option<&HeaderMap> {
+    match parsed {
+        StepParsedOutput::HTTP(http) => Some(&http.headers),
+        StepParsedOutput::GraphQL(graphql) => Some(&graphql.http.headers),
+        StepParsedOutput::GRPC(grpc) => Some(&grpc.http.headers),
+        StepParsedOutput::WebSocket(websocket) => Some(&websocket.upgrade.headers),
+        _ => None,
+    }
+}
+
+/// The directory of a request's path, which is where its cookies apply if they don't say.
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &path[..i],
+    }
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+            && host.trim_matches(['[', ']']).parse::<IpAddr>().is_err())
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path.strip_prefix(cookie_path)
+        .is_some_and(|rest| rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testutil::cookie_server;
+    use crate::exec::{Error, Executor, Expectation};
+    use crate::Plan;
+
+    #[test]
+    fn cookie_jar_test() {
+        let login: Uri = "https://api.example.com/auth/login".parse().unwrap();
+        let mut jar = CookieJar::default();
+        jar.store(&login, true, "session=abc; Secure; HttpOnly");
+        jar.store(&login, true, "theme=dark; Domain=.Example.com; Path=/");
+        jar.store(&login, true, "old=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT");
+        jar.store(
+            &login,
+            true,
+            "later=1; Max-Age=3600; Expires=Wed, 21 Oct 2015 07:28:00 GMT",
+        );
+        // Cookies for other domains and secure cookies from insecure origins are ignored.
+        jar.store(&login, true, "other=1; Domain=example.org");
+        jar.store(&login, false, "insecure=1; Secure");
+        assert_eq!(
+            jar.cookies().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            ["session", "theme", "later"]
+        );
+        let session = jar.cookies().next().unwrap();
+        assert_eq!(session.domain, "api.example.com");
+        assert_eq!(session.path, "/auth");
+        assert!(session.host_only && session.secure);
+
+        let header = |jar: &CookieJar, url: &str, secure| jar.header(&url.parse().unwrap(), secure);
+        assert_eq!(
+            header(&jar, "https://api.example.com/auth/me", true),
+            Some("session=abc; later=1; theme=dark".to_owned())
+        );
+        assert_eq!(
+            header(&jar, "http://api.example.com/auth/me", false),
+            Some("later=1; theme=dark".to_owned())
+        );
+        assert_eq!(
+            header(&jar, "https://www.example.com/auth", true),
+            Some("theme=dark".to_owned())
+        );
+        assert_eq!(
+            header(&jar, "https://api.example.com/authors", true),
+            Some("theme=dark".to_owned())
+        );
+        assert_eq!(header(&jar, "https://example.org/", true), None);
+
+        // Setting a cookie again replaces it, and setting it to expire removes it.
+        jar.store(&login, true, "theme=light; Domain=example.com; Path=/");
+        jar.store(&login, true, "later=; Max-Age=0");
+        assert_eq!(
+            header(&jar, "https://www.example.com/", true),
+            Some("theme=light".to_owned())
+        );
+        jar.clear();
+        assert_eq!(jar.cookies().count(), 0);
+
+        assert_eq!(default_path("/"), "/");
+        assert_eq!(default_path("/login"), "/");
+        assert_eq!(default_path("/a/b/"), "/a/b");
+        assert!(!domain_matches("127.0.0.1", "0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn cookie_executor_test() {
+        let port = cookie_server().await;
+        let step = |path: &str| format!("http EOF\nGET http://127.0.0.1:{port}{path}\n\n\nEOF\n");
+        let plan = [
+            step("/login"),
+            step("/me"),
+            "cookies enable\n".to_owned(),
+            step("/login"),
+            step("/me"),
+            step("/admin/login"),
+            step("/admin/me"),
+            "expect cookie_jar(\"role\") == \"admin\"\n".to_owned(),
+            format!("expect cookie_jar(\"role\", \"http://127.0.0.1:{port}/\") exists\n"),
+            "cookies set \"http://127.0.0.1/\" \"theme=dark\"\n".to_owned(),
+            step("/logout"),
+            step("/me"),
+            "cookies clear\n".to_owned(),
+            step("/me"),
+        ]
+        .concat();
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+
+        // Cookies are only kept once the jar is enabled.
+        get(&mut executor).await;
+        assert_eq!(get(&mut executor).await.1, "");
+        get(&mut executor).await;
+        let (request, body) = get(&mut executor).await;
+        assert_eq!(body, "session=abc");
+        assert!(request.contains("\r\ncookie: session=abc\r\n"));
+        get(&mut executor).await;
+        assert_eq!(get(&mut executor).await.1, "role=admin; session=abc");
+
+        // The admin cookie's path is /admin.
+        let url = format!("http://127.0.0.1:{port}/");
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::Expectation(Expectation {
+                condition: format!("cookie_jar(\"role\", \"{url}\") exists"),
+                values: vec![(format!("cookie_jar(\"role\", \"{url}\")"), None)],
+            }))
+        );
+        get(&mut executor).await;
+        assert_eq!(get(&mut executor).await.1, "theme=dark");
+        let (request, body) = get(&mut executor).await;
+        assert_eq!(body, "");
+        assert!(!request.contains("cookie"));
+        assert!(executor.cookies().is_empty());
+    }
+
+    /// Send the next step, returning its raw request and the body of its response.
+    async fn get(executor: &mut Executor<'_>) -> (String, String) {
+        let out = executor.next().await.unwrap();
+        let StepParsedOutput::HTTP(http) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        (
+            String::from_utf8(out.raw_request).unwrap(),
+            String::from_utf8(http.body).unwrap(),
+        )
+    }
+}
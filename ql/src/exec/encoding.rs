@@ -0,0 +1,180 @@
+use std::io::{Read, Write};
+
+use hyper::header::CONTENT_ENCODING;
+use hyper::HeaderMap;
+
+/// The most a response body may decode to, so a small compressed body can't exhaust memory.
+const MAX_DECODED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A content coding from a `Content-Encoding` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Coding {
+    fn parse(token: &str) -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        match token.to_ascii_lowercase().as_str() {
+            "identity" | "" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(Self::Gzip)),
+            "deflate" => Ok(Some(Self::Deflate)),
+            "br" => Ok(Some(Self::Brotli)),
+            "zstd" => Ok(Some(Self::Zstd)),
+            _ => Err(format!("unsupported content encoding {}", token).into()),
+        }
+    }
+
+    fn decode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => read_limited(flate2::read::MultiGzDecoder::new(body)),
+            // Deflate should be wrapped in zlib, but some servers send the raw stream instead.
+            Self::Deflate => read_limited(flate2::read::ZlibDecoder::new(body))
+                .or_else(|_| read_limited(flate2::read::DeflateDecoder::new(body))),
+            Self::Brotli => read_limited(brotli::Decompressor::new(body, 4096)),
+            Self::Zstd => read_limited(zstd::Decoder::new(body)?),
+        }
+    }
+
+    fn encode(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(body)?;
+                Ok(encoder.into_inner())
+            }
+            Self::Zstd => zstd::encode_all(body, 0),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Read a decoder to the end, failing if it produces more than [`MAX_DECODED_SIZE`] bytes.
+fn read_limited(decoder: impl Read) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decoder.take(MAX_DECODED_SIZE + 1).read_to_end(&mut out)?;
+    if out.len() as u64 > MAX_DECODED_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("decoded body is larger than {} bytes", MAX_DECODED_SIZE),
+        ));
+    }
+    Ok(out)
+}
+
+/// List the codings named by a message's `Content-Encoding` headers, in the order they were
+/// applied.
+fn codings(headers: &HeaderMap) -> Result<Vec<Coding>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut codings = Vec::new();
+    for value in headers.get_all(CONTENT_ENCODING) {
+        for token in value.to_str()?.split(',') {
+            codings.extend(Coding::parse(token.trim())?);
+        }
+    }
+    Ok(codings)
+}
+
+/// Undo the content codings of a response body, returning `None` if it has none. Codings are
+/// removed in the reverse of the order they were applied.
+pub(super) fn decode(
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+    let codings = codings(headers)?;
+    // Responses to HEAD requests and the like name a coding without having a body to decode.
+    if codings.is_empty() || body.is_empty() {
+        return Ok(None);
+    }
+    let mut body = body.to_vec();
+    for coding in codings.into_iter().rev() {
+        body = coding
+            .decode(&body)
+            .map_err(|e| format!("couldn't decode {} body: {}", coding.name(), e))?;
+    }
+    Ok(Some(body))
+}
+
+/// Apply the content codings named by a request's headers to its body, in order.
+pub(super) fn encode(
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut body = body;
+    for coding in codings(headers)? {
+        body = coding.encode(&body)?;
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, encoding.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn encoding_round_trip_test() {
+        for encoding in [
+            "gzip", "x-gzip", "deflate", "br", "zstd", "gzip, br", "identity",
+        ] {
+            let headers = headers(encoding);
+            let encoded = encode(&headers, b"hello hello hello".to_vec()).unwrap();
+            let decoded = decode(&headers, &encoded).unwrap();
+            if encoding == "identity" {
+                assert_eq!(encoded, b"hello hello hello");
+                assert_eq!(decoded, None);
+            } else {
+                assert_ne!(encoded, b"hello hello hello", "{}", encoding);
+                assert_eq!(decoded.as_deref(), Some(&b"hello hello hello"[..]));
+            }
+        }
+    }
+
+    #[test]
+    fn decode_test() {
+        // Raw deflate streams are accepted as well as zlib ones.
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"raw").unwrap();
+        let raw = encoder.finish().unwrap();
+        assert_eq!(
+            decode(&headers("deflate"), &raw).unwrap().as_deref(),
+            Some(&b"raw"[..])
+        );
+
+        assert_eq!(decode(&HeaderMap::new(), b"plain").unwrap(), None);
+        assert_eq!(decode(&headers("gzip"), b"").unwrap(), None);
+        assert!(decode(&headers("gzip"), b"not gzip").is_err());
+        assert!(decode(&headers("compress"), b"data").is_err());
+
+        // Bodies can't decode to more than the limit.
+        let bomb = encode(&headers("gzip"), vec![0; MAX_DECODED_SIZE as usize + 1]).unwrap();
+        assert!(decode(&headers("gzip"), &bomb).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+
+use super::function;
+use super::{
+    CookieJar, Error, Expectation, HTTPOutput, ParsedBody, Plugins, StepOutput, StepParsedOutput,
+};
+use crate::util::unescape;
+use crate::{Accessor, Comparison, Condition, Expr, Reference, Template, TemplatePart};
+
+/// The result of evaluating an expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Int(i) => i.to_string().into_bytes(),
+            Self::String(s) => s.into_bytes(),
+            Self::Bytes(b) => b,
+        }
+    }
+
+    /// The value as an integer, if it is one or is text holding one.
+    pub(super) fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            Self::String(s) => s.parse().ok(),
+            Self::Bytes(b) => std::str::from_utf8(b).ok()?.parse().ok(),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(i) => write!(f, "{}", i),
+            Self::String(s) => write!(f, "{:?}", s),
+            Self::Bytes(b) => write!(f, "{:?}", String::from_utf8_lossy(b)),
+        }
+    }
+}
+
+/// What expressions can refer to: the outputs of named steps and the variables of running loops,
+/// along with the plugins providing functions and the cookie jar, if it's enabled.
+#[derive(Debug, Default)]
+pub(super) struct Scope<'a> {
+    pub outputs: HashMap<&'a str, StepOutput>,
+    pub variables: HashMap<&'a str, Value>,
+    pub plugins: Plugins,
+    pub cookies: Option<Mutex<CookieJar>>,
+}
+
+pub(super) fn evaluate(expr: &Expr, previous: &Scope) -> Result<Value, Error> {
+    match expr {
+        Expr::String(s) => Ok(Value::String(s.to_string())),
+        Expr::Int(i) => Ok(Value::Int(*i)),
+        Expr::Reference(r) => resolve(r, previous),
+        Expr::Call(call) => function::call(call, previous),
+    }
+}
+
+/// Evaluate each expression in the template and concatenate the results with the literal text.
+pub(super) fn render(template: &Template, previous: &Scope) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    for part in &template.parts {
+        match part {
+            TemplatePart::Literal(text) => out.extend_from_slice(text.as_bytes()),
+            TemplatePart::Expr(expr) => out.extend(evaluate(expr, previous)?.into_bytes()),
+        }
+    }
+    Ok(out)
+}
+
+/// Like [`render`], but replaces escapes in the literal text of a template parsed with
+/// [`Template::parse_escaped`]. The values of expressions are left as they are.
+pub(super) fn render_escaped(template: &Template, previous: &Scope) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    for part in &template.parts {
+        match part {
+            // Escapes are checked when the plan is parsed.
+            TemplatePart::Literal(text) => out.extend(unescape(text).unwrap_or_default()),
+            TemplatePart::Expr(expr) => out.extend(evaluate(expr, previous)?.into_bytes()),
+        }
+    }
+    Ok(out)
+}
+
+/// Check a condition. Expressions in a comparison must have values, while `exists` holds only
+/// for expressions that have one.
+pub(super) fn check(condition: &Condition, previous: &Scope) -> Result<bool, Error> {
+    match condition {
+        Condition::Compare { left, op, right } => {
+            compare(&evaluate(left, previous)?, *op, &evaluate(right, previous)?)
+        }
+        Condition::Exists(expr) => Ok(value(expr, previous)?.is_some()),
+    }
+}
+
+/// Check the condition of an `expect` statement. Unlike [`check`], a comparison with an
+/// expression that has no value fails rather than being an error, and a failure records what
+/// the condition's references evaluated to.
+pub(super) fn expect(condition: &Condition, previous: &Scope) -> Result<(), Error> {
+    let (holds, values) = match condition {
+        Condition::Compare { left, op, right } => {
+            let values = (value(left, previous)?, value(right, previous)?);
+            let holds = match &values {
+                (Some(l), Some(r)) => compare(l, *op, r)?,
+                _ => false,
+            };
+            (holds, vec![(left, values.0), (right, values.1)])
+        }
+        Condition::Exists(expr) => {
+            let value = value(expr, previous)?;
+            (value.is_some(), vec![(expr, value)])
+        }
+    };
+    if holds {
+        return Ok(());
+    }
+    Err(Error::Expectation(Expectation {
+        condition: condition.to_string(),
+        values: values
+            .into_iter()
+            .filter(|(expr, _)| !matches!(expr, Expr::String(_) | Expr::Int(_)))
+            .map(|(expr, value)| (expr.to_string(), value))
+            .collect(),
+    }))
+}
+
+/// Evaluate an expression, or return `None` if it refers to something without a value.
+fn value(expr: &Expr, previous: &Scope) -> Result<Option<Value>, Error> {
+    match evaluate(expr, previous) {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::Undefined(_) | Error::UnknownStep(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Compare two values, as integers if both are, and byte by byte otherwise. `matches` treats
+/// both values as text.
+fn compare(left: &Value, op: Comparison, right: &Value) -> Result<bool, Error> {
+    let text = |value: &Value| String::from_utf8_lossy(&value.clone().into_bytes()).into_owned();
+    let ordering = || match (left.as_int(), right.as_int()) {
+        (Some(left), Some(right)) => left.cmp(&right),
+        _ => left.clone().into_bytes().cmp(&right.clone().into_bytes()),
+    };
+    Ok(match op {
+        Comparison::Equal => ordering().is_eq(),
+        Comparison::NotEqual => ordering().is_ne(),
+        Comparison::Less => ordering().is_lt(),
+        Comparison::LessOrEqual => ordering().is_le(),
+        Comparison::Greater => ordering().is_gt(),
+        Comparison::GreaterOrEqual => ordering().is_ge(),
+        Comparison::Matches => regex::Regex::new(&text(right))
+            .map_err(|e| Error::InvalidPattern(e.to_string()))?
+            .is_match(&text(left)),
+    })
+}
+
+fn resolve(reference: &Reference, previous: &Scope) -> Result<Value, Error> {
+    if reference.path.is_empty() {
+        if let Some(value) = previous.variables.get(reference.step) {
+            return Ok(value.clone());
+        }
+    }
+    let Some(out) = previous.outputs.get(reference.step) else {
+        return Err(Error::UnknownStep(reference.step.to_owned()));
+    };
+    let undefined = || Error::Undefined(reference.to_string());
+    match (&out.parsed, reference.path.as_slice()) {
+        (_, [Accessor::Field("raw_request")]) => Ok(Value::Bytes(out.raw_request.clone())),
+        (_, [Accessor::Field("raw_response")]) => Ok(Value::Bytes(out.raw_response.clone())),
+        (_, [Accessor::Field("duration")]) => Ok(Value::Int(
+            out.duration.as_millis().try_into().unwrap_or(i64::MAX),
+        )),
+        (StepParsedOutput::HTTP(http), [Accessor::Field("response"), path @ ..]) => {
+            resolve_http(http, path).ok_or_else(undefined)
+        }
+        (StepParsedOutput::GraphQL(graphql), [Accessor::Field("response"), path @ ..]) => {
+            match path {
+                [Accessor::Field("data"), path @ ..] => graphql
+                    .data
+                    .as_ref()
+                    .and_then(|data| resolve_json(data, path))
+                    .ok_or_else(undefined),
+                [Accessor::Field("extensions"), path @ ..] => graphql
+                    .extensions
+                    .as_ref()
+                    .and_then(|extensions| resolve_json(extensions, path))
+                    .ok_or_else(undefined),
+                [Accessor::Field("errors"), Accessor::Index(i), Accessor::Field("message")] => {
+                    graphql
+                        .errors
+                        .get(*i)
+                        .map(|error| Value::String(error.message.clone()))
+                        .ok_or_else(undefined)
+                }
+                path => resolve_http(&graphql.http, path).ok_or_else(undefined),
+            }
+        }
+        (StepParsedOutput::GRPC(grpc), [Accessor::Field("response"), path @ ..]) => match path {
+            [Accessor::Field("status")] => grpc
+                .status
+                .map(|s| Value::Int(s.into()))
+                .ok_or_else(undefined),
+            [Accessor::Field("message")] => grpc
+                .message
+                .clone()
+                .map(Value::String)
+                .ok_or_else(undefined),
+            // Messages are rendered as JSON.
+            [Accessor::Field("messages"), Accessor::Index(i)] => grpc
+                .messages
+                .get(*i)
+                .and_then(|message| serde_json::to_vec(message).ok())
+                .map(Value::Bytes)
+                .ok_or_else(undefined),
+            [Accessor::Field(field @ ("headers" | "trailers")), Accessor::Key(name)] => {
+                let headers = if *field == "headers" {
+                    &grpc.http.headers
+                } else {
+                    &grpc.trailers
+                };
+                headers
+                    .get(*name)
+                    .map(|v| Value::Bytes(v.as_bytes().to_vec()))
+                    .ok_or_else(undefined)
+            }
+            _ => Err(undefined()),
+        },
+        (
+            StepParsedOutput::QUIC(_),
+            [Accessor::Field("response"), Accessor::Field("streams"), Accessor::Index(i)],
+        ) => out
+            .quic
+            .as_ref()
+            .and_then(|quic| quic.streams.get(*i))
+            .map(|stream| Value::Bytes(stream.received.clone()))
+            .ok_or_else(undefined),
+        (
+            StepParsedOutput::UDP(udp),
+            [Accessor::Field("response"), Accessor::Field("datagrams"), Accessor::Index(i)],
+        ) => udp
+            .received()
+            .nth(*i)
+            .map(|d| Value::Bytes(d.payload.clone()))
+            .ok_or_else(undefined),
+        _ => Err(undefined()),
+    }
+}
+
+fn resolve_http(http: &HTTPOutput, path: &[Accessor]) -> Option<Value> {
+    match path {
+        [Accessor::Field("status")] => Some(Value::Int(http.status.as_u16().into())),
+        [Accessor::Field("version")] => Some(Value::String(http.version.to_string())),
+        [Accessor::Field("body")] => Some(Value::Bytes(http.body.clone())),
+        [Accessor::Field("raw_body")] => Some(Value::Bytes(
+            http.raw_body.as_ref().unwrap_or(&http.body).clone(),
+        )),
+        [Accessor::Field("decode_error")] => http.decode_error.clone().map(Value::String),
+        [Accessor::Field("parsed"), path @ ..] => resolve_parsed(http.parsed_body.as_ref()?, path),
+        // A header name alone gets the first value, or an index picks among repeated headers.
+        [Accessor::Field(field @ ("headers" | "trailers")), Accessor::Key(name), rest @ ..] => {
+            let headers = if *field == "headers" {
+                &http.headers
+            } else {
+                &http.trailers
+            };
+            let i = match rest {
+                [] => 0,
+                [Accessor::Index(i)] => *i,
+                _ => return None,
+            };
+            headers
+                .get_all(*name)
+                .iter()
+                .nth(i)
+                .map(|v| Value::Bytes(v.as_bytes().to_vec()))
+        }
+        [Accessor::Field("chunks"), Accessor::Index(i), rest @ ..] => {
+            let chunk = http.chunks.get(*i)?;
+            match rest {
+                [] => Some(Value::Bytes(chunk.data.clone())),
+                [Accessor::Field("size")] => Some(Value::Int(chunk.size.try_into().ok()?)),
+                [Accessor::Field("extensions")] => Some(Value::Bytes(chunk.extensions.clone())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn resolve_parsed(parsed: &ParsedBody, path: &[Accessor]) -> Option<Value> {
+    match (parsed, path) {
+        (ParsedBody::JSON(json), path) => resolve_json(json, path),
+        (ParsedBody::Protobuf(message), path) => {
+            resolve_json(&serde_json::to_value(message).ok()?, path)
+        }
+        // A field name alone gets the first value, or an index picks among repeated fields.
+        (ParsedBody::Form(fields), [Accessor::Field(key) | Accessor::Key(key)]) => fields
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| Value::String(v.clone())),
+        (
+            ParsedBody::Form(fields),
+            [Accessor::Field(key) | Accessor::Key(key), Accessor::Index(i)],
+        ) => fields
+            .iter()
+            .filter(|(k, _)| k == key)
+            .nth(*i)
+            .map(|(_, v)| Value::String(v.clone())),
+        (ParsedBody::Multipart(parts), [Accessor::Index(i), Accessor::Field("body")]) => {
+            Some(Value::Bytes(parts.get(*i)?.body.clone()))
+        }
+        (
+            ParsedBody::Multipart(parts),
+            [Accessor::Index(i), Accessor::Field("headers"), Accessor::Key(name)],
+        ) => parts
+            .get(*i)?
+            .headers
+            .get(*name)
+            .map(|v| Value::Bytes(v.as_bytes().to_vec())),
+        _ => None,
+    }
+}
+
+/// Walk into a JSON value. Strings and integers become values of their own, while anything else
+/// is rendered as JSON.
+pub(super) fn resolve_json(json: &serde_json::Value, path: &[Accessor]) -> Option<Value> {
+    let mut json = json;
+    for accessor in path {
+        json = match accessor {
+            Accessor::Field(key) | Accessor::Key(key) => json.get(*key)?,
+            Accessor::Index(i) => json.get(*i)?,
+        };
+    }
+    Some(match json {
+        serde_json::Value::String(s) => Value::String(s.clone()),
+        serde_json::Value::Number(n) if n.is_i64() => Value::Int(n.as_i64()?),
+        json => Value::Bytes(json.to_string().into_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hyper::header::{HeaderName, HeaderValue};
+    use hyper::{HeaderMap, StatusCode};
+
+    use super::*;
+    use crate::exec::{GraphQLOutput, HTTPChunk, HTTPVersion};
+
+    #[test]
+    fn render_test() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "a=1".parse().unwrap());
+        headers.append("set-cookie", "b=2".parse().unwrap());
+        let previous = Scope {
+            outputs: HashMap::from([(
+                "login",
+                StepOutput {
+                    raw_request: b"GET / HTTP/1.1\r\n\r\n".to_vec(),
+                    raw_response: Vec::new(),
+                    tls: None,
+                    http2: None,
+                    quic: None,
+                    connection: None,
+                    duration: Duration::from_millis(1500),
+                    parsed: StepParsedOutput::HTTP(HTTPOutput {
+                        version: HTTPVersion::HTTP1_1,
+                        status: StatusCode::CREATED,
+                        headers,
+                        body: b"token123".to_vec(),
+                        raw_body: None,
+                        decode_error: None,
+                        parsed_body: None,
+                        chunks: vec![HTTPChunk {
+                            size: 8,
+                            extensions: b"sig=abc".to_vec(),
+                            data: b"token123".to_vec(),
+                        }],
+                        trailers: HeaderMap::from_iter([(
+                            HeaderName::from_static("x-checksum"),
+                            HeaderValue::from_static("99"),
+                        )]),
+                    }),
+                },
+            )]),
+            variables: HashMap::from([("id", Value::Int(7))]),
+            ..Scope::default()
+        };
+        let render = |text| render(&Template::parse(text).unwrap().1, &previous);
+
+        assert_eq!(
+            render("Bearer ${login.response.body}"),
+            Ok(b"Bearer token123".to_vec())
+        );
+        // Bodies that weren't decoded are their own raw body.
+        assert_eq!(
+            render("${login.response.raw_body}"),
+            Ok(b"token123".to_vec())
+        );
+        assert_eq!(
+            render("${login.response.status} ${login.response.version}"),
+            Ok(b"201 HTTP/1.1".to_vec())
+        );
+        assert_eq!(
+            render("${login.response.headers[\"set-cookie\"]};${login.response.headers[\"Set-Cookie\"][1]}"),
+            Ok(b"a=1;b=2".to_vec())
+        );
+        assert_eq!(
+            render("${login.response.chunks[0]} ${login.response.chunks[0].size} ${login.response.chunks[0].extensions} ${login.response.trailers[\"x-checksum\"]}"),
+            Ok(b"token123 8 sig=abc 99".to_vec())
+        );
+        assert!(render("${login.response.chunks[1]}").is_err());
+        assert_eq!(render("${login.duration}"), Ok(b"1500".to_vec()));
+        // Loop variables are referred to by name alone.
+        assert_eq!(render("/users/${id}"), Ok(b"/users/7".to_vec()));
+        let check = |text| check(&Condition::parse(text).unwrap().1, &previous);
+        assert_eq!(check("login.response.status == 201"), Ok(true));
+        assert_eq!(check("login.response.body != \"token123\""), Ok(false));
+        // Integers written as text still compare as numbers.
+        assert_eq!(
+            check("login.response.trailers[\"x-checksum\"] > 100"),
+            Ok(false)
+        );
+        assert_eq!(check("\"b\" >= \"abc\""), Ok(true));
+        assert_eq!(check("id < login.response.status"), Ok(true));
+        assert_eq!(
+            render("${logout.response.body}"),
+            Err(Error::UnknownStep("logout".to_owned()))
+        );
+        assert_eq!(
+            render("${login.response.headers[\"x-token\"]}"),
+            Err(Error::Undefined(
+                "login.response.headers[\"x-token\"]".to_owned()
+            ))
+        );
+        assert_eq!(
+            render("${login.request}"),
+            Err(Error::Undefined("login.request".to_owned()))
+        );
+    }
+
+    #[test]
+    fn render_graphql_test() {
+        let previous = Scope {
+            outputs: HashMap::from([(
+                "user",
+                StepOutput {
+                    raw_request: Vec::new(),
+                    raw_response: Vec::new(),
+                    tls: None,
+                    http2: None,
+                    quic: None,
+                    connection: None,
+                    duration: Duration::ZERO,
+                    parsed: StepParsedOutput::GraphQL(GraphQLOutput::parse(HTTPOutput {
+                        version: HTTPVersion::HTTP1_1,
+                        status: StatusCode::OK,
+                        headers: HeaderMap::new(),
+                        body: br#"{"data": {"user": {"id": 7, "name": "ann", "tags": ["a", "b"]}}, "errors": [{"message": "partial"}]}"#.to_vec(),
+                        raw_body: None,
+                        decode_error: None,
+                        parsed_body: None,
+                        chunks: Vec::new(),
+                        trailers: HeaderMap::new(),
+                    })),
+                },
+            )]),
+            ..Scope::default()
+        };
+        let render = |text| render(&Template::parse(text).unwrap().1, &previous);
+
+        assert_eq!(
+            render("${user.response.data.user.id} ${user.response.data.user[\"name\"]} ${user.response.data.user.tags[1]}"),
+            Ok(b"7 ann b".to_vec())
+        );
+        assert_eq!(
+            render("${user.response.data.user.tags}"),
+            Ok(br#"["a","b"]"#.to_vec())
+        );
+        assert_eq!(
+            render("${user.response.errors[0].message} ${user.response.status}"),
+            Ok(b"partial 200".to_vec())
+        );
+        assert_eq!(
+            render("${user.response.data.user.email}"),
+            Err(Error::Undefined("user.response.data.user.email".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::cmp::Reverse;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::Uri;
+use nom::combinator::{all_consuming, map, opt};
+use nom::multi::many0;
+use nom::sequence::pair;
+use regex::Regex;
+use ring::digest;
+
+use super::expr::{evaluate, resolve_json, Scope, Value};
+use super::graphql::percent_encode;
+use super::{Error, HTTPOutput, StepParsedOutput};
+use crate::util::ident;
+use crate::{Accessor, Call, Expr};
+
+/// Call one of the functions expressions can use, falling back to the functions of plugins. Like
+/// references, functions that look up something that isn't there fail with [`Error::Undefined`].
+pub(super) fn call(call: &Call, previous: &Scope) -> Result<Value, Error> {
+    let undefined = || Error::Undefined(call.to_string());
+    let invalid = |reason: &str| Error::InvalidCall {
+        call: call.to_string(),
+        reason: reason.to_owned(),
+    };
+    let bytes = |expr| Ok::<_, Error>(evaluate(expr, previous)?.into_bytes());
+    let text = |expr| Ok::<_, Error>(String::from_utf8_lossy(&bytes(expr)?).into_owned());
+
+    match (call.function, call.args.as_slice()) {
+        ("json", [value, path]) => {
+            let json: serde_json::Value =
+                serde_json::from_slice(&bytes(value)?).map_err(|_| invalid("not JSON"))?;
+            let path = text(path)?;
+            let path = json_path(&path).ok_or_else(|| invalid("invalid JSON path"))?;
+            resolve_json(&json, &path).ok_or_else(undefined)
+        }
+        ("regex", [value, pattern, group @ ..]) if group.len() <= 1 => {
+            let group = match group.first() {
+                Some(group) => evaluate(group, previous)?
+                    .as_int()
+                    .and_then(|group| usize::try_from(group).ok())
+                    .ok_or_else(|| invalid("the group must be a number"))?,
+                None => 0,
+            };
+            let regex =
+                Regex::new(&text(pattern)?).map_err(|e| Error::InvalidPattern(e.to_string()))?;
+            regex
+                .captures(&text(value)?)
+                .and_then(|captures| captures.get(group))
+                .map(|group| Value::String(group.as_str().to_owned()))
+                .ok_or_else(undefined)
+        }
+        ("header", [step, name]) => {
+            let name = text(name)?;
+            http_output(step, previous)?
+                .and_then(|http| http.headers.get(name.as_str()))
+                .map(|value| Value::Bytes(value.as_bytes().to_vec()))
+                .ok_or_else(undefined)
+        }
+        ("cookie", [step, name]) => {
+            let name = text(name)?;
+            http_output(step, previous)?
+                .and_then(|http| {
+                    http.headers
+                        .get_all(hyper::header::SET_COOKIE)
+                        .iter()
+                        .filter_map(|value| value.to_str().ok())
+                        .filter_map(|value| value.split(';').next()?.split_once('='))
+                        .find(|(key, _)| key.trim() == name)
+                })
+                .map(|(_, value)| Value::String(value.trim().to_owned()))
+                .ok_or_else(undefined)
+        }
+        ("cookie_jar", [name, url @ ..]) if url.len() <= 1 => {
+            let name = text(name)?;
+            let url = match url.first() {
+                Some(url) => Some(
+                    text(url)?
+                        .parse::<Uri>()
+                        .map_err(|_| invalid("invalid URL"))?,
+                ),
+                None => None,
+            };
+            let jar = previous.cookies.as_ref().ok_or_else(undefined)?;
+            let jar = jar.lock().unwrap_or_else(|e| e.into_inner());
+            let value = match &url {
+                // The cookie that goes first in the request's header.
+                Some(url) => {
+                    let secure = matches!(url.scheme_str(), Some("https" | "wss"));
+                    jar.matching(url, secure)
+                        .filter(|cookie| cookie.name == name)
+                        .min_by_key(|cookie| Reverse(cookie.path.len()))
+                        .map(|cookie| cookie.value.clone())
+                }
+                None => jar
+                    .cookies()
+                    .find(|cookie| cookie.name == name)
+                    .map(|cookie| cookie.value.clone()),
+            };
+            value.map(Value::String).ok_or_else(undefined)
+        }
+        ("base64", [value]) => Ok(Value::String(STANDARD.encode(bytes(value)?))),
+        ("base64_decode", [value]) => STANDARD
+            .decode(bytes(value)?)
+            .map(Value::Bytes)
+            .map_err(|_| invalid("not base64")),
+        ("url_encode", [value]) => Ok(Value::String(percent_encode(bytes(value)?))),
+        ("sha1" | "sha256" | "sha512", [value]) => {
+            let algorithm = match call.function {
+                "sha1" => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+                "sha256" => &digest::SHA256,
+                _ => &digest::SHA512,
+            };
+            let hash = digest::digest(algorithm, &bytes(value)?);
+            Ok(Value::String(
+                hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect(),
+            ))
+        }
+        (
+            "json" | "regex" | "header" | "cookie" | "cookie_jar" | "base64" | "base64_decode"
+            | "url_encode" | "sha1" | "sha256" | "sha512",
+            _,
+        ) => Err(invalid("wrong number of arguments")),
+        (function, args) => {
+            let plugin = previous
+                .plugins
+                .get(function)
+                .ok_or_else(|| Error::UnknownFunction(function.to_owned()))?;
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, previous))
+                .collect::<Result<_, _>>()?;
+            plugin.call(function, args, previous)?.ok_or_else(undefined)
+        }
+    }
+}
+
+/// The HTTP response of the step named by `expr`, if the step got one.
+fn http_output<'s>(expr: &Expr, previous: &'s Scope) -> Result<Option<&'s HTTPOutput>, Error> {
+    let step = match expr {
+        Expr::Reference(reference) if reference.path.is_empty() => reference.step,
+        _ => {
+            return Err(Error::InvalidCall {
+                call: expr.to_string(),
+                reason: "expected a step name".to_owned(),
+            })
+        }
+    };
+    let out = previous
+        .outputs
+        .get(step)
+        .ok_or_else(|| Error::UnknownStep(step.to_owned()))?;
+    Ok(match &out.parsed {
+        StepParsedOutput::HTTP(http) => Some(http),
+        StepParsedOutput::GraphQL(graphql) => Some(&graphql.http),
+        StepParsedOutput::GRPC(grpc) => Some(&grpc.http),
+        _ => None,
+    })
+}
+
+/// Parse a path into a JSON document, written like the end of a reference, as in
+/// `user.tags[0]` or `["user"]["name"]`. A leading `$` or `$.` is allowed.
+fn json_path(path: &str) -> Option<Vec<Accessor<'_>>> {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let path = path.strip_prefix('.').unwrap_or(path);
+    let (_, (first, rest)) = all_consuming(pair(
+        opt(map(ident, Accessor::Field)),
+        many0(Accessor::parse),
+    ))(path)
+    .ok()?;
+    Some(first.into_iter().chain(rest).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use hyper::{HeaderMap, StatusCode};
+
+    use super::*;
+    use crate::exec::{HTTPVersion, StepOutput};
+
+    #[test]
+    fn call_test() {
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", "theme=dark; Path=/".parse().unwrap());
+        headers.append("set-cookie", "session=s3cr=t; HttpOnly".parse().unwrap());
+        headers.append("x-id", "7".parse().unwrap());
+        let previous = Scope {
+            outputs: HashMap::from([(
+                "login",
+                StepOutput {
+                    raw_request: Vec::new(),
+                    raw_response: Vec::new(),
+                    tls: None,
+                    http2: None,
+                    quic: None,
+                    connection: None,
+                    duration: Duration::ZERO,
+                    parsed: StepParsedOutput::HTTP(HTTPOutput {
+                        version: HTTPVersion::HTTP1_1,
+                        status: StatusCode::OK,
+                        headers,
+                        body: br#"{"user": {"id": 7, "tags": ["a", "b"]}, "token": "id=42;"}"#
+                            .to_vec(),
+                        raw_body: None,
+                        decode_error: None,
+                        parsed_body: None,
+                        chunks: Vec::new(),
+                        trailers: HeaderMap::new(),
+                    }),
+                },
+            )]),
+            ..Scope::default()
+        };
+        let call = |text| match Expr::parse_all(text).unwrap() {
+            Expr::Call(c) => call(&c, &previous),
+            _ => panic!("expected a call"),
+        };
+        let string = |s: &str| Ok(Value::String(s.to_owned()));
+
+        assert_eq!(
+            call("json(login.response.body, \"user.id\")"),
+            Ok(Value::Int(7))
+        );
+        assert_eq!(
+            call("json(login.response.body, \"$.user.tags[1]\")"),
+            string("b")
+        );
+        assert_eq!(
+            call("json(login.response.body, \"user\")"),
+            Ok(Value::Bytes(br#"{"id":7,"tags":["a","b"]}"#.to_vec()))
+        );
+        assert_eq!(
+            call("json(login.response.body, \"user.name\")"),
+            Err(Error::Undefined(
+                "json(login.response.body, \"user.name\")".to_owned()
+            ))
+        );
+        assert_eq!(
+            call("regex(json(login.response.body, \"token\"), \"id=(\\d+)\", 1)"),
+            string("42")
+        );
+        assert_eq!(call("regex(login.response.body, \"\\d\")"), string("7"));
+        assert!(matches!(
+            call("regex(login.response.body, \"x\")"),
+            Err(Error::Undefined(_))
+        ));
+        assert_eq!(
+            call("header(login, \"X-Id\")"),
+            Ok(Value::Bytes(b"7".to_vec()))
+        );
+        assert_eq!(call("cookie(login, \"session\")"), string("s3cr=t"));
+        assert!(matches!(
+            call("cookie(login, \"missing\")"),
+            Err(Error::Undefined(_))
+        ));
+        assert_eq!(
+            call("header(logout, \"x-id\")"),
+            Err(Error::UnknownStep("logout".to_owned()))
+        );
+        assert!(matches!(
+            call("header(login.response, \"x-id\")"),
+            Err(Error::InvalidCall { .. })
+        ));
+        // Failing to evaluate the name isn't mistaken for a missing header.
+        assert_eq!(
+            call("header(login, nope(1))"),
+            Err(Error::UnknownFunction("nope".to_owned()))
+        );
+
+        assert_eq!(call("base64(\"hi?\")"), string("aGk/"));
+        assert_eq!(
+            call("base64_decode(\"aGk/\")"),
+            Ok(Value::Bytes(b"hi?".to_vec()))
+        );
+        assert_eq!(call("url_encode(\"a b&c\")"), string("a%20b%26c"));
+        assert_eq!(
+            call("sha256(\"abc\")"),
+            string("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            call("sha1(\"abc\")"),
+            string("a9993e364706816aba3e25717850c26c9cd0d89d")
+        );
+        assert_eq!(
+            call("base64(\"a\", \"b\")"),
+            Err(Error::InvalidCall {
+                call: "base64(\"a\", \"b\")".to_owned(),
+                reason: "wrong number of arguments".to_owned(),
+            })
+        );
+        assert_eq!(
+            call("md4(\"abc\")"),
+            Err(Error::UnknownFunction("md4".to_owned()))
+        );
+    }
+}
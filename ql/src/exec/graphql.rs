@@ -0,0 +1,350 @@
+use serde_json::{json, Map, Value};
+
+use super::{expr, http, HTTPOutput, StepInputs, StepOutput, StepParsedOutput};
+use crate::{GraphQLRequest, HTTPBody, HTTPRequest, Payload, Template};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphQLOutput {
+    /// The HTTP response carrying the result, whose body holds the raw JSON.
+    pub http: HTTPOutput,
+    /// The `data` member of the result, or `None` if it was missing or null.
+    pub data: Option<Value>,
+    pub errors: Vec<GraphQLError>,
+    pub extensions: Option<Value>,
+}
+
+/// An entry in the `errors` list of a GraphQL result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphQLError {
+    pub message: String,
+    pub locations: Vec<GraphQLLocation>,
+    /// The path to the response field that failed, made of field names and list indexes.
+    pub path: Vec<GraphQLPathSegment>,
+    pub extensions: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GraphQLLocation {
+    pub line: u64,
+    pub column: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphQLPathSegment {
+    Field(String),
+    Index(u64),
+}
+
+pub(super) async fn execute(
+    step: &GraphQLRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let query = String::from_utf8(expr::render(&step.query, inputs.previous)?)?;
+    let variables = match &step.variables {
+        Some(variables) => {
+            let variables: Value =
+                serde_json::from_slice(&expr::render(variables, inputs.previous)?)?;
+            if !variables.is_object() {
+                return Err("graphql variables must be a JSON object".into());
+            }
+            Some(variables)
+        }
+        None => None,
+    };
+
+    // Persisted queries send the hash of the query in place of the query itself.
+    let (query, extensions) = if step.options.persisted {
+        let hash = ring::digest::digest(&ring::digest::SHA256, query.as_bytes());
+        let hash: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        let extensions = json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } });
+        (None, Some(extensions))
+    } else {
+        (Some(query), None)
+    };
+    let mut params = Map::new();
+    if let Some(query) = query {
+        params.insert("query".to_owned(), Value::String(query));
+    }
+    if let Some(operation) = step.options.operation {
+        params.insert(
+            "operationName".to_owned(),
+            Value::String(operation.to_owned()),
+        );
+    }
+    if let Some(variables) = variables {
+        params.insert("variables".to_owned(), variables);
+    }
+    if let Some(extensions) = extensions {
+        params.insert("extensions".to_owned(), extensions);
+    }
+
+    // GET requests carry the operation in the query string, with variables and extensions
+    // encoded as JSON. Other methods send it all as a JSON body.
+    let mut endpoint = String::from_utf8(expr::render(&step.http.endpoint, inputs.previous)?)?;
+    let mut headers = step.http.headers.clone();
+    let body = if step.http.method.eq_ignore_ascii_case("GET") {
+        for (i, (key, val)) in params.iter().enumerate() {
+            let val = match val {
+                Value::String(s) => s.clone(),
+                val => val.to_string(),
+            };
+            endpoint.push(match (i, endpoint.contains('?')) {
+                (0, false) => '?',
+                _ => '&',
+            });
+            endpoint.push_str(key);
+            endpoint.push('=');
+            endpoint.push_str(&percent_encode(&val));
+        }
+        String::new()
+    } else {
+        if !has_header(&headers, "content-type") {
+            headers.push(("Content-Type", Template::literal("application/json")));
+        }
+        Value::Object(params).to_string()
+    };
+    if !has_header(&headers, "accept") {
+        headers.push((
+            "Accept",
+            Template::literal("application/graphql-response+json, application/json"),
+        ));
+    }
+
+    let req = HTTPRequest {
+        method: step.http.method,
+        endpoint: Template::literal(&endpoint),
+        version: step.http.version,
+        headers,
+        directives: step.http.directives.clone(),
+        body: HTTPBody::Payload(Payload::Raw(Template::literal(&body))),
+        tls: step.http.tls.clone(),
+        h2c: step.http.h2c,
+        connection: None,
+        options: step.http.options.clone(),
+    };
+    let mut out = http::execute(&req, inputs).await?;
+    let StepParsedOutput::HTTP(http) = out.parsed else {
+        unreachable!("http steps produce http output");
+    };
+    out.parsed = StepParsedOutput::GraphQL(GraphQLOutput::parse(http));
+    Ok(out)
+}
+
+impl GraphQLOutput {
+    /// Read the result out of an HTTP response. Members that are missing or don't have the shape
+    /// the GraphQL spec gives them are left empty, so a response that isn't a GraphQL result at
+    /// all still has its HTTP details.
+    pub fn parse(http: HTTPOutput) -> Self {
+        let mut result = match serde_json::from_slice(&http.body) {
+            Ok(Value::Object(result)) => result,
+            _ => Map::new(),
+        };
+        let errors = match result.remove("errors") {
+            Some(Value::Array(errors)) => {
+                errors.into_iter().filter_map(GraphQLError::parse).collect()
+            }
+            _ => Vec::new(),
+        };
+        GraphQLOutput {
+            data: result.remove("data").filter(|data| !data.is_null()),
+            errors,
+            extensions: result.remove("extensions"),
+            http,
+        }
+    }
+}
+
+impl GraphQLError {
+    fn parse(error: Value) -> Option<Self> {
+        let Value::Object(mut error) = error else {
+            return None;
+        };
+        let Some(Value::String(message)) = error.remove("message") else {
+            return None;
+        };
+        let locations = match error.remove("locations") {
+            Some(Value::Array(locations)) => locations
+                .iter()
+                .filter_map(|location| {
+                    Some(GraphQLLocation {
+                        line: location.get("line")?.as_u64()?,
+                        column: location.get("column")?.as_u64()?,
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let path = match error.remove("path") {
+            Some(Value::Array(path)) => path
+                .into_iter()
+                .filter_map(|segment| match segment {
+                    Value::String(field) => Some(GraphQLPathSegment::Field(field)),
+                    Value::Number(i) => i.as_u64().map(GraphQLPathSegment::Index),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(GraphQLError {
+            message,
+            locations,
+            path,
+            extensions: error.remove("extensions"),
+        })
+    }
+}
+
+fn has_header(headers: &[(&str, Template)], key: &str) -> bool {
+    headers.iter().any(|(k, _)| key.eq_ignore_ascii_case(k))
+}
+
+/// Escape everything but unreserved characters for use in a query string.
+pub(super) fn percent_encode(input: impl AsRef<[u8]>) -> String {
+    let input = input.as_ref();
+    let mut out = String::with_capacity(input.len());
+    for &b in input {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testutil::graphql_server;
+    use crate::exec::Executor;
+    use crate::Plan;
+
+    #[tokio::test]
+    async fn graphql_test() {
+        let port = graphql_server().await;
+        let plan = format!(
+            "http login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            graphql(operation=User) EOF\nPOST http://127.0.0.1:{port}/graphql\n\n\
+            query User($id: ID!) {{ user(id: $id) {{ name }} }}\n\
+            variables {{\"id\": \"${{login.response.body}}\"}}\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        executor.next().await.unwrap();
+        let out = executor.next().await.unwrap();
+        let StepParsedOutput::GraphQL(parsed) = out.parsed else {
+            panic!("expected a GraphQL response");
+        };
+        assert_eq!(parsed.http.status, 200);
+        assert!(parsed.errors.is_empty());
+        // The server echoes the request it received.
+        assert_eq!(
+            parsed.data,
+            Some(json!({
+                "method": "POST",
+                "request": {
+                    "query": "query User($id: ID!) { user(id: $id) { name } }",
+                    "operationName": "User",
+                    "variables": { "id": "token123" },
+                },
+            }))
+        );
+        assert!(out
+            .raw_request
+            .windows(30)
+            .any(|w| w == b"Content-Type: application/json"));
+    }
+
+    #[tokio::test]
+    async fn graphql_persisted_test() {
+        let port = graphql_server().await;
+        let plan = format!(
+            "graphql(persisted=true) EOF\nGET http://127.0.0.1:{port}/graphql\n\n{{ me }}\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        let StepParsedOutput::GraphQL(parsed) = executor.next().await.unwrap().parsed else {
+            panic!("expected a GraphQL response");
+        };
+        let hash = ring::digest::digest(&ring::digest::SHA256, b"{ me }");
+        let hash: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            parsed.data,
+            Some(json!({
+                "method": "GET",
+                "query": format!(
+                    "extensions=%7B%22persistedQuery%22%3A%7B%22sha256Hash%22%3A%22{}%22%2C%22version%22%3A1%7D%7D",
+                    hash
+                ),
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn graphql_errors_test() {
+        let port = graphql_server().await;
+        let plan = format!("graphql EOF\nPOST http://127.0.0.1:{port}/graphql\n\n{{ fail }}\nEOF");
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        let StepParsedOutput::GraphQL(parsed) = executor.next().await.unwrap().parsed else {
+            panic!("expected a GraphQL response");
+        };
+        assert_eq!(parsed.data, None);
+        assert_eq!(
+            parsed.errors,
+            vec![GraphQLError {
+                message: "failed".to_owned(),
+                locations: vec![GraphQLLocation { line: 1, column: 3 }],
+                path: vec![
+                    GraphQLPathSegment::Field("fail".to_owned()),
+                    GraphQLPathSegment::Index(0)
+                ],
+                extensions: Some(json!({ "code": "FAILED" })),
+            }]
+        );
+        assert_eq!(parsed.extensions, None);
+    }
+
+    #[test]
+    fn graphql_output_test() {
+        let http = |body: &str| HTTPOutput {
+            version: crate::exec::HTTPVersion::HTTP1_1,
+            status: hyper::StatusCode::BAD_GATEWAY,
+            headers: hyper::HeaderMap::new(),
+            body: body.as_bytes().to_vec(),
+            raw_body: None,
+            decode_error: None,
+            parsed_body: None,
+            chunks: Vec::new(),
+            trailers: hyper::HeaderMap::new(),
+        };
+        let parsed = GraphQLOutput::parse(http("<html>bad gateway</html>"));
+        assert_eq!(parsed.data, None);
+        assert!(parsed.errors.is_empty());
+        assert_eq!(parsed.http.body, b"<html>bad gateway</html>");
+
+        // Errors without a message are dropped, as are malformed locations and path segments.
+        let parsed = GraphQLOutput::parse(http(
+            r#"{"data": {"a": 1}, "errors": [{"path": ["a"]}, {"message": "m", "locations": [{"line": 1}], "path": ["a", true]}]}"#,
+        ));
+        assert_eq!(parsed.data, Some(json!({ "a": 1 })));
+        assert_eq!(
+            parsed.errors,
+            vec![GraphQLError {
+                message: "m".to_owned(),
+                locations: Vec::new(),
+                path: vec![GraphQLPathSegment::Field("a".to_owned())],
+                extensions: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn percent_encode_test() {
+        assert_eq!(
+            percent_encode("{ a(b: \"é\") }"),
+            "%7B%20a%28b%3A%20%22%C3%A9%22%29%20%7D"
+        );
+    }
+}
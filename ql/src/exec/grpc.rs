@@ -0,0 +1,430 @@
+use std::path::Path;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{CONTENT_TYPE, COOKIE, TE, USER_AGENT};
+use hyper::{HeaderMap, Method, Request, Uri, Version};
+use prost::Message;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use super::http::send_http2;
+use super::tee::{Tee, Transcript};
+use super::{
+    cookie, expr, tls, HTTP2Output, HTTPOutput, StepInputs, StepOutput, StepParsedOutput, TLSOutput,
+};
+use crate::{GRPCDescriptors, GRPCRequest, TLSOptions};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GRPCOutput {
+    /// The HTTP/2 response carrying the call, whose body holds the length-prefixed messages.
+    pub http: HTTPOutput,
+    pub trailers: HeaderMap,
+    /// The response messages, decoded as the method's output type.
+    pub messages: Vec<DynamicMessage>,
+    /// The `grpc-status` code, read from the trailers or from the headers of a trailers-only
+    /// response.
+    pub status: Option<u32>,
+    /// The percent-decoded `grpc-message`.
+    pub message: Option<String>,
+}
+
+pub(super) async fn execute(
+    step: &GRPCRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let pool = load_descriptors(&step.descriptors)?;
+    let service = pool
+        .get_service_by_name(step.service)
+        .ok_or_else(|| format!("no service named {} in the descriptors", step.service))?;
+    let method = service
+        .methods()
+        .find(|method| method.name() == step.method)
+        .ok_or_else(|| format!("{} has no method {}", step.service, step.method))?;
+
+    let body = String::from_utf8(expr::render(&step.body, inputs.previous)?)?;
+    let messages = parse_messages(&body, method.input())?;
+    if !method.is_client_streaming() && messages.len() != 1 {
+        return Err(format!("{} takes exactly one message", method.full_name()).into());
+    }
+    let mut frames = Vec::new();
+    for message in messages {
+        let message = message.encode_to_vec();
+        // Messages aren't compressed.
+        frames.push(0);
+        frames.extend_from_slice(&u32::try_from(message.len())?.to_be_bytes());
+        frames.extend(message);
+    }
+
+    let endpoint: Uri =
+        String::from_utf8(expr::render(&step.endpoint, inputs.previous)?)?.parse()?;
+    let tls = step
+        .tls
+        .clone()
+        .or_else(|| (endpoint.scheme_str() == Some("https")).then(TLSOptions::default));
+    let host = endpoint.host().ok_or("uri has no host")?;
+    let port = endpoint
+        .port_u16()
+        .unwrap_or(if tls.is_some() { 443 } else { 80 });
+    let address = format!("{}:{}", host, port);
+
+    let uri = Uri::builder()
+        .scheme(if tls.is_some() { "https" } else { "http" })
+        .authority(endpoint.authority().ok_or("request missing host")?.clone())
+        .path_and_query(format!("/{}/{}", service.full_name(), method.name()))
+        .build()?;
+    let mut req_builder = Request::builder()
+        .method(Method::POST)
+        .uri(uri.clone())
+        .version(Version::HTTP_2)
+        .header(CONTENT_TYPE, "application/grpc")
+        .header(TE, "trailers")
+        .header(USER_AGENT, "courier/0.1.0");
+    // Cookies set in the metadata are sent in place of the jar's.
+    let has_cookie = step
+        .metadata
+        .iter()
+        .any(|(key, _)| key.eq_ignore_ascii_case(COOKIE.as_str()));
+    if let Some(cookies) = cookie::header(inputs, &uri, tls.is_some()).filter(|_| !has_cookie) {
+        req_builder = req_builder.header(COOKIE, cookies);
+    }
+    for (key, val) in step.metadata.iter() {
+        req_builder = req_builder.header(*key, expr::render(val, inputs.previous)?);
+    }
+    let req = req_builder.body(Full::new(Bytes::from(frames)))?;
+
+    let stream = TcpStream::connect(address).await?;
+    let out = match &tls {
+        Some(options) => {
+            let (stream, tls) =
+                tls::handshake(stream, host, &[b"h2"], options, inputs.tls_configs).await?;
+            send(stream, req, method.output(), Some(tls)).await?
+        }
+        None => send(stream, req, method.output(), None).await?,
+    };
+    cookie::store(inputs, &uri, tls.is_some(), &out);
+    Ok(out)
+}
+
+async fn send<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: T,
+    req: Request<Full<Bytes>>,
+    output: MessageDescriptor,
+    tls: Option<TLSOutput>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let (stream, transcript) = Tee::new(stream);
+    let (head, body, trailers) = send_http2(stream, req).await?;
+    // Wait for the connection to drop the stream so the transcript is complete.
+    let Transcript { reads, writes } = transcript.await?;
+
+    let messages = decode_messages(&body, &output)?;
+    // A call that fails before sending any messages can put the status in the headers instead.
+    let status_headers = if trailers.contains_key("grpc-status") {
+        &trailers
+    } else {
+        &head.headers
+    };
+    let status = status_headers
+        .get("grpc-status")
+        .and_then(|status| status.to_str().ok()?.parse().ok());
+    let message = status_headers
+        .get("grpc-message")
+        .map(|message| percent_decode(message.as_bytes()));
+
+    Ok(StepOutput {
+        http2: HTTP2Output::parse(&writes, &reads),
+        quic: None,
+        connection: None,
+        duration: Duration::ZERO,
+        raw_request: writes,
+        raw_response: reads,
+        tls,
+        parsed: StepParsedOutput::GRPC(GRPCOutput {
+            http: HTTPOutput {
+                version: head.version.into(),
+                status: head.status,
+                headers: head.headers,
+                body,
+                raw_body: None,
+                decode_error: None,
+                parsed_body: None,
+                chunks: Vec::new(),
+                trailers: trailers.clone(),
+            },
+            trailers,
+            messages,
+            status,
+            message,
+        }),
+    })
+}
+
+/// Build a pool from the descriptor sets and `.proto` files named in a step's options.
+pub(super) fn load_descriptors(
+    descriptors: &GRPCDescriptors,
+) -> Result<DescriptorPool, Box<dyn std::error::Error + Send + Sync>> {
+    let mut pool = DescriptorPool::new();
+    for set in &descriptors.sets {
+        pool.decode_file_descriptor_set(std::fs::read(set)?.as_slice())?;
+    }
+    if descriptors.protos.is_empty() {
+        return Ok(pool);
+    }
+
+    let mut parser = protobuf_parse::Parser::new();
+    parser.pure().inputs(&descriptors.protos);
+    if descriptors.includes.is_empty() {
+        for proto in &descriptors.protos {
+            match Path::new(proto).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => parser.include(dir),
+                _ => parser.include("."),
+            };
+        }
+    } else {
+        parser.includes(&descriptors.includes);
+    }
+    // The parsed files include everything the inputs import.
+    let mut set = protobuf::descriptor::FileDescriptorSet::new();
+    set.file = parser.parse_and_typecheck()?.file_descriptors;
+    pool.decode_file_descriptor_set(protobuf::Message::write_to_bytes(&set)?.as_slice())?;
+    Ok(pool)
+}
+
+/// Parse request messages written either as a single bare text format message, or as a sequence
+/// of JSON objects or brace-wrapped text format messages for streaming calls.
+fn parse_messages(
+    body: &str,
+    descriptor: MessageDescriptor,
+) -> Result<Vec<DynamicMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let body = body.trim();
+    if !body.starts_with('{') {
+        return Ok(vec![DynamicMessage::parse_text_format(descriptor, body)?]);
+    }
+    let json = serde_json::Deserializer::from_str(body).into_iter::<serde_json::Value>();
+    if let Ok(values) = json.collect::<Result<Vec<_>, _>>() {
+        return values
+            .into_iter()
+            .map(|value| Ok(DynamicMessage::deserialize(descriptor.clone(), value)?))
+            .collect();
+    }
+    split_messages(body)
+        .ok_or("unbalanced braces between messages")?
+        .into_iter()
+        .map(|text| Ok(DynamicMessage::parse_text_format(descriptor.clone(), text)?))
+        .collect()
+}
+
+/// Split brace-wrapped text format messages like `{ name: "a" } { name: "b" }`, returning the
+/// text inside each pair of braces. Returns `None` if there's anything between the messages or
+/// the braces don't balance.
+fn split_messages(input: &str) -> Option<Vec<&str>> {
+    let mut messages = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut comment = false;
+    for (i, c) in input.char_indices() {
+        if comment {
+            comment = c != '\n';
+            continue;
+        }
+        if let Some(q) = quote {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                _ if c == q => quote = None,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '#' => comment = true,
+            '{' => {
+                if depth == 0 {
+                    start = i + 1;
+                }
+                depth += 1;
+            }
+            '}' => {
+                depth -= 1;
+                match depth {
+                    0 => messages.push(&input[start..i]),
+                    d if d < 0 => return None,
+                    _ => {}
+                }
+            }
+            _ if depth == 0 && !c.is_whitespace() => return None,
+            _ => {}
+        }
+    }
+    (depth == 0).then_some(messages)
+}
+
+/// Split a response body into its length-prefixed messages and decode each one.
+fn decode_messages(
+    mut body: &[u8],
+    descriptor: &MessageDescriptor,
+) -> Result<Vec<DynamicMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut messages = Vec::new();
+    while !body.is_empty() {
+        let len = match body.get(1..5) {
+            Some(len) => u32::from_be_bytes(len.try_into()?) as usize,
+            None => return Err("truncated gRPC message".into()),
+        };
+        let message = body.get(5..5 + len).ok_or("truncated gRPC message")?;
+        if body[0] != 0 {
+            return Err("compressed gRPC messages aren't supported".into());
+        }
+        messages.push(DynamicMessage::decode(descriptor.clone(), message)?);
+        body = &body[5 + len..];
+    }
+    Ok(messages)
+}
+
+/// Decode `%XX` escapes, which `grpc-message` uses for anything outside printable ASCII.
+pub(super) fn percent_decode(input: &[u8]) -> String {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let hex = input
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (input[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use prost_reflect::Value;
+
+    use super::*;
+    use crate::exec::testutil::{grpc_server, TestInputs};
+    use crate::exec::HTTP2FrameKind;
+    use crate::{Step, StepBody};
+
+    const PROTO: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/greeter.proto");
+
+    async fn call(port: u16, method: &str, body: &str) -> (StepOutput, GRPCOutput) {
+        let plan = format!(
+            "grpc(proto=\"{}\") EOF\ntest.Greeter/{} http://127.0.0.1:{}\n\n{}\nEOF",
+            PROTO, method, port, body
+        );
+        let (_, Step { body, .. }) = Step::parse(&plan).unwrap();
+        let StepBody::GRPC(req) = body else {
+            panic!("expected a grpc step");
+        };
+        let out = execute(&req, &TestInputs::default().get()).await.unwrap();
+        let StepParsedOutput::GRPC(parsed) = out.parsed.clone() else {
+            panic!("expected a gRPC response");
+        };
+        (out, parsed)
+    }
+
+    fn replies(out: &GRPCOutput) -> Vec<String> {
+        out.messages
+            .iter()
+            .map(
+                |message| match message.get_field_by_name("message").as_deref() {
+                    Some(Value::String(text)) => text.clone(),
+                    other => panic!("unexpected message field {:?}", other),
+                },
+            )
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn grpc_unary_test() {
+        let port = grpc_server().await;
+        let (out, parsed) = call(port, "SayHello", "name: \"world\"").await;
+        assert_eq!(replies(&parsed), vec!["Hello world"]);
+        assert_eq!(parsed.status, Some(0));
+        assert_eq!(parsed.http.status, hyper::StatusCode::OK);
+        assert!(parsed.trailers.contains_key("grpc-status"));
+
+        let http2 = out.http2.unwrap();
+        assert!(http2
+            .sent
+            .iter()
+            .any(|frame| frame.kind == HTTP2FrameKind::Data && frame.stream_id == 1));
+
+        let (_, parsed) = call(port, "SayHello", "name: \"error\"").await;
+        assert_eq!(parsed.messages, Vec::new());
+        assert_eq!(parsed.status, Some(3));
+        assert_eq!(parsed.message.as_deref(), Some("bad name: \"error\""));
+    }
+
+    #[tokio::test]
+    async fn grpc_streaming_test() {
+        let port = grpc_server().await;
+        let (_, parsed) = call(
+            port,
+            "SayHelloToAll",
+            "{\"name\": \"a\"}\n{\"name\": \"b\"}",
+        )
+        .await;
+        assert_eq!(replies(&parsed), vec!["Hello a, b"]);
+
+        let (_, parsed) = call(
+            port,
+            "SayHelloRepeatedly",
+            "{\"name\": \"c\", \"times\": 2}",
+        )
+        .await;
+        assert_eq!(replies(&parsed), vec!["Hello c #1", "Hello c #2"]);
+
+        let (_, parsed) = call(port, "Chat", "{ name: \"d\" }\n{ name: \"{e}\" # }\n}").await;
+        assert_eq!(replies(&parsed), vec!["Hello d", "Hello {e}"]);
+        assert_eq!(parsed.status, Some(0));
+    }
+
+    #[tokio::test]
+    async fn grpc_descriptor_set_test() {
+        let mut parser = protobuf_parse::Parser::new();
+        parser
+            .pure()
+            .include(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata"));
+        parser.input(PROTO);
+        let set =
+            protobuf::Message::write_to_bytes(&parser.file_descriptor_set().unwrap()).unwrap();
+        let path = std::env::temp_dir().join(format!("courier-test-{}.desc", std::process::id()));
+        std::fs::write(&path, set).unwrap();
+
+        let pool = load_descriptors(&GRPCDescriptors {
+            sets: vec![path.to_str().unwrap()],
+            ..Default::default()
+        });
+        std::fs::remove_file(&path).unwrap();
+        assert!(pool.unwrap().get_service_by_name("test.Greeter").is_some());
+    }
+
+    #[test]
+    fn split_messages_test() {
+        assert_eq!(
+            split_messages("{ a: 1 }\n{ b: \"}\" # }\n c { d: 2 } }"),
+            Some(vec![" a: 1 ", " b: \"}\" # }\n c { d: 2 } "])
+        );
+        assert_eq!(split_messages("{ a: 1 } b: 2"), None);
+        assert_eq!(split_messages("{ a: 1 "), None);
+        assert_eq!(split_messages("}"), None);
+    }
+
+    #[test]
+    fn percent_decode_test() {
+        assert_eq!(percent_decode(b"caf%C3%A9 100%"), "café 100%");
+    }
+}
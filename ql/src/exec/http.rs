@@ -1,24 +1,51 @@
 use std::fmt::Display;
-use std::future;
-use std::ops::DerefMut;
-use std::pin::Pin;
-use std::task::Poll;
-
-use bytes::Buf;
-use http_body_util::BodyExt;
-use hyper::{HeaderMap, Request, StatusCode, Version};
-use tokio::io::{self, AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use hyper::http::response::Parts;
+use hyper::{HeaderMap, Request, StatusCode, Uri, Version};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
-use super::{StepInputs, StepOutput, StepParsedOutput};
-use crate::HTTPRequest;
+use super::connection::Target;
+use super::tee::{Tee, Transcript};
+use super::{
+    body, cookie, encoding, expr, http2, http3, raw, tls, HTTP2Output, ParsedBody, StepInputs,
+    StepOutput, StepParsedOutput, TLSOutput,
+};
+use crate::{HTTPOptions, HTTPRequest, HeaderDirective, Protocol, TLSOptions, H2C};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct HTTPOutput {
     pub version: HTTPVersion,
     pub status: StatusCode,
     pub headers: HeaderMap,
+    /// The body with any content codings removed.
     pub body: Vec<u8>,
+    /// The body as it arrived, if it was decoded.
+    pub raw_body: Option<Vec<u8>>,
+    /// Why the body couldn't be decoded, in which case `body` is the body as it arrived.
+    pub decode_error: Option<String>,
+    /// The body read according to its `Content-Type`, for the formats that are understood.
+    pub parsed_body: Option<ParsedBody>,
+    /// The chunks of a chunked HTTP/1 body as they arrived, ending with the last chunk.
+    pub chunks: Vec<HTTPChunk>,
+    /// The fields sent after the body.
+    pub trailers: HeaderMap,
+}
+
+/// One chunk of a chunked body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HTTPChunk {
+    /// The size from the chunk's size line, which may not match the data if the connection
+    /// closed early.
+    pub size: usize,
+    /// The chunk extensions after the size, without the leading `;`.
+    pub extensions: Vec<u8>,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -61,72 +88,426 @@ pub(super) async fn execute(
     step: &HTTPRequest<'_>,
     inputs: &StepInputs<'_>,
 ) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let mut out = if step.version == Protocol::HTTP3 {
+        http3::execute(step, inputs).await?
+    } else {
+        execute_tcp(step, inputs).await?
+    };
+    if let StepParsedOutput::HTTP(http) = &mut out.parsed {
+        read_body(http, &step.options)?;
+    }
+    Ok(out)
+}
+
+/// Decode a response's body if the options ask for it, then parse it according to its type. A
+/// body that can't be decoded is kept as it arrived, with the reason in `decode_error`, so the
+/// response can still be inspected.
+pub(super) fn read_body(
+    http: &mut HTTPOutput,
+    options: &HTTPOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if options.decode {
+        match encoding::decode(&http.headers, &http.body) {
+            Ok(Some(decoded)) => http.raw_body = Some(std::mem::replace(&mut http.body, decoded)),
+            Ok(None) => {}
+            Err(e) => http.decode_error = Some(e.to_string()),
+        }
+    }
+    http.parsed_body = body::parse(&http.headers, &http.body, options)?;
+    Ok(())
+}
+
+/// Send a request over HTTP/1 or HTTP/2.
+async fn execute_tcp(
+    step: &HTTPRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let endpoint: Uri =
+        String::from_utf8(expr::render(&step.endpoint, inputs.previous)?)?.parse()?;
+
+    // Use TLS if the endpoint asks for it, even when the step didn't set any TLS options.
+    let tls = step
+        .tls
+        .clone()
+        .or_else(|| (endpoint.scheme_str() == Some("https")).then(TLSOptions::default));
+
     // Get the host and the port
-    let host = step.endpoint.host().expect("uri has no host");
-    let port = step.endpoint.port_u16().unwrap_or(80);
+    let host = endpoint.host().ok_or("uri has no host")?;
+    let port = endpoint
+        .port_u16()
+        .unwrap_or(if tls.is_some() { 443 } else { 80 });
 
     let address = format!("{}:{}", host, port);
 
-    // Open a TCP connection to the remote host
-    let stream = TcpStream::connect(address).await?;
-    let stream = Tee::new(stream);
+    // An h2c upgrade starts out as an HTTP/1.1 request.
+    let mode = match (step.version, step.h2c, &tls) {
+        (Protocol::HTTP2, Some(_), Some(_)) => return Err("h2c can't be used with TLS".into()),
+        (Protocol::HTTP2, Some(H2C::Upgrade), None) => Mode::H2CUpgrade,
+        (Protocol::HTTP2, _, _) => Mode::HTTP2,
+        _ => Mode::HTTP1,
+    };
+    let version = match (step.version, mode) {
+        (Protocol::HTTP1_0, _) => Version::HTTP_10,
+        (_, Mode::HTTP2) => Version::HTTP_2,
+        _ => Version::HTTP_11,
+    };
 
     // Prepare the request.
-    let authority = step
-        .endpoint
-        .authority()
-        .ok_or("request missing host")?
-        .clone();
-    let default_headers = [
+    let authority = endpoint.authority().ok_or("request missing host")?.clone();
+    let mut default_headers = vec![
         (hyper::header::HOST, authority.as_str()),
         (hyper::header::USER_AGENT, "courier/0.1.0"),
     ];
-    let mut req_builder = Request::builder()
-        .method(step.method)
-        .uri(step.endpoint.clone());
-    for (k, v) in default_headers {
-        if !contains_header(step, k.as_str()) {
-            req_builder = req_builder.header(k, v);
+    let cookies = cookie::header(inputs, &endpoint, tls.is_some());
+    if let Some(cookies) = &cookies {
+        default_headers.push((hyper::header::COOKIE, cookies));
+    }
+    if mode == Mode::H2CUpgrade {
+        // Our initial settings are all defaults, so the encoded SETTINGS payload is empty.
+        default_headers.extend([
+            (hyper::header::CONNECTION, "Upgrade, HTTP2-Settings"),
+            (hyper::header::UPGRADE, "h2c"),
+            (HeaderName::from_static("http2-settings"), ""),
+        ]);
+    }
+    // HTTP/1.0 has no Host header, and HTTP/2 sends the authority as a pseudo-header.
+    if version != Version::HTTP_11 {
+        default_headers.retain(|(k, _)| k != hyper::header::HOST);
+    }
+    let req = build_request(step, endpoint.clone(), version, default_headers, inputs)?;
+    let alpn: &[u8] = match step.version {
+        Protocol::HTTP1_0 => b"http/1.0",
+        Protocol::HTTP1_1 => b"http/1.1",
+        Protocol::HTTP2 => b"h2",
+        Protocol::HTTP3 => unreachable!("http/3 requests are sent over quic"),
+    };
+
+    let out = if let Some(connection) = &step.connection {
+        if mode != Mode::HTTP1 {
+            return Err("only HTTP/1 connections can be shared between steps".into());
+        }
+        let head_request = req.method.eq_ignore_ascii_case("HEAD");
+        let (bytes, pauses) = req.encode_http1();
+        let target = Target {
+            host,
+            port,
+            tls: tls.as_ref(),
+            alpn,
+        };
+        let mut connections = inputs.connections.lock().await;
+        connections
+            .send(
+                connection,
+                target,
+                &bytes,
+                &pauses,
+                head_request,
+                inputs.tls_configs,
+            )
+            .await?
+    } else {
+        // Open a TCP connection to the remote host, wrapping it in TLS if requested so the Tee
+        // records the plaintext HTTP exchange.
+        let stream = TcpStream::connect(address).await?;
+        match &tls {
+            Some(options) => {
+                let (stream, tls) =
+                    tls::handshake(stream, host, &[alpn], options, inputs.tls_configs).await?;
+                send(stream, req, mode, Some(tls)).await?
+            }
+            None => send(stream, req, mode, None).await?,
+        }
+    };
+    cookie::store(inputs, &endpoint, tls.is_some(), &out);
+    Ok(out)
+}
+
+/// A request ready to send, with its headers in the order they go out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct PreparedRequest {
+    pub method: String,
+    pub target: Uri,
+    pub version: Version,
+    /// Header names keep the case they were written with.
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    /// Where to pause while sending a body that's already in the chunked encoding, as offsets
+    /// into the body. Other bodies have no pauses.
+    pub chunked: Option<Vec<(usize, Duration)>>,
+}
+
+impl PreparedRequest {
+    /// Write the request as HTTP/1 with its headers exactly as they are, returning it along with
+    /// where to pause while sending it. Unless the body is already chunked, it's sent as a single
+    /// chunk if the final transfer coding is `chunked`.
+    pub(super) fn encode_http1(&self) -> (Vec<u8>, Vec<(usize, Duration)>) {
+        let version = if self.version == Version::HTTP_10 {
+            "HTTP/1.0"
+        } else {
+            "HTTP/1.1"
+        };
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, version).into_bytes();
+        for (key, val) in &self.headers {
+            out.extend_from_slice(key.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(val);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+        if let Some(pauses) = &self.chunked {
+            let head = out.len();
+            out.extend_from_slice(&self.body);
+            let pauses = pauses
+                .iter()
+                .map(|(offset, delay)| (head + offset, *delay))
+                .collect();
+            return (out, pauses);
         }
+        if self.is_chunked() {
+            if !self.body.is_empty() {
+                out.extend_from_slice(format!("{:X}\r\n", self.body.len()).as_bytes());
+                out.extend_from_slice(&self.body);
+                out.extend_from_slice(b"\r\n");
+            }
+            out.extend_from_slice(b"0\r\n\r\n");
+        } else {
+            out.extend_from_slice(&self.body);
+        }
+        (out, Vec::new())
     }
-    for (key, val) in step.headers.iter() {
-        req_builder = req_builder.header(*key, *val)
+
+    /// Convert the request for sending with hyper, which lowercases header names and sends
+    /// repeated headers together.
+    pub(super) fn into_hyper(
+        self,
+    ) -> Result<Request<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
+        if self.chunked.is_some() {
+            return Err("chunked bodies can only be sent over HTTP/1".into());
+        }
+        let mut builder = Request::builder()
+            .method(self.method.as_str())
+            .uri(self.target)
+            .version(self.version);
+        for (key, val) in self.headers {
+            builder = builder.header(key, val);
+        }
+        Ok(builder.body(Full::new(Bytes::from(self.body)))?)
     }
-    let req = req_builder.body(step.body.to_owned())?;
 
-    // Perform a TCP handshake
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(stream).await?;
+    fn is_chunked(&self) -> bool {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(TRANSFER_ENCODING.as_str()))
+            .flat_map(|(_, val)| val.split(|&b| b == b','))
+            .last()
+            .is_some_and(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+    }
+}
 
-    // Wrap conn in an Option to convince the compiler that it's ok to move
-    // conn out of the closure even if it may be called again (it won't).
-    let mut conn = Some(conn);
-    let (parts, (head, body)) = futures::try_join!(
-        future::poll_fn(move |cx| {
-            futures::ready!(conn.as_mut().unwrap().poll_without_shutdown(cx))?;
-            Poll::Ready(Ok::<_, hyper::Error>(conn.take().unwrap().into_parts()))
-        }),
-        async move {
-            let res = sender.send_request(req).await?;
-            Ok(res.into_parts())
+/// Fill in the step's headers and body, adding any of the default headers that the step doesn't
+/// set itself. The step's directives decide where the default headers go. Otherwise the given
+/// defaults go before the step's headers, and the `Content-Type` of a structured body and the
+/// `Content-Length` or `Transfer-Encoding` of an HTTP/1 body go after them.
+pub(super) fn build_request(
+    step: &HTTPRequest,
+    target: Uri,
+    version: Version,
+    default_headers: Vec<(HeaderName, &str)>,
+    inputs: &StepInputs,
+) -> Result<PreparedRequest, Box<dyn std::error::Error + Send + Sync>> {
+    let mut headers = Vec::with_capacity(step.headers.len());
+    for (key, val) in &step.headers {
+        headers.push((key.to_string(), expr::render(val, inputs.previous)?));
+    }
+    // Bodies are rendered and encoded according to the step's headers, skipping any that aren't
+    // valid.
+    let header_map: HeaderMap = headers
+        .iter()
+        .filter_map(|(key, val)| {
+            Some((
+                HeaderName::from_bytes(key.as_bytes()).ok()?,
+                HeaderValue::from_bytes(val).ok()?,
+            ))
+        })
+        .collect();
+    let rendered = body::render(&step.body, &step.options, &header_map, inputs)?;
+    let mut body = rendered.bytes;
+    // Chunks are sent as written.
+    if step.options.encode && rendered.chunked.is_none() {
+        body = encoding::encode(&header_map, body)?;
+    }
+
+    // Each default header is marked with whether it goes before the step's headers.
+    let mut defaults: Vec<(HeaderName, Vec<u8>, bool)> = default_headers
+        .into_iter()
+        .map(|(key, val)| (key, val.as_bytes().to_vec(), true))
+        .collect();
+    if let Some(content_type) = rendered.content_type {
+        defaults.push((CONTENT_TYPE, content_type.into_bytes(), false));
+    }
+    let http1 = version == Version::HTTP_10 || version == Version::HTTP_11;
+    if rendered.chunked.is_some() {
+        defaults.push((TRANSFER_ENCODING, b"chunked".to_vec(), false));
+    } else if http1 && !body.is_empty() && !contains_header(step, TRANSFER_ENCODING.as_str()) {
+        defaults.push((CONTENT_LENGTH, body.len().to_string().into_bytes(), false));
+    }
+    defaults.retain(|(key, _, _)| {
+        !contains_header(step, key.as_str())
+            && !step.directives.iter().any(|directive| match directive {
+                HeaderDirective::Omit(None) => true,
+                HeaderDirective::Omit(Some(name)) => key.as_str().eq_ignore_ascii_case(name),
+                HeaderDirective::Default { .. } => false,
+            })
+    });
+
+    // Put the defaults in place, working through the positions the directives name.
+    let mut placed: Vec<Vec<(HeaderName, Vec<u8>, bool)>> = vec![Vec::new(); headers.len() + 1];
+    for directive in &step.directives {
+        if let HeaderDirective::Default { position, name } = directive {
+            let (here, rest) = defaults.into_iter().partition(|(key, _, _)| {
+                name.is_none_or(|name| key.as_str().eq_ignore_ascii_case(name))
+            });
+            placed[*position].extend::<Vec<_>>(here);
+            defaults = rest;
         }
-    )?;
+    }
+    let (before, after): (Vec<_>, Vec<_>) =
+        defaults.into_iter().partition(|(_, _, before)| *before);
+    placed[0].splice(0..0, before);
+    placed[headers.len()].extend(after);
+
+    let mut ordered =
+        Vec::with_capacity(headers.len() + placed.iter().map(Vec::len).sum::<usize>());
+    let mut headers = headers.into_iter();
+    for defaults in placed {
+        ordered.extend(
+            defaults
+                .into_iter()
+                .map(|(key, val, _)| (key.as_str().to_owned(), val)),
+        );
+        ordered.extend(headers.next());
+    }
+
+    Ok(PreparedRequest {
+        method: step.method.to_owned(),
+        target,
+        version,
+        headers: ordered,
+        body,
+        chunked: rendered.chunked,
+    })
+}
 
-    let mut body_bytes = body.collect().await?.aggregate();
-    let mut body = Vec::with_capacity(body_bytes.remaining());
-    body_bytes.copy_to_slice(&mut body);
+/// How a request is sent over the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    HTTP1,
+    HTTP2,
+    /// Send an HTTP/1.1 request asking to upgrade, then read the response over HTTP/2 if the
+    /// server switches protocols.
+    H2CUpgrade,
+}
+
+async fn send<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: T,
+    req: PreparedRequest,
+    mode: Mode,
+    tls: Option<TLSOutput>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    // HTTP/1 requests are written by hand so their headers go out exactly as prepared.
+    if mode == Mode::HTTP1 {
+        let head_request = req.method.eq_ignore_ascii_case("HEAD");
+        let (bytes, pauses) = req.encode_http1();
+        return raw::send(stream, &bytes, &pauses, head_request, tls).await;
+    }
+    let (stream, transcript) = Tee::new(stream);
+    let (head, body, trailers) = if mode == Mode::HTTP2 {
+        send_http2(stream, req.into_hyper()?).await?
+    } else {
+        let (head, body) = send_h2c_upgrade(stream, req.into_hyper()?).await?;
+        (head, body, HeaderMap::new())
+    };
+    // Wait for the connection to drop the stream so the transcript is complete.
+    let Transcript { reads, writes } = transcript.await?;
 
     Ok(StepOutput {
-        raw_request: parts.io.writes,
-        raw_response: parts.io.reads,
+        http2: HTTP2Output::parse(&writes, &reads),
+        quic: None,
+        connection: None,
+        duration: Duration::ZERO,
+        raw_request: writes,
+        raw_response: reads,
+        tls,
         parsed: StepParsedOutput::HTTP(HTTPOutput {
             status: head.status,
             headers: head.headers,
             version: head.version.into(),
             body,
+            raw_body: None,
+            decode_error: None,
+            parsed_body: None,
+            chunks: Vec::new(),
+            trailers,
         }),
     })
 }
 
+/// Send a request over HTTP/2, returning the response's head, body and trailers.
+pub(super) async fn send_http2<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: Tee<T>,
+    req: Request<Full<Bytes>>,
+) -> Result<(Parts, Vec<u8>, HeaderMap), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut sender, conn) = hyper::client::conn::http2::Builder::new()
+        .executor(TokioExecutor)
+        .handshake(stream)
+        .await?;
+
+    // The connection finishes once the sender is dropped at the end of the request.
+    let (_, response) = futures::try_join!(conn, async move {
+        let (head, body) = sender.send_request(req).await?.into_parts();
+        let body = body.collect().await?;
+        let trailers = body.trailers().cloned().unwrap_or_default();
+        Ok((head, body.to_bytes().to_vec(), trailers))
+    })?;
+    Ok(response)
+}
+
+async fn send_h2c_upgrade<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: Tee<T>,
+    req: Request<Full<Bytes>>,
+) -> Result<(Parts, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(stream).await?;
+    // The connection finishes once it hands the stream over to the upgrade, or once the sender is
+    // dropped if the server doesn't switch protocols.
+    let conn = tokio::spawn(conn);
+
+    let res = sender.send_request(req).await?;
+    if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+        let (head, body) = res.into_parts();
+        let body = body.collect().await?.to_bytes().to_vec();
+        drop(sender);
+        conn.await??;
+        return Ok((head, body));
+    }
+    let upgraded = hyper::upgrade::on(res).await?;
+    conn.await??;
+    http2::upgraded_response(upgraded).await
+}
+
+/// Runs the background tasks of HTTP/2 connections on the tokio runtime.
+#[derive(Clone, Copy)]
+pub(super) struct TokioExecutor;
+
+impl<F> hyper::rt::Executor<F> for TokioExecutor
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    fn execute(&self, fut: F) {
+        tokio::spawn(fut);
+    }
+}
+
 fn contains_header(step: &HTTPRequest, key: &str) -> bool {
     step.headers
         .iter()
@@ -134,57 +515,418 @@ fn contains_header(step: &HTTPRequest, key: &str) -> bool {
         .is_some()
 }
 
-struct Tee<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> {
-    inner: T,
-    pub reads: Vec<u8>,
-    pub writes: Vec<u8>,
-}
+#[cfg(test)]
+mod tests {
 
-impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Tee<T> {
-    pub fn new(wrap: T) -> Self {
-        Tee {
-            inner: wrap,
-            reads: Vec::new(),
-            writes: Vec::new(),
+    use super::*;
+    use crate::exec::testutil::{
+        h2_server, h2c_upgrade_server, http_server, raw_server, tls_server, tls_server_at,
+        TestInputs,
+    };
+    use crate::exec::{Executor, HTTP2Frame, HTTP2FrameKind, TLSVersion};
+    use crate::{HTTPBody, Payload, Plan, TLSVerify, Template};
+
+    fn request<'a>(endpoint: &'a str, verify: TLSVerify<'a>) -> HTTPRequest<'a> {
+        HTTPRequest {
+            method: "GET",
+            endpoint: Template::literal(endpoint),
+            version: crate::Protocol::HTTP1_1,
+            headers: Vec::new(),
+            directives: Vec::new(),
+            body: HTTPBody::Payload(Payload::Raw(Template::default())),
+            tls: Some(TLSOptions { verify }),
+            h2c: None,
+            connection: None,
+            options: HTTPOptions::default(),
         }
     }
-}
 
-impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncRead for Tee<T> {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        let old_len = buf.filled().len();
-        let poll = Pin::new(&mut self.deref_mut().inner).poll_read(cx, buf);
-        self.reads.extend_from_slice(&buf.filled()[old_len..]);
-        poll
+    #[tokio::test]
+    async fn https_insecure_test() {
+        let (port, cert) = tls_server().await;
+        let inputs = TestInputs::default();
+        let endpoint = format!("https://localhost:{}/", port);
+        let out = execute(&request(&endpoint, TLSVerify::Insecure), &inputs.get())
+            .await
+            .unwrap();
+
+        let tls = out.tls.unwrap();
+        assert_eq!(tls.version, TLSVersion::TLS1_3);
+        assert!(tls.cipher_suite.starts_with("TLS13_"));
+        assert_eq!(tls.sni.as_deref(), Some("localhost"));
+        assert_eq!(tls.alpn, Some(b"http/1.1".to_vec()));
+        assert_eq!(tls.server_certificates[0].der, cert.der().to_vec());
+        assert_eq!(
+            tls.server_certificates[0].pem(),
+            cert.pem().replace("\r\n", "\n")
+        );
+        assert!(!tls.resumed);
+
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.status, StatusCode::OK);
+        assert_eq!(parsed.body, b"secret");
+        // The Tee sits inside the TLS layer so it records plaintext.
+        assert!(out.raw_request.starts_with(b"GET https://localhost:"));
+        assert!(out.raw_response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // A second connection to the same server resumes the session.
+        let out = execute(&request(&endpoint, TLSVerify::Insecure), &inputs.get())
+            .await
+            .unwrap();
+        assert!(out.tls.unwrap().resumed);
     }
-}
 
-impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncWrite for Tee<T> {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &[u8],
-    ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        let poll = Pin::new(&mut self.deref_mut().inner).poll_write(cx, buf);
-        if poll.is_ready() {
-            self.get_mut().writes.extend_from_slice(&buf);
-        }
-        poll
-    }
-    fn poll_flush(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.deref_mut().inner).poll_flush(cx)
-    }
-    fn poll_shutdown(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Result<(), std::io::Error>> {
-        Pin::new(&mut self.deref_mut().inner).poll_shutdown(cx)
+    #[tokio::test]
+    async fn https_ca_test() {
+        let (port, cert) = tls_server().await;
+        let path = std::env::temp_dir().join(format!("courier-test-ca-{}.pem", port));
+        std::fs::write(&path, cert.pem()).unwrap();
+        let out = execute(
+            &request(
+                &format!("https://localhost:{}/", port),
+                TLSVerify::CA(path.to_str().unwrap()),
+            ),
+            &TestInputs::default().get(),
+        )
+        .await;
+        std::fs::remove_file(&path).unwrap();
+        let StepParsedOutput::HTTP(parsed) = out.unwrap().parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.body, b"secret");
+    }
+
+    #[tokio::test]
+    async fn https_ipv6_test() {
+        let (port, _) = tls_server_at("[::1]:0").await;
+        let endpoint = format!("https://[::1]:{}/", port);
+        let out = execute(
+            &request(&endpoint, TLSVerify::Insecure),
+            &TestInputs::default().get(),
+        )
+        .await
+        .unwrap();
+        // No SNI is sent for IP addresses.
+        assert_eq!(out.tls.unwrap().sni, None);
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.body, b"secret");
+    }
+
+    #[tokio::test]
+    async fn https_untrusted_test() {
+        let (port, _) = tls_server().await;
+        let plan = format!("https EOF\nGET localhost:{}\n\n\nEOF", port);
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        assert!(executor.next().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn interpolation_test() {
+        let port = http_server().await;
+        let plan = format!(
+            "http login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            http EOF\nGET http://127.0.0.1:{port}/\nAuthorization: Bearer ${{login.response.body}}\n\n\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        executor.next().await.unwrap();
+        let StepParsedOutput::HTTP(parsed) = executor.next().await.unwrap().parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.body, b"Bearer token123");
+    }
+
+    #[tokio::test]
+    async fn http1_0_test() {
+        // No Content-Length, so the body runs until the server closes the connection.
+        let (port, server) = raw_server(b"HTTP/1.0 200 OK\r\n\r\nuntil close").await;
+        let endpoint = format!("http://127.0.0.1:{}/", port);
+        let out = execute(
+            &HTTPRequest {
+                method: "GET",
+                endpoint: Template::literal(&endpoint),
+                version: Protocol::HTTP1_0,
+                headers: Vec::new(),
+                directives: Vec::new(),
+                body: HTTPBody::Payload(Payload::Raw(Template::default())),
+                tls: None,
+                h2c: None,
+                connection: None,
+                options: HTTPOptions::default(),
+            },
+            &TestInputs::default().get(),
+        )
+        .await
+        .unwrap();
+
+        let expected = format!(
+            "GET http://127.0.0.1:{}/ HTTP/1.0\r\nuser-agent: courier/0.1.0\r\n\r\n",
+            port
+        );
+        assert_eq!(server.await.unwrap(), expected.as_bytes());
+        assert_eq!(out.raw_request, expected.as_bytes());
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, HTTPVersion::HTTP1_0);
+        assert_eq!(parsed.body, b"until close");
+    }
+
+    #[tokio::test]
+    async fn header_order_test() {
+        let run = |headers: &'static str, body: &'static str| async move {
+            let (port, _) = raw_server(b"HTTP/1.1 204 No Content\r\n\r\n".as_slice()).await;
+            let plan = format!("http EOF\nPOST http://127.0.0.1:{port}/\n{headers}\n{body}\nEOF");
+            let plan = Plan::parse(&plan).unwrap();
+            let out = Executor::new(&plan).next().await.unwrap();
+            (port, String::from_utf8(out.raw_request).unwrap())
+        };
+
+        // Headers keep their case and order, with the defaults around them.
+        let (port, raw) = run("X-B: 1\nx-a: 2\nX-B: 3\n", "body").await;
+        assert_eq!(
+            raw,
+            format!(
+                "POST http://127.0.0.1:{port}/ HTTP/1.1\r\nhost: 127.0.0.1:{port}\r\n\
+                user-agent: courier/0.1.0\r\nX-B: 1\r\nx-a: 2\r\nX-B: 3\r\n\
+                content-length: 4\r\n\r\nbody"
+            )
+        );
+
+        // Directives move or leave out the defaults.
+        let (port, raw) = run(
+            "X-B: 1\n@default Host\nHOST-X: 2\n@omit User-Agent\n@default\n",
+            "body",
+        )
+        .await;
+        assert_eq!(
+            raw,
+            format!(
+                "POST http://127.0.0.1:{port}/ HTTP/1.1\r\nX-B: 1\r\nhost: 127.0.0.1:{port}\r\n\
+                HOST-X: 2\r\ncontent-length: 4\r\n\r\nbody"
+            )
+        );
+        let (port, raw) = run("@omit\nUser-Agent: mine\n", "").await;
+        assert_eq!(
+            raw,
+            format!("POST http://127.0.0.1:{port}/ HTTP/1.1\r\nUser-Agent: mine\r\n\r\n")
+        );
+
+        // A chunked body is sent as one chunk, without a Content-Length.
+        let (port, raw) = run("Transfer-Encoding: chunked\n", "hello").await;
+        assert_eq!(
+            raw,
+            format!(
+                "POST http://127.0.0.1:{port}/ HTTP/1.1\r\nhost: 127.0.0.1:{port}\r\n\
+                user-agent: courier/0.1.0\r\nTransfer-Encoding: chunked\r\n\r\n\
+                5\r\nhello\r\n0\r\n\r\n"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn content_encoding_test() {
+        let gzip = |body: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            std::io::Write::write_all(&mut encoder, body).unwrap();
+            encoder.finish().unwrap()
+        };
+        let encoded = gzip(b"decoded");
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            encoded.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&encoded);
+
+        let run = |options: &'static str, response: Vec<u8>| async move {
+            let (port, _) = raw_server(response).await;
+            let plan = format!(
+                "http{options} EOF\nPOST http://127.0.0.1:{port}/\nContent-Encoding: gzip\n\nhello\nEOF"
+            );
+            let plan = Plan::parse(&plan).unwrap();
+            Executor::new(&plan).next().await.unwrap()
+        };
+
+        // The request body is sent compressed and the response body is decoded.
+        let out = run("", response.clone()).await;
+        assert!(out.raw_request.ends_with(&gzip(b"hello")));
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.body, b"decoded");
+        assert_eq!(parsed.raw_body, Some(encoded.clone()));
+
+        // Both can be turned off, leaving the bodies as they were written.
+        let out = run("(decode=false, encode=false)", response).await;
+        assert!(out.raw_request.ends_with(b"\r\n\r\nhello"));
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.body, encoded);
+        assert_eq!(parsed.raw_body, None);
+
+        // A body that doesn't decode is kept as it arrived.
+        let response =
+            b"HTTP/1.1 502 Bad Gateway\r\nContent-Encoding: gzip\r\nContent-Length: 7\r\n\r\nnot gz!"
+                .to_vec();
+        let out = run("", response).await;
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(parsed.body, b"not gz!");
+        assert_eq!(parsed.raw_body, None);
+        assert!(parsed
+            .decode_error
+            .is_some_and(|e| e.starts_with("couldn't decode gzip body")));
+    }
+
+    #[tokio::test]
+    async fn https_scheme_test() {
+        let (port, _) = tls_server().await;
+        let endpoint = format!("https://localhost:{}/", port);
+        let mut req = request(&endpoint, TLSVerify::Insecure);
+        req.tls = None;
+        // Without TLS options the system roots are used, which don't trust the test server.
+        let err = execute(&req, &TestInputs::default().get())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[tokio::test]
+    async fn http2_test() {
+        let port = h2_server().await;
+        let endpoint = format!("http://127.0.0.1:{}/users/1", port);
+        let mut req = request(&endpoint, TLSVerify::System);
+        req.version = Protocol::HTTP2;
+        req.tls = None;
+        let out = execute(&req, &TestInputs::default().get()).await.unwrap();
+
+        assert!(out
+            .raw_request
+            .starts_with(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"));
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, HTTPVersion::HTTP2);
+        assert_eq!(parsed.body, b"/users/1");
+
+        let frames = out.http2.unwrap();
+        assert_eq!(frames.sent[0].kind, HTTP2FrameKind::Settings);
+        assert!(frames
+            .sent
+            .iter()
+            .any(|f| f.kind == HTTP2FrameKind::Headers && f.stream_id == 1));
+        assert_eq!(frames.received[0].kind, HTTP2FrameKind::Settings);
+        assert!(frames
+            .received
+            .iter()
+            .any(|f| f.kind == HTTP2FrameKind::Data && f.payload == b"/users/1"));
+    }
+
+    #[tokio::test]
+    async fn https_http2_test() {
+        let (port, _) = tls_server().await;
+        let endpoint = format!("https://localhost:{}/", port);
+        let mut req = request(&endpoint, TLSVerify::Insecure);
+        req.version = Protocol::HTTP2;
+        let out = execute(&req, &TestInputs::default().get()).await.unwrap();
+
+        assert_eq!(out.tls.unwrap().alpn, Some(b"h2".to_vec()));
+        assert!(out.http2.is_some());
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, HTTPVersion::HTTP2);
+        assert_eq!(parsed.body, b"secret");
+    }
+
+    #[tokio::test]
+    async fn h2c_upgrade_test() {
+        let (port, server) = h2c_upgrade_server().await;
+        let endpoint = format!("http://127.0.0.1:{}/", port);
+        let mut req = request(&endpoint, TLSVerify::System);
+        req.version = Protocol::HTTP2;
+        req.tls = None;
+        req.h2c = Some(H2C::Upgrade);
+        let out = execute(&req, &TestInputs::default().get()).await.unwrap();
+
+        let sent = server.await.unwrap();
+        assert_eq!(out.raw_request, sent);
+        let head = format!(
+            "GET {} HTTP/1.1\r\nhost: 127.0.0.1:{}\r\nuser-agent: courier/0.1.0\r\n\
+            connection: Upgrade, HTTP2-Settings\r\nupgrade: h2c\r\nhttp2-settings: \r\n\r\n\
+            PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n",
+            endpoint, port
+        );
+        assert!(
+            sent.starts_with(head.as_bytes()),
+            "{}",
+            String::from_utf8_lossy(&sent)
+        );
+        assert!(out
+            .raw_response
+            .starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+        let frames = out.http2.unwrap();
+        let kinds = |frames: &[HTTP2Frame]| frames.iter().map(|f| f.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds(&frames.sent),
+            [
+                HTTP2FrameKind::Settings,
+                HTTP2FrameKind::Settings,
+                HTTP2FrameKind::WindowUpdate,
+                HTTP2FrameKind::WindowUpdate,
+                HTTP2FrameKind::GoAway,
+            ]
+        );
+        assert_eq!(
+            kinds(&frames.received),
+            [
+                HTTP2FrameKind::Settings,
+                HTTP2FrameKind::Headers,
+                HTTP2FrameKind::Data,
+            ]
+        );
+
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, HTTPVersion::HTTP2);
+        assert_eq!(parsed.status, StatusCode::OK);
+        assert_eq!(parsed.body, b"upgraded");
+    }
+
+    #[tokio::test]
+    async fn h2c_upgrade_declined_test() {
+        let port = http_server().await;
+        let endpoint = format!("http://127.0.0.1:{}/login", port);
+        let mut req = request(&endpoint, TLSVerify::System);
+        req.version = Protocol::HTTP2;
+        req.tls = None;
+        req.h2c = Some(H2C::Upgrade);
+        let inputs = TestInputs::default();
+        let out = execute(&req, &inputs.get()).await.unwrap();
+
+        // The server ignores the upgrade, so the response is plain HTTP/1.1.
+        assert_eq!(out.http2, None);
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, HTTPVersion::HTTP1_1);
+        assert_eq!(parsed.body, b"token123");
+
+        let endpoint = format!("https://127.0.0.1:{}/login", port);
+        req.endpoint = Template::literal(&endpoint);
+        let err = execute(&req, &inputs.get()).await.unwrap_err();
+        assert_eq!(err.to_string(), "h2c can't be used with TLS");
     }
 }
@@ -0,0 +1,264 @@
+use std::fmt::Display;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use h2::frame::{Frame, GoAway, Ping, Settings, StreamId, WindowUpdate};
+use h2::{Codec, Reason};
+use hyper::http::response::Parts;
+use hyper::{Response, Version};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// The connection preface a client sends before its first frame.
+pub(super) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The frames sent and received on an HTTP/2 connection, in the order they were written and read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HTTP2Output {
+    pub sent: Vec<HTTP2Frame>,
+    pub received: Vec<HTTP2Frame>,
+}
+
+impl HTTP2Output {
+    /// Split the raw bytes of each side of a connection into frames, or returns `None` if the
+    /// client never sent the HTTP/2 preface. Anything sent up to the end of the preface is
+    /// skipped, as is an HTTP/1.1 response switching protocols and a truncated final frame.
+    pub(super) fn parse(writes: &[u8], reads: &[u8]) -> Option<Self> {
+        let start = writes.windows(PREFACE.len()).position(|w| w == PREFACE)?;
+        let reads = match reads.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) if reads.starts_with(b"HTTP/1.1 101") => &reads[end + 4..],
+            _ => reads,
+        };
+        Some(HTTP2Output {
+            sent: frames(&writes[start + PREFACE.len()..]),
+            received: frames(reads),
+        })
+    }
+}
+
+/// Read the response to an upgrade request from a connection that has switched to HTTP/2. The
+/// server sends it on stream 1, which hyper's HTTP/2 client can't adopt, so the frames are
+/// handled here directly.
+pub(super) async fn upgraded_response<T: AsyncRead + AsyncWrite + Unpin>(
+    mut io: T,
+) -> Result<(Parts, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    io.write_all(PREFACE).await?;
+    let mut codec = Codec::<_, Bytes>::new(io);
+    codec
+        .send(Frame::Settings(Settings::default()))
+        .await
+        .map_err(h2::Error::from)?;
+
+    let stream = StreamId::from(1);
+    let mut head = None;
+    let mut body = Vec::new();
+    loop {
+        let frame = match codec.next().await {
+            Some(frame) => frame.map_err(h2::Error::from)?,
+            None => return Err("connection closed before the response finished".into()),
+        };
+        let end_stream = match frame {
+            Frame::Settings(settings) if !settings.is_ack() => {
+                codec
+                    .send(Frame::Settings(Settings::ack()))
+                    .await
+                    .map_err(h2::Error::from)?;
+                false
+            }
+            Frame::Ping(ping) if !ping.is_ack() => {
+                codec
+                    .send(Frame::Ping(Ping::pong(ping.into_payload())))
+                    .await
+                    .map_err(h2::Error::from)?;
+                false
+            }
+            Frame::Headers(headers) if headers.stream_id() == stream => {
+                let end_stream = headers.is_end_stream();
+                // Later header blocks are trailers.
+                if head.is_none() {
+                    let (pseudo, fields) = headers.into_parts();
+                    let mut res = Response::new(());
+                    *res.status_mut() = pseudo.status.ok_or("response is missing :status")?;
+                    *res.version_mut() = Version::HTTP_2;
+                    *res.headers_mut() = fields;
+                    head = Some(res.into_parts().0);
+                }
+                end_stream
+            }
+            Frame::Data(data) if data.stream_id() == stream => {
+                body.extend_from_slice(data.payload());
+                // Give back the flow control window so the server can keep sending.
+                if let Ok(len @ 1..) = u32::try_from(data.payload().len()) {
+                    codec
+                        .feed(Frame::WindowUpdate(WindowUpdate::new(
+                            StreamId::zero(),
+                            len,
+                        )))
+                        .await
+                        .map_err(h2::Error::from)?;
+                    codec
+                        .send(Frame::WindowUpdate(WindowUpdate::new(stream, len)))
+                        .await
+                        .map_err(h2::Error::from)?;
+                }
+                data.is_end_stream()
+            }
+            Frame::Reset(reset) if reset.stream_id() == stream => {
+                return Err(h2::Error::from(reset.reason()).into());
+            }
+            Frame::GoAway(go_away) => return Err(h2::Error::from(go_away.reason()).into()),
+            _ => false,
+        };
+        if end_stream {
+            break;
+        }
+    }
+
+    codec
+        .send(Frame::GoAway(GoAway::new(
+            StreamId::zero(),
+            Reason::NO_ERROR,
+        )))
+        .await
+        .map_err(h2::Error::from)?;
+    let head = head.ok_or("response ended without headers")?;
+    Ok((head, body))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HTTP2Frame {
+    pub kind: HTTP2FrameKind,
+    pub flags: u8,
+    pub stream_id: u32,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HTTP2FrameKind {
+    Data,
+    Headers,
+    Priority,
+    RstStream,
+    Settings,
+    PushPromise,
+    Ping,
+    GoAway,
+    WindowUpdate,
+    Continuation,
+    Unknown(u8),
+}
+
+impl Display for HTTP2FrameKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Data => f.write_str("DATA"),
+            Self::Headers => f.write_str("HEADERS"),
+            Self::Priority => f.write_str("PRIORITY"),
+            Self::RstStream => f.write_str("RST_STREAM"),
+            Self::Settings => f.write_str("SETTINGS"),
+            Self::PushPromise => f.write_str("PUSH_PROMISE"),
+            Self::Ping => f.write_str("PING"),
+            Self::GoAway => f.write_str("GOAWAY"),
+            Self::WindowUpdate => f.write_str("WINDOW_UPDATE"),
+            Self::Continuation => f.write_str("CONTINUATION"),
+            Self::Unknown(kind) => write!(f, "UNKNOWN({:#04x})", kind),
+        }
+    }
+}
+
+impl From<u8> for HTTP2FrameKind {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0 => Self::Data,
+            0x1 => Self::Headers,
+            0x2 => Self::Priority,
+            0x3 => Self::RstStream,
+            0x4 => Self::Settings,
+            0x5 => Self::PushPromise,
+            0x6 => Self::Ping,
+            0x7 => Self::GoAway,
+            0x8 => Self::WindowUpdate,
+            0x9 => Self::Continuation,
+            _ => Self::Unknown(value),
+        }
+    }
+}
+
+fn frames(mut input: &[u8]) -> Vec<HTTP2Frame> {
+    let mut frames = Vec::new();
+    while input.len() >= 9 {
+        let len = u32::from_be_bytes([0, input[0], input[1], input[2]]) as usize;
+        if input.len() < 9 + len {
+            break;
+        }
+        frames.push(HTTP2Frame {
+            kind: input[3].into(),
+            flags: input[4],
+            // The high bit is reserved.
+            stream_id: u32::from_be_bytes([input[5], input[6], input[7], input[8]]) & 0x7fff_ffff,
+            payload: input[9..9 + len].to_vec(),
+        });
+        input = &input[9 + len..];
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_test() {
+        let mut writes = PREFACE.to_vec();
+        // SETTINGS with no parameters, then a DATA frame on stream 1 with the reserved bit set.
+        writes.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        writes.extend_from_slice(&[0, 0, 2, 0x0, 0x1, 0x80, 0, 0, 1, b'h', b'i']);
+        // RST_STREAM, then a truncated frame.
+        let reads = [0, 0, 4, 0x3, 0, 0, 0, 0, 1, 0, 0, 0, 0x8, 0, 0, 9, 0xff];
+
+        assert_eq!(
+            HTTP2Output::parse(&writes, &reads),
+            Some(HTTP2Output {
+                sent: vec![
+                    HTTP2Frame {
+                        kind: HTTP2FrameKind::Settings,
+                        flags: 0,
+                        stream_id: 0,
+                        payload: Vec::new(),
+                    },
+                    HTTP2Frame {
+                        kind: HTTP2FrameKind::Data,
+                        flags: 0x1,
+                        stream_id: 1,
+                        payload: b"hi".to_vec(),
+                    },
+                ],
+                received: vec![HTTP2Frame {
+                    kind: HTTP2FrameKind::RstStream,
+                    flags: 0,
+                    stream_id: 1,
+                    payload: vec![0, 0, 0, 0x8],
+                }],
+            })
+        );
+        assert_eq!(HTTP2Output::parse(b"GET / HTTP/1.1\r\n\r\n", &reads), None);
+
+        // After an upgrade the frames follow the HTTP/1.1 request and response.
+        let mut writes = b"GET / HTTP/1.1\r\nupgrade: h2c\r\n\r\n".to_vec();
+        writes.extend_from_slice(PREFACE);
+        writes.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        let mut reads = b"HTTP/1.1 101 Switching Protocols\r\nupgrade: h2c\r\n\r\n".to_vec();
+        reads.extend_from_slice(&[0, 0, 0, 0x4, 0x1, 0, 0, 0, 0]);
+        let out = HTTP2Output::parse(&writes, &reads).unwrap();
+        assert_eq!(out.sent.len(), 1);
+        assert_eq!(
+            out.received,
+            vec![HTTP2Frame {
+                kind: HTTP2FrameKind::Settings,
+                flags: 0x1,
+                stream_id: 0,
+                payload: Vec::new(),
+            }]
+        );
+        assert_eq!(HTTP2FrameKind::from(0xff).to_string(), "UNKNOWN(0xff)");
+    }
+}
@@ -0,0 +1,545 @@
+use std::future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes};
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream};
+use futures::{ready, StreamExt};
+use h3::quic::{self, ConnectionErrorIncoming, StreamErrorIncoming, StreamId, WriteBuf};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{HeaderMap, StatusCode, Uri, Version};
+use quinn::VarInt;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use super::http::build_request;
+use super::quic::{self as quic_conn, Recorder};
+use super::{cookie, expr, HTTPOutput, StepInputs, StepOutput, StepParsedOutput};
+use crate::HTTPRequest;
+
+/// The error code for closing an HTTP/3 connection without an error.
+const H3_NO_ERROR: u32 = 0x100;
+
+pub(super) async fn execute(
+    step: &HTTPRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let endpoint: Uri =
+        String::from_utf8(expr::render(&step.endpoint, inputs.previous)?)?.parse()?;
+    let host = endpoint.host().ok_or("uri has no host")?;
+    let port = endpoint.port_u16().unwrap_or(443);
+    // HTTP/3 always runs over TLS, so the scheme is always https.
+    let target = Uri::builder()
+        .scheme("https")
+        .authority(endpoint.authority().ok_or("request missing host")?.clone())
+        .path_and_query(endpoint.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+        .build()?;
+    let mut default_headers = vec![(hyper::header::USER_AGENT, "courier/0.1.0")];
+    let cookies = cookie::header(inputs, &target, true);
+    if let Some(cookies) = &cookies {
+        default_headers.push((hyper::header::COOKIE, cookies));
+    }
+    let req = build_request(
+        step,
+        target.clone(),
+        Version::HTTP_3,
+        default_headers,
+        inputs,
+    )?;
+
+    // h3 is built on http 1.0, so the request is copied over.
+    let mut builder = http1::Request::builder()
+        .method(req.method.as_str())
+        .uri(req.target.to_string());
+    for (k, v) in &req.headers {
+        builder = builder.header(k.as_str(), v.as_slice());
+    }
+    let body = Bytes::from(req.body);
+    let req = builder.body(())?;
+
+    let tls = step.tls.clone().unwrap_or_default();
+    let (endpoint, conn, mut output) =
+        quic_conn::connect(host, port, &[b"h3"], &tls, inputs.tls_configs).await?;
+    let recorder = Recorder::default();
+    let (mut driver, mut sender) =
+        h3::client::new(RecordedConnection::new(conn.clone(), recorder.clone())).await?;
+    // The driver handles the control streams until the connection closes.
+    let driver = tokio::spawn(async move { future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let mut stream = sender.send_request(req).await?;
+    if !body.is_empty() {
+        stream.send_data(body).await?;
+    }
+    stream.finish().await?;
+    let res = stream.recv_response().await?;
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    let trailers = stream.recv_trailers().await?.unwrap_or_default();
+    let request_stream = stream.id().into_inner();
+
+    output.rtt = conn.rtt();
+    conn.close(H3_NO_ERROR.into(), b"");
+    // The driver finishes with an error once the connection is closed.
+    let _ = driver.await;
+    endpoint.wait_idle().await;
+
+    // The h3 crate uses its own copy of the http crate, so its headers are copied over.
+    let convert =
+        |fields: &http1::HeaderMap| -> Result<HeaderMap, Box<dyn std::error::Error + Send + Sync>> {
+            let mut headers = HeaderMap::new();
+            for (k, v) in fields {
+                headers.append(
+                    HeaderName::from_bytes(k.as_str().as_bytes())?,
+                    HeaderValue::from_bytes(v.as_bytes())?,
+                );
+            }
+            Ok(headers)
+        };
+    let headers = convert(res.headers())?;
+    let trailers = convert(&trailers)?;
+    output.streams = recorder.streams();
+    // The raw request and response are the HTTP/3 frames on the request stream.
+    let (raw_request, raw_response) = output
+        .streams
+        .iter()
+        .find(|s| s.id == request_stream)
+        .map(|s| (s.sent.clone(), s.received.clone()))
+        .unwrap_or_default();
+    let out = StepOutput {
+        raw_request,
+        raw_response,
+        tls: None,
+        http2: None,
+        quic: Some(output),
+        connection: None,
+        duration: Duration::ZERO,
+        parsed: StepParsedOutput::HTTP(HTTPOutput {
+            version: Version::HTTP_3.into(),
+            status: StatusCode::from_u16(res.status().as_u16())?,
+            headers,
+            body,
+            raw_body: None,
+            decode_error: None,
+            parsed_body: None,
+            chunks: Vec::new(),
+            trailers,
+        }),
+    };
+    cookie::store(inputs, &target, true, &out);
+    Ok(out)
+}
+
+type BoxResultStream<T, E> = BoxStream<'static, Result<T, E>>;
+
+/// A QUIC connection for h3 that records the payload of every stream.
+struct RecordedConnection {
+    incoming_bi: BoxResultStream<(quinn::SendStream, quinn::RecvStream), quinn::ConnectionError>,
+    incoming_uni: BoxResultStream<quinn::RecvStream, quinn::ConnectionError>,
+    opener: RecordedOpener,
+}
+
+impl RecordedConnection {
+    fn new(conn: quinn::Connection, recorder: Recorder) -> Self {
+        RecordedConnection {
+            incoming_bi: stream::unfold(conn.clone(), |conn| async {
+                Some((conn.accept_bi().await, conn))
+            })
+            .boxed(),
+            incoming_uni: stream::unfold(conn.clone(), |conn| async {
+                Some((conn.accept_uni().await, conn))
+            })
+            .boxed(),
+            opener: RecordedOpener {
+                conn,
+                recorder,
+                opening_bi: None,
+                opening_uni: None,
+            },
+        }
+    }
+}
+
+impl<B: Buf> quic::Connection<B> for RecordedConnection {
+    type RecvStream = RecordedRecvStream;
+    type OpenStreams = RecordedOpener;
+
+    fn poll_accept_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::RecvStream, ConnectionErrorIncoming>> {
+        let recv = ready!(self.incoming_uni.poll_next_unpin(cx))
+            .expect("the stream of incoming streams never ends")
+            .map_err(connection_error)?;
+        Poll::Ready(Ok(RecordedRecvStream::new(
+            recv,
+            self.opener.recorder.clone(),
+        )))
+    }
+
+    fn poll_accept_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, ConnectionErrorIncoming>> {
+        let (send, recv) = ready!(self.incoming_bi.poll_next_unpin(cx))
+            .expect("the stream of incoming streams never ends")
+            .map_err(connection_error)?;
+        Poll::Ready(Ok(RecordedBidiStream::new(
+            send,
+            recv,
+            &self.opener.recorder,
+        )))
+    }
+
+    fn opener(&self) -> Self::OpenStreams {
+        self.opener.clone()
+    }
+}
+
+impl<B: Buf> quic::OpenStreams<B> for RecordedConnection {
+    type BidiStream = RecordedBidiStream;
+    type SendStream = RecordedSendStream;
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, StreamErrorIncoming>> {
+        quic::OpenStreams::<B>::poll_open_bidi(&mut self.opener, cx)
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, StreamErrorIncoming>> {
+        quic::OpenStreams::<B>::poll_open_send(&mut self.opener, cx)
+    }
+
+    fn close(&mut self, code: h3::error::Code, reason: &[u8]) {
+        quic::OpenStreams::<B>::close(&mut self.opener, code, reason)
+    }
+}
+
+struct RecordedOpener {
+    conn: quinn::Connection,
+    recorder: Recorder,
+    opening_bi: Option<BoxFuture<'static, OpenBiOutput>>,
+    opening_uni: Option<BoxFuture<'static, OpenUniOutput>>,
+}
+
+type OpenBiOutput = Result<(quinn::SendStream, quinn::RecvStream), quinn::ConnectionError>;
+type OpenUniOutput = Result<quinn::SendStream, quinn::ConnectionError>;
+
+impl Clone for RecordedOpener {
+    fn clone(&self) -> Self {
+        RecordedOpener {
+            conn: self.conn.clone(),
+            recorder: self.recorder.clone(),
+            opening_bi: None,
+            opening_uni: None,
+        }
+    }
+}
+
+impl<B: Buf> quic::OpenStreams<B> for RecordedOpener {
+    type BidiStream = RecordedBidiStream;
+    type SendStream = RecordedSendStream;
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, StreamErrorIncoming>> {
+        let conn = self.conn.clone();
+        let opening = self
+            .opening_bi
+            .get_or_insert_with(|| Box::pin(async move { conn.open_bi().await }));
+        let result = ready!(opening.as_mut().poll(cx));
+        self.opening_bi = None;
+        let (send, recv) = result.map_err(|e| StreamErrorIncoming::ConnectionErrorIncoming {
+            connection_error: connection_error(e),
+        })?;
+        Poll::Ready(Ok(RecordedBidiStream::new(send, recv, &self.recorder)))
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, StreamErrorIncoming>> {
+        let conn = self.conn.clone();
+        let opening = self
+            .opening_uni
+            .get_or_insert_with(|| Box::pin(async move { conn.open_uni().await }));
+        let result = ready!(opening.as_mut().poll(cx));
+        self.opening_uni = None;
+        let send = result.map_err(|e| StreamErrorIncoming::ConnectionErrorIncoming {
+            connection_error: connection_error(e),
+        })?;
+        Poll::Ready(Ok(RecordedSendStream::new(send, self.recorder.clone())))
+    }
+
+    fn close(&mut self, code: h3::error::Code, reason: &[u8]) {
+        self.conn.close(
+            VarInt::from_u64(code.value()).unwrap_or(VarInt::MAX),
+            reason,
+        );
+    }
+}
+
+struct RecordedBidiStream {
+    send: RecordedSendStream,
+    recv: RecordedRecvStream,
+}
+
+impl RecordedBidiStream {
+    fn new(send: quinn::SendStream, recv: quinn::RecvStream, recorder: &Recorder) -> Self {
+        RecordedBidiStream {
+            send: RecordedSendStream::new(send, recorder.clone()),
+            recv: RecordedRecvStream::new(recv, recorder.clone()),
+        }
+    }
+}
+
+impl<B: Buf> quic::BidiStream<B> for RecordedBidiStream {
+    type SendStream = RecordedSendStream;
+    type RecvStream = RecordedRecvStream;
+
+    fn split(self) -> (Self::SendStream, Self::RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl<B: Buf> quic::SendStream<B> for RecordedBidiStream {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        quic::SendStream::<B>::poll_ready(&mut self.send, cx)
+    }
+
+    fn send_data<T: Into<WriteBuf<B>>>(&mut self, data: T) -> Result<(), StreamErrorIncoming> {
+        self.send.send_data(data)
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        quic::SendStream::<B>::poll_finish(&mut self.send, cx)
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        quic::SendStream::<B>::reset(&mut self.send, reset_code)
+    }
+
+    fn send_id(&self) -> StreamId {
+        quic::SendStream::<B>::send_id(&self.send)
+    }
+}
+
+impl quic::RecvStream for RecordedBidiStream {
+    type Buf = Bytes;
+
+    fn poll_data(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::Buf>, StreamErrorIncoming>> {
+        self.recv.poll_data(cx)
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.recv.stop_sending(error_code)
+    }
+
+    fn recv_id(&self) -> StreamId {
+        self.recv.recv_id()
+    }
+}
+
+struct RecordedSendStream {
+    stream: quinn::SendStream,
+    recorder: Recorder,
+    /// Data passed to `send_data` that hasn't been written to the stream yet.
+    pending: Bytes,
+}
+
+impl RecordedSendStream {
+    fn new(stream: quinn::SendStream, recorder: Recorder) -> Self {
+        recorder.open(stream.id());
+        RecordedSendStream {
+            stream,
+            recorder,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<B: Buf> quic::SendStream<B> for RecordedSendStream {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.pending))
+                .map_err(|e| StreamErrorIncoming::Unknown(Box::new(e)))?;
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn send_data<T: Into<WriteBuf<B>>>(&mut self, data: T) -> Result<(), StreamErrorIncoming> {
+        if !self.pending.is_empty() {
+            return Err(StreamErrorIncoming::Unknown(
+                "data sent before the previous write finished".into(),
+            ));
+        }
+        let mut data = data.into();
+        self.pending = data.copy_to_bytes(data.remaining());
+        self.recorder.sent(self.stream.id(), &self.pending);
+        Ok(())
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        ready!(quic::SendStream::<B>::poll_ready(self, cx))?;
+        self.stream
+            .finish()
+            .map_err(|e| StreamErrorIncoming::Unknown(Box::new(e)))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        let _ = self
+            .stream
+            .reset(VarInt::from_u64(reset_code).unwrap_or(VarInt::MAX));
+    }
+
+    fn send_id(&self) -> StreamId {
+        stream_id(self.stream.id())
+    }
+}
+
+struct RecordedRecvStream {
+    stream: quinn::RecvStream,
+    recorder: Recorder,
+}
+
+impl RecordedRecvStream {
+    fn new(stream: quinn::RecvStream, recorder: Recorder) -> Self {
+        recorder.open(stream.id());
+        RecordedRecvStream { stream, recorder }
+    }
+}
+
+impl quic::RecvStream for RecordedRecvStream {
+    type Buf = Bytes;
+
+    fn poll_data(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::Buf>, StreamErrorIncoming>> {
+        let mut buf = vec![0; 16 * 1024];
+        let mut read = ReadBuf::new(&mut buf);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read)).map_err(|e| {
+            match e.downcast::<quinn::ReadError>() {
+                Ok(quinn::ReadError::Reset(code)) => StreamErrorIncoming::StreamTerminated {
+                    error_code: code.into_inner(),
+                },
+                Ok(quinn::ReadError::ConnectionLost(e)) => {
+                    StreamErrorIncoming::ConnectionErrorIncoming {
+                        connection_error: connection_error(e),
+                    }
+                }
+                Ok(e) => StreamErrorIncoming::Unknown(Box::new(e)),
+                Err(e) => StreamErrorIncoming::Unknown(Box::new(e)),
+            }
+        })?;
+        // The stream reads nothing once it's finished.
+        let n = read.filled().len();
+        if n == 0 {
+            return Poll::Ready(Ok(None));
+        }
+        buf.truncate(n);
+        self.recorder.received(self.stream.id(), &buf);
+        Poll::Ready(Ok(Some(Bytes::from(buf))))
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        let _ = self
+            .stream
+            .stop(VarInt::from_u64(error_code).unwrap_or(VarInt::MAX));
+    }
+
+    fn recv_id(&self) -> StreamId {
+        stream_id(self.stream.id())
+    }
+}
+
+fn stream_id(id: quinn::StreamId) -> StreamId {
+    StreamId::try_from(u64::from(id)).expect("quic stream ids fit in a varint")
+}
+
+fn connection_error(e: quinn::ConnectionError) -> ConnectionErrorIncoming {
+    match e {
+        quinn::ConnectionError::ApplicationClosed(close) => {
+            ConnectionErrorIncoming::ApplicationClose {
+                error_code: close.error_code.into_inner(),
+            }
+        }
+        quinn::ConnectionError::TimedOut => ConnectionErrorIncoming::Timeout,
+        e => ConnectionErrorIncoming::Undefined(Arc::new(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testutil::h3_server;
+    use crate::exec::{Executor, QUICSide};
+    use crate::Plan;
+
+    #[tokio::test]
+    async fn http3_test() {
+        let (port, cert) = h3_server().await;
+        let plan = format!(
+            "http3(verify=insecure) EOF\nPOST https://localhost:{port}/echo\nX-Test: 1\n\nhello\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        let out = executor.next().await.unwrap();
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, crate::exec::HTTPVersion::HTTP3);
+        assert_eq!(parsed.status, StatusCode::OK);
+        assert_eq!(parsed.headers["x-path"], "/echo");
+        // The server echoes the request body.
+        assert_eq!(parsed.body, b"hello");
+
+        let quic = out.quic.unwrap();
+        assert_eq!(quic.alpn, Some(b"h3".to_vec()));
+        assert_eq!(quic.server_certificates[0].der, cert.der().to_vec());
+        // The request goes out on the first client bidirectional stream, alongside the control
+        // streams each side opens.
+        let request = quic.streams.iter().find(|s| s.id == 0).unwrap();
+        assert!(request.bidirectional);
+        assert_eq!(request.sent, out.raw_request);
+        assert_eq!(request.received, out.raw_response);
+        // h3 may follow the data with a grease frame.
+        assert!(out.raw_request.windows(5).any(|w| w == b"hello"));
+        assert!(quic
+            .streams
+            .iter()
+            .any(|s| s.initiator == QUICSide::Server && !s.bidirectional));
+        assert!(quic
+            .streams
+            .iter()
+            .any(|s| s.initiator == QUICSide::Client && !s.bidirectional));
+    }
+
+    #[tokio::test]
+    async fn http3_version_test() {
+        // An http step asking for HTTP/3 in the request line is sent the same way.
+        let (port, _) = h3_server().await;
+        let plan =
+            format!("http(verify=insecure) EOF\nGET https://localhost:{port}/v HTTP/3\n\n\nEOF");
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        let StepParsedOutput::HTTP(parsed) = executor.next().await.unwrap().parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.version, crate::exec::HTTPVersion::HTTP3);
+        assert_eq!(parsed.headers["x-path"], "/v");
+    }
+}
@@ -1,75 +1,660 @@
+mod body;
+mod connection;
+mod cookie;
+mod encoding;
+mod expr;
+mod function;
+mod graphql;
+mod grpc;
 mod http;
+mod http2;
+mod http3;
+mod plugin;
+mod quic;
+mod raw;
+mod tcp;
+mod tee;
+#[cfg(test)]
+mod testutil;
+mod tls;
+mod udp;
+mod websocket;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::time::{Duration, Instant};
 
+pub use body::*;
+pub use connection::ConnectionOutput;
+use connection::Connections;
+pub use cookie::{Cookie, CookieJar};
+use expr::Scope;
+pub use expr::Value;
+pub use graphql::*;
+pub use grpc::*;
 pub use http::*;
+pub use http2::*;
+pub use plugin::Plugins;
+pub use quic::*;
+pub use tcp::*;
+use tls::ClientConfigs;
+pub use tls::*;
+pub use udp::*;
+pub use websocket::*;
 
-use crate::{Plan, StepBody};
+use tokio::sync::Mutex;
 
+use crate::{CookieCommand, LoopStep, Plan, Statement, Step, StepBody, Values};
+
+/// How many times a loop may send its step unless [`Executor::set_iteration_limit`] is used.
+pub const DEFAULT_ITERATION_LIMIT: usize = 1000;
+
+/// Runs the statements of a plan in order. The executor tracks the index of the next statement to
+/// run, which can be moved with [`Executor::jump`] to skip or repeat statements. A loop returns an
+/// output each time it sends its step, and the executor moves past it once it's finished.
 pub struct Executor<'a> {
     plan: &'a Plan<'a>,
-    current: Option<usize>,
-    outputs: HashMap<&'a str, StepOutput>,
+    current: usize,
+    /// The state of the loop at the current statement, once it has started.
+    iteration: Option<Iteration>,
+    iteration_limit: usize,
+    scope: Scope<'a>,
+    tls_configs: ClientConfigs,
+    connections: Mutex<Connections>,
+    /// The steps waiting for the responses to their pipelined requests.
+    pipelined: HashMap<ConnectionOutput, (&'a Step<'a>, Duration)>,
+}
+
+enum Iteration {
+    /// The values a `for` loop hasn't gone through yet.
+    For(std::vec::IntoIter<Value>),
+    /// How many times a `while` loop has sent its step.
+    While(usize),
 }
 
 impl<'a> Executor<'a> {
     pub fn new(plan: &'a Plan) -> Self {
         Executor {
             plan,
-            current: plan.steps.first().map(|_| 0),
-            outputs: HashMap::new(),
+            current: 0,
+            iteration: None,
+            iteration_limit: DEFAULT_ITERATION_LIMIT,
+            scope: Scope::default(),
+            tls_configs: ClientConfigs::default(),
+            connections: Mutex::default(),
+            pipelined: HashMap::new(),
         }
     }
 
+    /// Set how many times a loop may send its step. A `for` loop with more values fails before
+    /// sending anything, and a `while` loop fails when it would go past the limit.
+    pub fn set_iteration_limit(&mut self, limit: usize) {
+        self.iteration_limit = limit;
+    }
+
+    /// Make the functions of plugins available to expressions. Built-in functions take
+    /// precedence over plugin functions with the same name.
+    pub fn set_plugins(&mut self, plugins: Plugins) {
+        self.scope.plugins = plugins;
+    }
+
+    /// Keep the cookies responses set and send them with later requests, as a plan does with
+    /// `cookies enable`. The jar starts out empty.
+    pub fn enable_cookies(&mut self) {
+        self.scope.cookies.get_or_insert_with(Default::default);
+    }
+
+    /// The cookies in the jar that haven't expired, oldest first, or nothing if the jar isn't
+    /// enabled.
+    pub fn cookies(&self) -> Vec<Cookie> {
+        self.scope.cookies.as_ref().map_or_else(Vec::new, |jar| {
+            let jar = jar.lock().unwrap_or_else(|e| e.into_inner());
+            jar.cookies().cloned().collect()
+        })
+    }
+
+    /// Send the next step and advance past it, or past its loop once the loop is finished. The
+    /// executor advances even if the step fails so a failing step doesn't block the rest of the
+    /// plan, though a failing step ends a `while` loop. Returns [`Error::Done`] once every
+    /// statement has run.
     pub async fn next(&mut self) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
-        let Some(current) = &mut self.current else {
-            return Err(Box::new(Error::Done));
-        };
-        let step = &self.plan.steps[*current];
-        let out = match &step.body {
-            StepBody::HTTP(req) => {
-                http::execute(
-                    &req,
-                    &StepInputs {
-                        previous: &self.outputs,
-                    },
-                )
-                .await?
+        loop {
+            let plan = self.plan;
+            let statement = plan.statements.get(self.current).ok_or(Error::Done)?;
+            match statement {
+                Statement::Define(_) => self.current += 1,
+                Statement::Expect(condition) => {
+                    self.current += 1;
+                    expr::expect(condition, &self.scope)?;
+                }
+                Statement::Cookies(command) => {
+                    self.current += 1;
+                    self.cookie_command(command)?;
+                }
+                Statement::Step(step) => {
+                    self.current += 1;
+                    return self.send(step).await;
+                }
+                Statement::For {
+                    variable,
+                    values,
+                    step,
+                } => {
+                    let step = self.loop_step(step)?;
+                    if !matches!(self.iteration, Some(Iteration::For(_))) {
+                        match self.values(values) {
+                            Ok(values) => self.iteration = Some(Iteration::For(values.into_iter())),
+                            Err(e) => {
+                                self.finish_loop();
+                                return Err(Box::new(e));
+                            }
+                        }
+                    }
+                    let Some(Iteration::For(values)) = &mut self.iteration else {
+                        unreachable!("the loop was just started");
+                    };
+                    let Some(value) = values.next() else {
+                        self.finish_loop();
+                        continue;
+                    };
+                    let last = values.len() == 0;
+                    self.scope.variables.insert(variable, value);
+                    let result = self.send(step).await;
+                    if last {
+                        self.finish_loop();
+                    }
+                    return result;
+                }
+                Statement::While { condition, step } => {
+                    let step = self.loop_step(step)?;
+                    let count = match self.iteration {
+                        Some(Iteration::While(count)) => count,
+                        _ => 0,
+                    };
+                    if count == self.iteration_limit {
+                        self.finish_loop();
+                        return Err(Box::new(Error::IterationLimit(self.iteration_limit)));
+                    }
+                    self.iteration = Some(Iteration::While(count + 1));
+                    let out = self.send(step).await.inspect_err(|_| self.finish_loop())?;
+                    if !expr::check(condition, &self.scope).inspect_err(|_| self.finish_loop())? {
+                        self.finish_loop();
+                    }
+                    return Ok(out);
+                }
+            }
+        }
+    }
+
+    /// Send the step of the statement at `index` without moving the executor's position. For a
+    /// `define` this is the defined step, and for a loop it's the loop's step, sent once with the
+    /// loop's variable as it is now.
+    pub async fn run(
+        &mut self,
+        index: usize,
+    ) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+        let plan = self.plan;
+        let step = match plan.statements.get(index).ok_or(Error::NoStep(index))? {
+            Statement::Step(step) | Statement::Define(step) => step,
+            Statement::For { step, .. } | Statement::While { step, .. } => self.loop_step(step)?,
+            Statement::Expect(_) | Statement::Cookies(_) => {
+                return Err(Box::new(Error::NoStep(index)))
             }
         };
-        let Some(name) = step.name else {
-            return Ok(out);
+        self.send(step).await
+    }
+
+    async fn send(
+        &mut self,
+        step: &'a Step<'a>,
+    ) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+        let start = Instant::now();
+        let inputs = StepInputs {
+            previous: &self.scope,
+            tls_configs: &self.tls_configs,
+            connections: &self.connections,
         };
-        self.outputs.insert(name, out.clone());
-        *current += 1;
+        let result = match &step.body {
+            StepBody::HTTP(req) => http::execute(req, &inputs).await,
+            StepBody::RawHTTP(req) => raw::execute(req, &inputs).await,
+            StepBody::TCP(req) => tcp::execute(req, &inputs).await,
+            StepBody::UDP(req) => udp::execute(req, &inputs).await,
+            StepBody::WebSocket(req) => websocket::execute(req, &inputs).await,
+            StepBody::GRPC(req) => grpc::execute(req, &inputs).await,
+            StepBody::GraphQL(req) => graphql::execute(req, &inputs).await,
+            StepBody::QUIC(req) => quic::execute(req, &inputs).await,
+        };
+        self.collect_pipelined()?;
+        let mut out = result?;
+        out.duration = start.elapsed();
+        if let Some(connection) = out.connection.clone().filter(|c| c.pipelined) {
+            self.pipelined.insert(connection, (step, out.duration));
+        }
+        if let Some(name) = step.name {
+            self.scope.outputs.insert(name, out.clone());
+        }
         Ok(out)
     }
+
+    /// Give the steps that pipelined requests the responses read for them since the last step.
+    fn collect_pipelined(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        for mut out in self.connections.get_mut().answered() {
+            if let Some(jar) = &mut self.scope.cookies {
+                cookie::store_pipelined(jar.get_mut().unwrap_or_else(|e| e.into_inner()), &out);
+            }
+            let Some((step, duration)) = out
+                .connection
+                .as_ref()
+                .and_then(|c| self.pipelined.remove(c))
+            else {
+                continue;
+            };
+            let options = match &step.body {
+                StepBody::HTTP(req) => &req.options,
+                StepBody::RawHTTP(req) => &req.options,
+                _ => continue,
+            };
+            if let StepParsedOutput::HTTP(http) = &mut out.parsed {
+                http::read_body(http, options)?;
+            }
+            out.duration = duration;
+            if let Some(name) = step.name {
+                self.scope.outputs.insert(name, out);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a `cookies` command, enabling the jar first.
+    fn cookie_command(&mut self, command: &CookieCommand) -> Result<(), Error> {
+        let text = |expr| {
+            let value = expr::evaluate(expr, &self.scope)?;
+            Ok::<_, Error>(String::from_utf8_lossy(&value.into_bytes()).into_owned())
+        };
+        let set = match command {
+            CookieCommand::Set { url, cookie } => {
+                let parsed: hyper::Uri = text(url)?
+                    .parse()
+                    .map_err(|_| Error::InvalidURL(url.to_string()))?;
+                Some((parsed, text(cookie)?))
+            }
+            CookieCommand::Enable | CookieCommand::Clear => None,
+        };
+        let jar = self.scope.cookies.get_or_insert_with(Default::default);
+        let jar = jar.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let CookieCommand::Clear = command {
+            jar.clear();
+        }
+        if let Some((url, cookie)) = set {
+            let secure = matches!(url.scheme_str(), Some("https" | "wss"));
+            jar.store(&url, secure, &cookie);
+        }
+        Ok(())
+    }
+
+    fn loop_step(&self, step: &'a LoopStep<'a>) -> Result<&'a Step<'a>, Error> {
+        let plan = self.plan;
+        plan.step(step).ok_or_else(|| match step {
+            LoopStep::Defined(name) => Error::NotDefined(name.to_string()),
+            LoopStep::Step(_) => unreachable!("loops with their own step always have one"),
+        })
+    }
+
+    /// Evaluate the values of a `for` loop, checking there aren't more than the iteration limit.
+    fn values(&self, values: &Values) -> Result<Vec<Value>, Error> {
+        let len = match values {
+            Values::List(exprs) => exprs.len(),
+            Values::Range(range) => {
+                usize::try_from(range.end.saturating_sub(range.start)).unwrap_or(0)
+            }
+        };
+        if len > self.iteration_limit {
+            return Err(Error::IterationLimit(self.iteration_limit));
+        }
+        match values {
+            Values::List(exprs) => exprs
+                .iter()
+                .map(|expr| expr::evaluate(expr, &self.scope))
+                .collect(),
+            Values::Range(range) => Ok(range.clone().map(Value::Int).collect()),
+        }
+    }
+
+    /// Stop the loop at the current statement, if it has started, and advance past it.
+    fn finish_loop(&mut self) {
+        self.end_loop();
+        self.current += 1;
+    }
+
+    /// Forget the state of the loop at the current statement and unset its variable.
+    fn end_loop(&mut self) {
+        if self.iteration.take().is_some() {
+            if let Some(Statement::For { variable, .. }) = self.plan.statements.get(self.current) {
+                self.scope.variables.remove(variable);
+            }
+        }
+    }
+
+    /// Set the index of the next statement to run, stopping any loop that has started. Jumping to
+    /// the end of the plan marks the executor as done.
+    pub fn jump(&mut self, index: usize) -> Result<(), Error> {
+        if index > self.plan.statements.len() {
+            return Err(Error::NoStep(index));
+        }
+        self.end_loop();
+        self.current = index;
+        Ok(())
+    }
+
+    /// The index of the next statement to run.
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
+    /// The step [`Executor::next`] will send, if any.
+    pub fn current_step(&self) -> Option<&'a Step<'a>> {
+        let plan = self.plan;
+        plan.statements
+            .get(self.current..)?
+            .iter()
+            .find_map(|statement| match statement {
+                Statement::Define(_) | Statement::Expect(_) | Statement::Cookies(_) => None,
+                Statement::Step(step) => Some(step),
+                Statement::For { step, .. } | Statement::While { step, .. } => plan.step(step),
+            })
+    }
+
+    /// Whether every statement has run. Definitions left at the end of the plan don't need to run.
+    pub fn is_done(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// The number of statements left to run, not counting definitions. A loop counts once until
+    /// it's finished.
+    pub fn remaining(&self) -> usize {
+        self.plan
+            .statements
+            .get(self.current..)
+            .unwrap_or_default()
+            .iter()
+            .filter(|statement| !matches!(statement, Statement::Define(_)))
+            .count()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct StepOutput {
     pub raw_request: Vec<u8>,
     pub raw_response: Vec<u8>,
+    pub tls: Option<TLSOutput>,
+    pub http2: Option<HTTP2Output>,
+    /// The handshake parameters and stream payloads of `quic` and `http3` steps.
+    pub quic: Option<QUICOutput>,
+    /// The named connection the request was sent over, for steps with the `connection` option.
+    pub connection: Option<ConnectionOutput>,
+    /// How long the step took to run, from before connecting to after reading the response. For
+    /// a pipelined request, this is how long sending it took.
+    pub duration: Duration,
     pub parsed: StepParsedOutput,
 }
 #[derive(Debug, Clone, PartialEq)]
 pub enum StepParsedOutput {
     HTTP(HTTPOutput),
+    TCP(TCPOutput),
+    UDP(UDPOutput),
+    WebSocket(WebSocketOutput),
+    GRPC(GRPCOutput),
+    GraphQL(GraphQLOutput),
+    QUIC(QUICReadEnd),
+    /// A pipelined request whose response hasn't been read yet. Once a later step on the same
+    /// connection reads it, the step's output is replaced with one holding the response.
+    Pending,
 }
 
 struct StepInputs<'a> {
-    previous: &'a HashMap<&'a str, StepOutput>,
+    previous: &'a Scope<'a>,
+    tls_configs: &'a ClientConfigs,
+    connections: &'a Mutex<Connections>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     Done,
+    NoStep(usize),
+    UnknownStep(String),
+    Undefined(String),
+    /// A loop refers to a step that isn't defined in the plan.
+    NotDefined(String),
+    /// A loop would send its step more times than the limit.
+    IterationLimit(usize),
+    /// A regular expression in a condition or function call couldn't be parsed.
+    InvalidPattern(String),
+    UnknownFunction(String),
+    /// A function was called with arguments it can't use.
+    InvalidCall {
+        call: String,
+        reason: String,
+    },
+    /// A plugin function failed or its plugin couldn't be used.
+    Plugin {
+        function: String,
+        message: String,
+    },
+    /// A `cookies set` command's URL isn't a valid URL.
+    InvalidURL(String),
+    /// The condition of an `expect` statement didn't hold.
+    Expectation(Expectation),
+}
+
+/// A failed `expect` statement.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Expectation {
+    /// The condition as written in the plan.
+    pub condition: String,
+    /// What each reference or call in the condition evaluated to, or `None` if it had no value.
+    pub values: Vec<(String, Option<Value>)>,
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("execution done")
+        match self {
+            Self::Done => f.write_str("execution done"),
+            Self::NoStep(index) => write!(f, "no step at index {}", index),
+            Self::UnknownStep(name) => write!(f, "no output for step `{}`", name),
+            Self::Undefined(expr) => write!(f, "`{}` is undefined", expr),
+            Self::NotDefined(name) => write!(f, "no step named `{}` is defined", name),
+            Self::IterationLimit(limit) => write!(f, "loop exceeded {} iterations", limit),
+            Self::InvalidPattern(e) => write!(f, "invalid regular expression: {}", e),
+            Self::UnknownFunction(name) => write!(f, "no function named `{}`", name),
+            Self::InvalidCall { call, reason } => write!(f, "`{}`: {}", call, reason),
+            Self::Plugin { function, message } => {
+                write!(f, "plugin function `{}` failed: {}", function, message)
+            }
+            Self::InvalidURL(expr) => write!(f, "`{}` isn't a valid URL", expr),
+            Self::Expectation(expectation) => {
+                write!(f, "expected `{}`", expectation.condition)?;
+                for (i, (expr, value)) in expectation.values.iter().enumerate() {
+                    f.write_str(if i == 0 { ", but " } else { " and " })?;
+                    match value {
+                        Some(value) => write!(f, "`{}` is {}", expr, value)?,
+                        None => write!(f, "`{}` is undefined", expr)?,
+                    }
+                }
+                Ok(())
+            }
+        }
     }
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testutil::http_server;
+
+    #[tokio::test]
+    async fn executor_test() {
+        let port = http_server().await;
+        let plan = format!(
+            "http EOF\nGET http://127.0.0.1:{port}/\n\n\nEOF\n\
+            http login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            http EOF\nGET http://127.0.0.1:{port}/\nAuthorization: ${{login.response.body}}\n\n\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        assert_eq!(executor.remaining(), 3);
+
+        // Unnamed steps advance the executor just like named ones.
+        executor.next().await.unwrap();
+        assert_eq!(executor.position(), 1);
+        executor.next().await.unwrap();
+        executor.next().await.unwrap();
+        assert!(executor.is_done());
+        assert_eq!(executor.remaining(), 0);
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::Done)
+        );
+
+        // Re-running a step leaves the position alone.
+        let StepParsedOutput::HTTP(out) = executor.run(2).await.unwrap().parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(out.body, b"token123");
+        assert!(executor.is_done());
+
+        executor.jump(2).unwrap();
+        assert_eq!(executor.remaining(), 1);
+        assert!(!executor.is_done());
+        assert_eq!(executor.jump(4), Err(Error::NoStep(4)));
+        assert_eq!(
+            executor.run(3).await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::NoStep(3))
+        );
+    }
+    #[tokio::test]
+    async fn loop_test() {
+        let port = http_server().await;
+        let plan = format!(
+            "define http echo EOF\nGET http://127.0.0.1:{port}/\nAuthorization: ${{token}}\n\n\nEOF\n\
+            http login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            for token in [\"a\", login.response.body, 3] echo\n\
+            for i in 5..=6 http EOF\nGET http://127.0.0.1:{port}/\nAuthorization: ${{i}}\n\n\nEOF\n\
+            for i in 1..1 echo\n\
+            while poll.response.body != \"token123\" http poll EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            define http unused EOF\nGET http://127.0.0.1:{port}/\n\n\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        assert_eq!(executor.remaining(), 5);
+        assert_eq!(executor.current_step().unwrap().name, Some("login"));
+
+        let mut bodies = Vec::new();
+        while !executor.is_done() {
+            let StepParsedOutput::HTTP(out) = executor.next().await.unwrap().parsed else {
+                panic!("expected an HTTP response");
+            };
+            bodies.push(out.body);
+        }
+        // The empty range sends nothing, and the `while` loop stops after one iteration.
+        assert_eq!(
+            bodies,
+            ["token123", "a", "token123", "3", "5", "6", "token123"].map(|b| b.as_bytes().to_vec())
+        );
+        // Loop variables are unset once their loop is finished.
+        assert!(executor.scope.variables.is_empty());
+        assert_eq!(executor.position(), plan.statements.len() - 1);
+
+        // Running a definition sends its step.
+        assert_eq!(
+            executor.run(0).await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::UnknownStep("token".to_owned()))
+        );
+        assert!(executor.run(6).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn iteration_limit_test() {
+        let port = http_server().await;
+        let plan = format!(
+            "define http poll EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            while poll.response.body == \"token123\" poll\n\
+            for i in 0..3 poll"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        executor.set_iteration_limit(2);
+        executor.next().await.unwrap();
+        executor.next().await.unwrap();
+        assert_eq!(executor.position(), 1);
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::IterationLimit(2))
+        );
+        // Loops with too many values fail before sending anything.
+        assert_eq!(executor.position(), 2);
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::IterationLimit(2))
+        );
+        assert!(executor.is_done());
+    }
+    #[tokio::test]
+    async fn expect_test() {
+        let port = http_server().await;
+        let plan = format!(
+            "http login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            expect login.response.status == 200\n\
+            expect login.response.body matches \"^token\\d+$\"\n\
+            expect login.response.headers[\"x-missing\"] exists\n\
+            expect login.duration < 60000\n\
+            expect login.response.body == \"nope\"\n\
+            expect login.response.body matches \"(\""
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        executor.next().await.unwrap();
+        assert_eq!(executor.current_step(), None);
+
+        // Expectations that hold are passed over until one fails.
+        let e = executor.next().await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<Error>(),
+            Some(&Error::Expectation(Expectation {
+                condition: "login.response.headers[\"x-missing\"] exists".to_owned(),
+                values: vec![("login.response.headers[\"x-missing\"]".to_owned(), None)],
+            }))
+        );
+        assert_eq!(executor.position(), 4);
+
+        let e = executor.next().await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "expected `login.response.body == \"nope\"`, but `login.response.body` is \"token123\""
+        );
+        assert!(matches!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::InvalidPattern(_))
+        ));
+        assert!(executor.is_done());
+    }
+
+    /// Patterns are matched in linear time without recursion, so long or adversarial texts
+    /// can't exhaust the stack or take exponential time.
+    #[tokio::test]
+    async fn expect_pattern_test() {
+        let plan = format!(
+            "expect \"{}\" matches \"^(?:ab)*$\"\n\
+            expect \"{}\" matches \"^(a|a)*b\"",
+            "ab".repeat(100_000),
+            "a".repeat(64)
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        assert!(matches!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::Expectation(_))
+        ));
+        assert_eq!(executor.position(), 2);
+    }
+}
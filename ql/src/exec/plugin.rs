@@ -0,0 +1,485 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::json;
+
+use super::expr::{Scope, Value};
+use super::{Error, StepParsedOutput};
+
+/// The version of the protocol plugins are spoken to in, sent when they're started.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// How long a plugin has to list its functions before it's skipped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a plugin has to answer a call. A plugin that doesn't is stopped, since a late answer
+/// would be mistaken for the answer to the next call.
+const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// External programs that provide functions for expressions. Each plugin is started once and
+/// sent one JSON request per line on its stdin, answering each with one JSON line on its stdout.
+/// The protocol is described in `docs/ql.md`.
+#[derive(Debug, Default)]
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+    skipped: Vec<(PathBuf, String)>,
+}
+
+#[derive(Debug)]
+pub(super) struct Plugin {
+    path: PathBuf,
+    functions: Vec<String>,
+    process: Mutex<Process>,
+}
+
+#[derive(Debug)]
+struct Process {
+    child: Child,
+    /// Requests written to stdin by a thread of their own, so a plugin that stops reading can't
+    /// block the executor.
+    requests: Sender<String>,
+    /// Lines read from stdout by a thread of their own, so waiting for them can time out. Failures
+    /// to write requests, and the plugin exiting, are sent here too.
+    responses: Receiver<Result<String, String>>,
+    /// Set once the plugin fails to answer, after which it isn't sent anything else.
+    failure: Option<String>,
+}
+
+impl Plugins {
+    /// Start every executable file in `dirs` as a plugin and ask it which functions it provides.
+    /// Plugins are started in order of their paths, and the first to provide a function is the
+    /// one that's called. Executables that don't answer with their functions are skipped and
+    /// listed by [`Plugins::skipped`].
+    pub fn discover<P: AsRef<Path>>(
+        dirs: impl IntoIterator<Item = P>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut paths = Vec::new();
+        for dir in dirs {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if is_executable(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        paths.sort();
+        let mut plugins = Self::default();
+        for path in paths {
+            match blocking(|| Plugin::start(path.clone(), HANDSHAKE_TIMEOUT)) {
+                Ok(plugin) => plugins.plugins.push(plugin),
+                Err(e) => plugins.skipped.push((path, e)),
+            }
+        }
+        Ok(plugins)
+    }
+
+    /// The executables that weren't used as plugins, with the reason why.
+    pub fn skipped(&self) -> impl Iterator<Item = (&Path, &str)> {
+        self.skipped
+            .iter()
+            .map(|(path, reason)| (path.as_path(), reason.as_str()))
+    }
+
+    /// Each function the plugins provide, with the path of the plugin that provides it.
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &Path)> {
+        self.plugins.iter().flat_map(|plugin| {
+            plugin
+                .functions
+                .iter()
+                .map(|function| (function.as_str(), plugin.path.as_path()))
+        })
+    }
+
+    pub(super) fn get(&self, function: &str) -> Option<&Plugin> {
+        self.plugins
+            .iter()
+            .find(|plugin| plugin.functions.iter().any(|f| f == function))
+    }
+}
+
+impl Plugin {
+    fn start(path: PathBuf, timeout: Duration) -> Result<Self, String> {
+        let mut child = Command::new(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("couldn't start: {}", e))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let (sender, responses) = mpsc::channel();
+        let (requests, pending) = mpsc::channel::<String>();
+        let failed = sender.clone();
+        std::thread::spawn(move || {
+            for line in pending {
+                if let Err(e) = stdin
+                    .write_all(line.as_bytes())
+                    .and_then(|()| stdin.flush())
+                {
+                    let _ = failed.send(Err(format!("couldn't send request: {}", e)));
+                    break;
+                }
+            }
+        });
+        std::thread::spawn(move || {
+            for line in stdout.lines() {
+                let line = line.map_err(|e| format!("couldn't read response: {}", e));
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+            // The writer keeps the channel open, so the end of stdout is sent explicitly.
+            let _ = sender.send(Err("plugin exited".to_owned()));
+        });
+        let mut process = Process {
+            child,
+            requests,
+            responses,
+            failure: None,
+        };
+        let response = process.request(
+            &json!({"method": "functions", "protocol": PROTOCOL_VERSION}),
+            timeout,
+        )?;
+        let functions = response
+            .get("functions")
+            .and_then(|functions| functions.as_array())
+            .and_then(|functions| {
+                functions
+                    .iter()
+                    .map(|f| f.as_str().map(str::to_owned))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| "invalid list of functions".to_owned())?;
+        Ok(Self {
+            path,
+            functions,
+            process: Mutex::new(process),
+        })
+    }
+
+    /// Call a function with the values of its arguments, sending the outputs of named steps and
+    /// loop variables along. Returns `None` if the function has no value for the arguments.
+    ///
+    /// Expressions are evaluated synchronously, so this blocks until the plugin answers or
+    /// [`CALL_TIMEOUT`] passes, letting the runtime move other tasks off the thread meanwhile.
+    pub(super) fn call(
+        &self,
+        function: &str,
+        args: Vec<Value>,
+        previous: &Scope,
+    ) -> Result<Option<Value>, Error> {
+        let steps: serde_json::Map<_, _> = previous
+            .outputs
+            .iter()
+            .map(|(name, out)| {
+                let mut step = json!({
+                    "raw_request": bytes_json(&out.raw_request),
+                    "raw_response": bytes_json(&out.raw_response),
+                    "duration": u64::try_from(out.duration.as_millis()).unwrap_or(u64::MAX),
+                });
+                let http = match &out.parsed {
+                    StepParsedOutput::HTTP(http) => Some(http),
+                    StepParsedOutput::GraphQL(graphql) => Some(&graphql.http),
+                    StepParsedOutput::GRPC(grpc) => Some(&grpc.http),
+                    _ => None,
+                };
+                if let Some(http) = http {
+                    step["response"] = json!({
+                        "status": http.status.as_u16(),
+                        "headers": http
+                            .headers
+                            .iter()
+                            .map(|(k, v)| json!([k.as_str(), bytes_json(v.as_bytes())]))
+                            .collect::<Vec<_>>(),
+                        "body": bytes_json(&http.body),
+                    });
+                }
+                (name.to_string(), step)
+            })
+            .collect();
+        let variables: serde_json::Map<_, _> = previous
+            .variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value_json(value.clone())))
+            .collect();
+        let request = json!({
+            "method": "call",
+            "function": function,
+            "args": args.into_iter().map(value_json).collect::<Vec<_>>(),
+            "steps": steps,
+            "variables": variables,
+        });
+
+        let failed = |message: String| Error::Plugin {
+            function: function.to_owned(),
+            message,
+        };
+        let mut process = self
+            .process
+            .lock()
+            .map_err(|_| failed("plugin is unusable after a panic".to_owned()))?;
+        let response = blocking(|| process.request(&request, CALL_TIMEOUT)).map_err(failed)?;
+        if let Some(error) = response.get("error") {
+            return Err(failed(
+                error
+                    .as_str()
+                    .map_or_else(|| error.to_string(), str::to_owned),
+            ));
+        }
+        match response.get("result") {
+            Some(result) => json_value(result).map_err(failed),
+            None => Err(failed("response has no result".to_owned())),
+        }
+    }
+}
+
+impl Process {
+    fn request(
+        &mut self,
+        request: &serde_json::Value,
+        timeout: Duration,
+    ) -> Result<serde_json::Value, String> {
+        if let Some(failure) = &self.failure {
+            return Err(failure.clone());
+        }
+        let mut line = request.to_string();
+        line.push('\n');
+        // The timeout covers writing the request as well as reading the response.
+        if self.requests.send(line).is_err() {
+            return Err("plugin stopped reading requests".to_owned());
+        }
+        let response = match self.responses.recv_timeout(timeout) {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                self.failure = Some(e.clone());
+                let _ = self.child.kill();
+                return Err(e);
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err("plugin exited".to_owned()),
+            Err(RecvTimeoutError::Timeout) => {
+                self.failure = Some("plugin was stopped after failing to answer".to_owned());
+                let _ = self.child.kill();
+                return Err(format!("no response within {:?}", timeout));
+            }
+        };
+        serde_json::from_str(&response).map_err(|e| format!("invalid response: {}", e))
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Plugins may keep running after their stdin closes.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Run `f`, which waits on a plugin, telling a multi-threaded runtime to move its other tasks to
+/// another thread.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Values are sent as JSON integers and strings, with bytes that aren't UTF-8 sent as
+/// `{"base64": "..."}`.
+fn value_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Int(i) => json!(i),
+        Value::String(s) => json!(s),
+        Value::Bytes(b) => bytes_json(&b),
+    }
+}
+
+fn bytes_json(bytes: &[u8]) -> serde_json::Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => json!(s),
+        Err(_) => json!({"base64": STANDARD.encode(bytes)}),
+    }
+}
+
+/// Read a result sent in the same form as values, with `null` for no value. Other JSON is used
+/// as the JSON text.
+fn json_value(json: &serde_json::Value) -> Result<Option<Value>, String> {
+    Ok(Some(match json {
+        serde_json::Value::Null => return Ok(None),
+        serde_json::Value::String(s) => Value::String(s.clone()),
+        serde_json::Value::Number(n) if n.is_i64() => Value::Int(n.as_i64().unwrap_or_default()),
+        serde_json::Value::Object(object) if object.len() == 1 && object.contains_key("base64") => {
+            let encoded = object["base64"].as_str().unwrap_or_default();
+            Value::Bytes(
+                STANDARD
+                    .decode(encoded)
+                    .map_err(|_| "invalid base64 result".to_owned())?,
+            )
+        }
+        json => Value::Bytes(json.to_string().into_bytes()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::exec::testutil::http_server;
+    use crate::exec::{Executor, Expectation, StepParsedOutput};
+    use crate::Plan;
+
+    /// Runs the example bash plugin, so it's checked along with the protocol.
+    #[tokio::test]
+    async fn plugin_test() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/plugins/bash");
+        let plugins = Plugins::discover([&dir]).unwrap();
+        let mut functions: Vec<_> = plugins.functions().map(|(f, _)| f).collect();
+        functions.sort();
+        assert_eq!(functions, ["shout", "status"]);
+
+        let port = http_server().await;
+        let plan = format!(
+            "http login EOF\nGET http://127.0.0.1:{port}/login\n\n\nEOF\n\
+            http EOF\nGET http://127.0.0.1:{port}/\nAuthorization: ${{shout(login.response.body)}} ${{status(\"login\")}}\n\n\nEOF\n\
+            expect status(\"logout\") exists\n\
+            expect shout(1) == 1\n\
+            expect missing() == 1"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = Executor::new(&plan);
+        executor.set_plugins(plugins);
+        executor.next().await.unwrap();
+        let StepParsedOutput::HTTP(out) = executor.next().await.unwrap().parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(out.body, b"TOKEN123! 200");
+
+        // A null result is no value.
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::Expectation(Expectation {
+                condition: "status(\"logout\") exists".to_owned(),
+                values: vec![("status(\"logout\")".to_owned(), None)],
+            }))
+        );
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::Plugin {
+                function: "shout".to_owned(),
+                message: "expected a string".to_owned(),
+            })
+        );
+        assert_eq!(
+            executor.next().await.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::UnknownFunction("missing".to_owned()))
+        );
+    }
+
+    /// Executables that don't speak the protocol are skipped instead of failing discovery, and
+    /// one that never answers is given up on.
+    #[cfg(unix)]
+    #[test]
+    fn plugin_skipped_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("courier-test-plugins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, script) in [
+            ("exits", "#!/bin/sh\nexit 1\n"),
+            ("prints", "#!/bin/sh\nread line\necho hello\n"),
+            ("hangs", "#!/bin/sh\nexec sleep 60\n"),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let e = Plugin::start(dir.join("hangs"), Duration::from_millis(100)).unwrap_err();
+        assert_eq!(e, "no response within 100ms");
+        std::fs::remove_file(dir.join("hangs")).unwrap();
+
+        let bash = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/plugins/bash");
+        let plugins = Plugins::discover([&bash, &dir]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(plugins.functions().count(), 2);
+        let mut skipped: Vec<_> = plugins
+            .skipped()
+            .map(|(path, _)| path.file_name().unwrap())
+            .collect();
+        skipped.sort();
+        assert_eq!(skipped, ["exits", "prints"]);
+    }
+
+    /// A plugin that stops reading its stdin can't block the executor once a request fills the
+    /// pipe.
+    #[cfg(unix)]
+    #[test]
+    fn plugin_unread_test() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("courier-test-deaf-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "#!/bin/sh\necho '{\"functions\":[\"deaf\"]}'\nexec sleep 60\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let plugin = Plugin::start(path.clone(), HANDSHAKE_TIMEOUT);
+        std::fs::remove_file(&path).unwrap();
+        let plugin = plugin.unwrap();
+        assert_eq!(plugin.functions, ["deaf"]);
+
+        let request = json!({"method": "call", "args": ["x".repeat(1 << 20)]});
+        let mut process = plugin.process.lock().unwrap();
+        assert_eq!(
+            process.request(&request, Duration::from_millis(100)),
+            Err("no response within 100ms".to_owned())
+        );
+        assert_eq!(
+            process.request(&request, Duration::from_millis(100)),
+            Err("plugin was stopped after failing to answer".to_owned())
+        );
+    }
+
+    #[test]
+    fn plugin_value_test() {
+        assert_eq!(value_json(Value::Int(-3)), json!(-3));
+        assert_eq!(
+            value_json(Value::Bytes(vec![0xff, 0])),
+            json!({"base64": "/wA="})
+        );
+        assert_eq!(
+            json_value(&json!("a")),
+            Ok(Some(Value::String("a".to_owned())))
+        );
+        assert_eq!(
+            json_value(&json!({"base64": "/wA="})),
+            Ok(Some(Value::Bytes(vec![0xff, 0])))
+        );
+        assert_eq!(json_value(&json!(null)), Ok(None));
+        assert_eq!(
+            json_value(&json!([1, true])),
+            Ok(Some(Value::Bytes(b"[1,true]".to_vec())))
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use hyper::http::uri::Authority;
+use quinn::crypto::rustls::{HandshakeData, QuicClientConfig};
+use quinn::{Connection, Endpoint, RecvStream, Side, StreamId};
+use rustls::pki_types::CertificateDer;
+use tokio::net::lookup_host;
+
+use super::tls::{self, ClientConfigs};
+use super::{expr, Certificate, StepInputs, StepOutput, StepParsedOutput};
+use crate::{QUICRequest, QUICStreamRequest, TLSOptions};
+
+/// The handshake parameters of a QUIC connection and everything sent and received on its
+/// streams. The QUIC version and the server's transport parameters aren't recorded, since the
+/// QUIC implementation doesn't expose them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QUICOutput {
+    pub remote_address: SocketAddr,
+    pub alpn: Option<Vec<u8>>,
+    pub sni: Option<String>,
+    /// The certificate chain presented by the server, starting with the end-entity certificate.
+    pub server_certificates: Vec<Certificate>,
+    /// The estimated round trip time once the step finished.
+    pub rtt: Duration,
+    /// Every stream opened by either side, in the order they were opened. Streams the server
+    /// opens are recorded once they're accepted, while the step is reading.
+    pub streams: Vec<QUICStream>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QUICStream {
+    pub id: u64,
+    pub initiator: QUICSide,
+    pub bidirectional: bool,
+    pub sent: Vec<u8>,
+    pub received: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QUICSide {
+    Client,
+    Server,
+}
+
+impl Display for QUICSide {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Client => f.write_str("client"),
+            Self::Server => f.write_str("server"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QUICReadEnd {
+    /// The server finished every bidirectional stream and every stream it opened.
+    Finished,
+    Timeout,
+}
+
+impl Display for QUICReadEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Finished => f.write_str("streams finished"),
+            Self::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+/// Collects the payloads of a connection's streams as they're sent and received.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Recorder(Arc<Mutex<Vec<QUICStream>>>);
+
+impl Recorder {
+    /// Start recording a stream. Streams are listed in the order they're opened.
+    pub(super) fn open(&self, id: StreamId) {
+        self.with(id, |_| ());
+    }
+
+    pub(super) fn sent(&self, id: StreamId, data: &[u8]) {
+        self.with(id, |stream| stream.sent.extend_from_slice(data));
+    }
+
+    pub(super) fn received(&self, id: StreamId, data: &[u8]) {
+        self.with(id, |stream| stream.received.extend_from_slice(data));
+    }
+
+    pub(super) fn streams(&self) -> Vec<QUICStream> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn with(&self, id: StreamId, f: impl FnOnce(&mut QUICStream)) {
+        let mut streams = self.0.lock().unwrap();
+        let index = match streams.iter().position(|s| s.id == u64::from(id)) {
+            Some(index) => index,
+            None => {
+                streams.push(QUICStream {
+                    id: id.into(),
+                    initiator: match id.initiator() {
+                        Side::Client => QUICSide::Client,
+                        Side::Server => QUICSide::Server,
+                    },
+                    bidirectional: id.dir() == quinn::Dir::Bi,
+                    sent: Vec::new(),
+                    received: Vec::new(),
+                });
+                streams.len() - 1
+            }
+        };
+        f(&mut streams[index]);
+    }
+}
+
+/// Open a QUIC connection to the host, offering the given ALPN protocols. The returned output has
+/// no streams yet.
+pub(super) async fn connect(
+    host: &str,
+    port: u16,
+    alpn: &[&[u8]],
+    options: &TLSOptions<'_>,
+    configs: &ClientConfigs,
+) -> Result<(Endpoint, Connection, QUICOutput), Box<dyn std::error::Error + Send + Sync>> {
+    // IPv6 hosts in URIs are wrapped in brackets.
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let remote = lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| format!("no addresses found for {}", host))?;
+    let local: SocketAddr = if remote.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+
+    let crypto = QuicClientConfig::try_from(tls::client_config(options, alpn, configs)?)?;
+    let endpoint = Endpoint::client(local)?;
+    let conn = endpoint
+        .connect_with(quinn::ClientConfig::new(Arc::new(crypto)), remote, host)?
+        .await?;
+
+    let alpn = conn
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.protocol);
+    let server_certificates = conn
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer>>().ok())
+        .map(|certs| {
+            certs
+                .iter()
+                .map(|cert| Certificate {
+                    der: cert.as_ref().to_vec(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let output = QUICOutput {
+        remote_address: remote,
+        alpn,
+        // No SNI is sent for IP addresses.
+        sni: host.parse::<IpAddr>().is_err().then(|| host.to_owned()),
+        server_certificates,
+        rtt: conn.rtt(),
+        streams: Vec::new(),
+    };
+    Ok((endpoint, conn, output))
+}
+
+pub(super) async fn execute(
+    step: &QUICRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let address: Authority =
+        String::from_utf8(expr::render(&step.address, inputs.previous)?)?.parse()?;
+    let port = address.port_u16().ok_or("quic address has no port")?;
+    let alpn: Vec<_> = step.options.alpn.iter().map(|p| p.as_bytes()).collect();
+    let (endpoint, conn, mut output) =
+        connect(address.host(), port, &alpn, &step.tls, inputs.tls_configs).await?;
+
+    // Send every stream before reading any responses, so the server sees them all no matter
+    // which order it answers in.
+    let recorder = Recorder::default();
+    let mut reads = Vec::new();
+    for stream in &step.streams {
+        let (mut send, recv, payload) = match stream {
+            QUICStreamRequest::Bidi(payload) => {
+                let (send, recv) = conn.open_bi().await?;
+                (send, Some(recv), payload)
+            }
+            QUICStreamRequest::Uni(payload) => (conn.open_uni().await?, None, payload),
+        };
+        let payload = expr::render_escaped(payload, inputs.previous)?;
+        recorder.open(send.id());
+        send.write_all(&payload).await?;
+        recorder.sent(send.id(), &payload);
+        send.finish()?;
+        if let Some(recv) = recv {
+            reads.push(read(recv, recorder.clone()));
+        }
+    }
+    let reads = read_all(&conn, reads, &recorder);
+    let end = match step.options.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, reads).await {
+            Ok(result) => result.map(|_| QUICReadEnd::Finished)?,
+            Err(_) => QUICReadEnd::Timeout,
+        },
+        None => reads.await.map(|_| QUICReadEnd::Finished)?,
+    };
+
+    output.rtt = conn.rtt();
+    conn.close(0u32.into(), b"");
+    endpoint.wait_idle().await;
+
+    output.streams = recorder.streams();
+    let raw_request = output.streams.iter().flat_map(|s| s.sent.clone()).collect();
+    let raw_response = output
+        .streams
+        .iter()
+        .flat_map(|s| s.received.clone())
+        .collect();
+    Ok(StepOutput {
+        raw_request,
+        raw_response,
+        tls: None,
+        http2: None,
+        quic: Some(output),
+        connection: None,
+        duration: Duration::ZERO,
+        parsed: StepParsedOutput::QUIC(end),
+    })
+}
+
+/// Read every stream in `reads` and every stream the server opens meanwhile until they're all
+/// finished.
+async fn read_all(
+    conn: &Connection,
+    reads: Vec<impl Future<Output = Result<(), ReadError>> + Send + 'static>,
+    recorder: &Recorder,
+) -> Result<(), ReadError> {
+    let mut reads: FuturesUnordered<BoxFuture<'static, _>> =
+        reads.into_iter().map(FutureExt::boxed).collect();
+    loop {
+        // Streams the server already opened are accepted before deciding that reading is done.
+        tokio::select! {
+            biased;
+            Ok(recv) = conn.accept_uni() => {
+                recorder.open(recv.id());
+                reads.push(read(recv, recorder.clone()).boxed());
+            }
+            Ok((mut send, recv)) = conn.accept_bi() => {
+                recorder.open(recv.id());
+                // Nothing is sent on streams the server opens.
+                let _ = send.finish();
+                reads.push(read(recv, recorder.clone()).boxed());
+            }
+            result = reads.next() => match result {
+                Some(result) => result?,
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+type ReadError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Read a stream until the server finishes it, recording each chunk as it arrives so a timeout
+/// keeps whatever was read.
+async fn read(mut recv: RecvStream, recorder: Recorder) -> Result<(), ReadError> {
+    while let Some(chunk) = recv.read_chunk(usize::MAX, true).await? {
+        recorder.received(recv.id(), &chunk.bytes);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::exec::testutil::{quic_echo_server, TestInputs};
+    use crate::{QUICOptions, TLSVerify, Template};
+
+    async fn run(
+        port: u16,
+        streams: Vec<QUICStreamRequest<'_>>,
+        timeout: Option<Duration>,
+    ) -> StepOutput {
+        let address = format!("localhost:{}", port);
+        execute(
+            &QUICRequest {
+                address: Template::literal(&address),
+                streams,
+                tls: TLSOptions {
+                    verify: TLSVerify::Insecure,
+                },
+                options: QUICOptions {
+                    alpn: vec!["echo"],
+                    timeout,
+                },
+            },
+            &TestInputs::default().get(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn quic_test() {
+        let (port, cert) = quic_echo_server().await;
+        let out = run(
+            port,
+            vec![
+                QUICStreamRequest::Bidi(Template::literal("hello\\n")),
+                QUICStreamRequest::Uni(Template::literal("ignored")),
+                QUICStreamRequest::Bidi(Template::literal("again")),
+            ],
+            None,
+        )
+        .await;
+        assert_eq!(out.parsed, StepParsedOutput::QUIC(QUICReadEnd::Finished));
+
+        let quic = out.quic.unwrap();
+        assert_eq!(quic.alpn, Some(b"echo".to_vec()));
+        assert_eq!(quic.sni.as_deref(), Some("localhost"));
+        assert_eq!(quic.server_certificates[0].der, cert.der().to_vec());
+        assert_eq!(
+            quic.streams,
+            vec![
+                QUICStream {
+                    id: 0,
+                    initiator: QUICSide::Client,
+                    bidirectional: true,
+                    sent: b"hello\n".to_vec(),
+                    received: b"hello\n".to_vec(),
+                },
+                QUICStream {
+                    id: 2,
+                    initiator: QUICSide::Client,
+                    bidirectional: false,
+                    sent: b"ignored".to_vec(),
+                    received: Vec::new(),
+                },
+                QUICStream {
+                    id: 4,
+                    initiator: QUICSide::Client,
+                    bidirectional: true,
+                    sent: b"again".to_vec(),
+                    received: b"again".to_vec(),
+                },
+            ]
+        );
+        assert_eq!(out.raw_request, b"hello\nignoredagain");
+        assert_eq!(out.raw_response, b"hello\nagain");
+    }
+
+    #[tokio::test]
+    async fn quic_server_stream_test() {
+        // The server opens a unidirectional stream of its own before answering "push".
+        let (port, _) = quic_echo_server().await;
+        let out = run(
+            port,
+            vec![QUICStreamRequest::Bidi(Template::literal("push"))],
+            None,
+        )
+        .await;
+        assert_eq!(out.parsed, StepParsedOutput::QUIC(QUICReadEnd::Finished));
+        assert_eq!(
+            out.quic.unwrap().streams,
+            vec![
+                QUICStream {
+                    id: 0,
+                    initiator: QUICSide::Client,
+                    bidirectional: true,
+                    sent: b"push".to_vec(),
+                    received: b"push".to_vec(),
+                },
+                QUICStream {
+                    id: 3,
+                    initiator: QUICSide::Server,
+                    bidirectional: false,
+                    sent: Vec::new(),
+                    received: b"pushed".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn quic_timeout_test() {
+        // The server holds streams that start with "wait" open without answering.
+        let (port, _) = quic_echo_server().await;
+        let out = run(
+            port,
+            vec![
+                QUICStreamRequest::Bidi(Template::literal("hi")),
+                QUICStreamRequest::Bidi(Template::literal("wait")),
+            ],
+            Some(Duration::from_millis(200)),
+        )
+        .await;
+        assert_eq!(out.parsed, StepParsedOutput::QUIC(QUICReadEnd::Timeout));
+        let streams = out.quic.unwrap().streams;
+        assert_eq!(streams[0].received, b"hi");
+        assert_eq!(streams[1].received, b"");
+    }
+}
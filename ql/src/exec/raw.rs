@@ -0,0 +1,659 @@
+use std::time::Duration;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::uri::Authority;
+use hyper::{HeaderMap, StatusCode, Uri};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::body::{find, render_payload};
+use super::connection::Target;
+use super::http::read_body;
+use super::tee::{Tee, Transcript};
+use super::{
+    tls, HTTPChunk, HTTPOutput, HTTPVersion, StepInputs, StepOutput, StepParsedOutput, TLSOutput,
+};
+use crate::{RawHTTPRequest, TLSOptions};
+
+pub(super) async fn execute(
+    step: &RawHTTPRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let mut request = render_payload(&step.request, step.options.charset, inputs)?;
+    if step.raw.content_length {
+        request = add_content_length(request);
+    }
+
+    let (host, port, https) = target(&request)?;
+    // Use TLS if the target asks for it, even when the step didn't set any TLS options.
+    let tls = step.tls.clone().or_else(|| https.then(TLSOptions::default));
+    let port = port.unwrap_or(if tls.is_some() { 443 } else { 80 });
+    let head_request = lines(&request)
+        .next()
+        .is_some_and(|line| line.starts_with(b"HEAD "));
+
+    let mut out = if let Some(connection) = &step.connection {
+        let target = Target {
+            host: &host,
+            port,
+            tls: tls.as_ref(),
+            alpn: b"http/1.1",
+        };
+        let mut connections = inputs.connections.lock().await;
+        connections
+            .send(
+                connection,
+                target,
+                &request,
+                &[],
+                head_request,
+                inputs.tls_configs,
+            )
+            .await?
+    } else {
+        let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        match &tls {
+            Some(options) => {
+                let (stream, tls) =
+                    tls::handshake(stream, &host, &[b"http/1.1"], options, inputs.tls_configs)
+                        .await?;
+                send(stream, &request, &[], head_request, Some(tls)).await?
+            }
+            None => send(stream, &request, &[], head_request, None).await?,
+        }
+    };
+    if let StepParsedOutput::HTTP(http) = &mut out.parsed {
+        read_body(http, &step.options)?;
+    }
+    Ok(out)
+}
+
+/// Write a request and read the response to it on a connection of its own.
+pub(super) async fn send<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: T,
+    request: &[u8],
+    pauses: &[(usize, Duration)],
+    head_request: bool,
+    tls: Option<TLSOutput>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let (mut stream, transcript) = Tee::new(stream);
+    write_request(&mut stream, request, pauses).await?;
+    let (response, _) =
+        read_response(&mut stream, &mut Vec::new(), &mut false, head_request).await?;
+    drop(stream);
+    let Transcript { reads, writes } = transcript.await?;
+
+    Ok(StepOutput {
+        raw_request: writes,
+        raw_response: reads,
+        tls,
+        http2: None,
+        quic: None,
+        connection: None,
+        duration: Duration::ZERO,
+        parsed: StepParsedOutput::HTTP(response),
+    })
+}
+
+/// Write a request in pieces if there are any pauses, each an offset into the request and how
+/// long to wait before writing from there.
+pub(super) async fn write_request<T: AsyncWrite + Unpin>(
+    stream: &mut T,
+    request: &[u8],
+    pauses: &[(usize, Duration)],
+) -> std::io::Result<()> {
+    let mut written = 0;
+    for &(offset, delay) in pauses {
+        stream.write_all(&request[written..offset]).await?;
+        stream.flush().await?;
+        tokio::time::sleep(delay).await;
+        written = offset;
+    }
+    stream.write_all(&request[written..]).await?;
+    stream.flush().await
+}
+
+/// Read the next response from a stream, starting with the bytes already in `received`. The
+/// response's bytes are taken out of `received` and returned with it, leaving anything after
+/// the response for the next read. `closed` is set once the stream has closed.
+pub(super) async fn read_response<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    received: &mut Vec<u8>,
+    closed: &mut bool,
+    head_request: bool,
+) -> Result<(HTTPOutput, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut buf = [0; 4096];
+    loop {
+        if let Some((response, length)) = parse_response(received, *closed, head_request)? {
+            return Ok((response, received.drain(..length).collect()));
+        }
+        let n = match stream.read(&mut buf).await {
+            Ok(n) => n,
+            // Servers often reset the connection after answering a request they didn't like, so
+            // treat that like closing it once something has arrived.
+            Err(_) if !received.is_empty() => 0,
+            Err(e) => return Err(e.into()),
+        };
+        *closed = n == 0;
+        received.extend_from_slice(&buf[..n]);
+    }
+}
+
+/// Find where to send a request: the request target if it's an absolute URL, or the `Host`
+/// header otherwise. Returns the host, the port if one was given, and whether the URL asks for
+/// TLS.
+fn target(
+    request: &[u8],
+) -> Result<(String, Option<u16>, bool), Box<dyn std::error::Error + Send + Sync>> {
+    let head = &request[..head_end(request).unwrap_or(request.len())];
+    let mut lines = lines(head);
+    let url = lines
+        .next()
+        .and_then(|line| {
+            line.split(u8::is_ascii_whitespace)
+                .filter(|t| !t.is_empty())
+                .nth(1)
+        })
+        .and_then(|target| Uri::try_from(target).ok())
+        .filter(|uri| uri.host().is_some());
+    if let Some(url) = url {
+        let host = url.host().unwrap_or_default().to_owned();
+        return Ok((host, url.port_u16(), url.scheme_str() == Some("https")));
+    }
+    let host = lines
+        .find_map(|line| header(line).filter(|(name, _)| name.eq_ignore_ascii_case(b"host")))
+        .ok_or("the request has no absolute URL or Host header to connect to")?
+        .1;
+    let authority: Authority = std::str::from_utf8(host)?.parse()?;
+    Ok((authority.host().to_owned(), authority.port_u16(), false))
+}
+
+/// Add a `Content-Length` header for everything after the head, unless the head already has
+/// one. The header goes at the end of the head, using the same line ending as the blank line
+/// after it. Requests without a blank line after the head are left alone.
+fn add_content_length(mut request: Vec<u8>) -> Vec<u8> {
+    let Some(end) = head_end(&request) else {
+        return request;
+    };
+    let has_length = lines(&request[..end])
+        .skip(1)
+        .filter_map(header)
+        .any(|(name, _)| name.eq_ignore_ascii_case(b"content-length"));
+    if has_length {
+        return request;
+    }
+    let line_ending: &[u8] = if request[..end].ends_with(b"\n\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+    let mut header = format!("Content-Length: {}", request.len() - end).into_bytes();
+    header.extend_from_slice(line_ending);
+    let at = end - line_ending.len();
+    request.splice(at..at, header);
+    request
+}
+
+/// Read an HTTP/1 response, accepting the mistakes servers make and the malformed responses
+/// that request smuggling tests provoke. Lines may end with a bare `\n`, header lines that
+/// can't be parsed are skipped, and a body cut short by the connection closing is kept.
+/// Responses without a status line are taken as HTTP/0.9. Interim `1xx` responses are skipped.
+///
+/// Returns the response and how many bytes of `data` it took up, or `None` if more data is
+/// needed, which can only happen before the connection closes.
+pub(super) fn parse_response(
+    input: &[u8],
+    closed: bool,
+    head_request: bool,
+) -> Result<Option<(HTTPOutput, usize)>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut data = input;
+    loop {
+        let start = data
+            .iter()
+            .position(|b| !matches!(b, b'\r' | b'\n'))
+            .unwrap_or(data.len());
+        let message = &data[start..];
+        if !message.starts_with(b"HTTP/") {
+            // Either the status line hasn't fully arrived yet, or this is an HTTP/0.9 response
+            // which ends when the connection closes.
+            if !closed {
+                return Ok(None);
+            }
+            if message.is_empty() {
+                return Err("the connection closed without a response".into());
+            }
+            let out = HTTPOutput {
+                version: HTTPVersion::HTTP0_9,
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: data.to_vec(),
+                raw_body: None,
+                decode_error: None,
+                parsed_body: None,
+                chunks: Vec::new(),
+                trailers: HeaderMap::new(),
+            };
+            return Ok(Some((out, input.len())));
+        }
+
+        let end = match head_end(message) {
+            Some(end) => end,
+            None if closed => message.len(),
+            None => return Ok(None),
+        };
+        let mut lines = lines(&message[..end]);
+        let status_line = lines.next().unwrap_or_default();
+        let mut tokens = status_line
+            .split(u8::is_ascii_whitespace)
+            .filter(|t| !t.is_empty());
+        let version = match tokens.next() {
+            Some(b"HTTP/1.0") => HTTPVersion::HTTP1_0,
+            Some(b"HTTP/1.1") => HTTPVersion::HTTP1_1,
+            _ => HTTPVersion::Unrecognized,
+        };
+        let status = tokens
+            .next()
+            .and_then(|code| StatusCode::from_bytes(code).ok())
+            .ok_or_else(|| {
+                format!(
+                    "invalid status line {:?}",
+                    String::from_utf8_lossy(status_line)
+                )
+            })?;
+        let headers = headers(lines);
+        let rest = &message[end..];
+        // The length of the response so far.
+        let head = input.len() - rest.len();
+
+        // Skip interim responses like 100 Continue, which are followed by the real one.
+        if status.is_informational() && status != StatusCode::SWITCHING_PROTOCOLS {
+            if rest.is_empty() && !closed {
+                return Ok(None);
+            }
+            if !rest.is_empty() {
+                data = rest;
+                continue;
+            }
+        }
+
+        let out = |body| HTTPOutput {
+            version: version.clone(),
+            status,
+            headers: headers.clone(),
+            body,
+            raw_body: None,
+            decode_error: None,
+            parsed_body: None,
+            chunks: Vec::new(),
+            trailers: HeaderMap::new(),
+        };
+        if head_request
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return Ok(Some((out(Vec::new()), head)));
+        }
+        if is_chunked(&headers) {
+            return Ok(dechunk(rest, closed).map(|chunked| {
+                let out = HTTPOutput {
+                    chunks: chunked.chunks,
+                    trailers: chunked.trailers,
+                    ..out(chunked.body)
+                };
+                (out, head + chunked.length)
+            }));
+        }
+        let body = if headers.contains_key(hyper::header::TRANSFER_ENCODING) {
+            // Other transfer codings are delimited by the connection closing.
+            closed.then(|| rest.to_vec())
+        } else if let Some(length) = content_length(&headers) {
+            match rest.get(..length) {
+                Some(body) => Some(body.to_vec()),
+                None => closed.then(|| rest.to_vec()),
+            }
+        } else {
+            closed.then(|| rest.to_vec())
+        };
+        return Ok(body.map(|body| {
+            let length = head + body.len();
+            (out(body), length)
+        }));
+    }
+}
+
+/// Collect header lines into a map, joining folded lines onto the header before them and
+/// skipping lines that aren't a valid header.
+fn headers<'a>(lines: impl Iterator<Item = &'a [u8]>) -> HeaderMap {
+    let mut fields: Vec<(&[u8], Vec<u8>)> = Vec::new();
+    for line in lines {
+        match (line.first(), fields.last_mut()) {
+            (Some(b' ' | b'\t'), Some((_, value))) => {
+                value.push(b' ');
+                value.extend_from_slice(line.trim_ascii());
+            }
+            _ => fields.extend(header(line).map(|(name, value)| (name, value.to_vec()))),
+        }
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in fields {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name),
+            HeaderValue::from_bytes(&value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+/// Split a header line into its name and value, allowing whitespace around either.
+fn header(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let colon = line.iter().position(|&b| b == b':')?;
+    let name = line[..colon].trim_ascii();
+    (!name.is_empty()).then(|| (name, line[colon + 1..].trim_ascii()))
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(hyper::header::TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Get the body length from the first `Content-Length` header. A list of lengths, as sent by
+/// some proxies, uses the first one.
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(hyper::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .split(',')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// A chunked body split into its chunks, ending with the last chunk if it arrived.
+struct Dechunked {
+    body: Vec<u8>,
+    chunks: Vec<HTTPChunk>,
+    trailers: HeaderMap,
+    /// How many bytes the chunks and trailers took up.
+    length: usize,
+}
+
+/// Split a chunked body into its chunks and read any trailers after the last one. A missing
+/// line ending after a chunk is allowed, and a chunk size that can't be parsed ends the body.
+fn dechunk(data: &[u8], closed: bool) -> Option<Dechunked> {
+    let mut out = Dechunked {
+        body: Vec::new(),
+        chunks: Vec::new(),
+        trailers: HeaderMap::new(),
+        length: data.len(),
+    };
+    let mut rest = data;
+    loop {
+        let Some(line_end) = find(rest, b"\n") else {
+            return closed.then_some(out);
+        };
+        let line = &rest[..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (size, extensions) = match line.iter().position(|&b| b == b';') {
+            Some(semicolon) => (&line[..semicolon], &line[semicolon + 1..]),
+            None => (line, &b""[..]),
+        };
+        let Some(size) = std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+        else {
+            out.length = data.len() - rest.len();
+            return Some(out);
+        };
+        rest = &rest[line_end + 1..];
+        if size == 0 {
+            out.chunks.push(HTTPChunk {
+                size,
+                extensions: extensions.to_vec(),
+                data: Vec::new(),
+            });
+            // The body ends at the blank line after any trailers.
+            let end = if rest.starts_with(b"\n") {
+                Some(1)
+            } else if rest.starts_with(b"\r\n") {
+                Some(2)
+            } else {
+                head_end(rest)
+            };
+            let end = match end {
+                Some(end) => end,
+                None if closed => rest.len(),
+                None => return None,
+            };
+            out.trailers = headers(lines(&rest[..end]));
+            out.length = data.len() - rest.len() + end;
+            return Some(out);
+        }
+        let Some(chunk) = rest.get(..size) else {
+            if !closed {
+                return None;
+            }
+            out.body.extend_from_slice(rest);
+            out.chunks.push(HTTPChunk {
+                size,
+                extensions: extensions.to_vec(),
+                data: rest.to_vec(),
+            });
+            return Some(out);
+        };
+        out.body.extend_from_slice(chunk);
+        out.chunks.push(HTTPChunk {
+            size,
+            extensions: extensions.to_vec(),
+            data: chunk.to_vec(),
+        });
+        rest = &rest[size..];
+        rest = rest
+            .strip_prefix(b"\r\n")
+            .or_else(|| rest.strip_prefix(b"\n"))
+            .unwrap_or(rest);
+    }
+}
+
+/// Find the end of a message's head, just after the blank line that ends it.
+fn head_end(data: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    for (i, _) in data.iter().enumerate().filter(|(_, &b)| b == b'\n') {
+        let line = &data[start..i];
+        if start > 0 && (line.is_empty() || line == b"\r") {
+            return Some(i + 1);
+        }
+        start = i + 1;
+    }
+    None
+}
+
+/// Split text into lines ending in either `\r\n` or `\n`.
+fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::testutil::raw_server;
+    use crate::exec::Executor;
+    use crate::Plan;
+
+    fn parse(data: &[u8], closed: bool) -> Option<HTTPOutput> {
+        parse_response(data, closed, false)
+            .unwrap()
+            .map(|(out, _)| out)
+    }
+
+    #[test]
+    fn parse_response_test() {
+        // Bare line endings, spaces around header names and folded lines are all accepted.
+        let out = parse(
+            b"HTTP/1.1 200 OK\nContent-Length : 2\nX-Long: a\n  b\nbad line\n\nokextra",
+            false,
+        )
+        .unwrap();
+        assert_eq!(out.version, HTTPVersion::HTTP1_1);
+        assert_eq!(out.status, StatusCode::OK);
+        assert_eq!(out.headers["content-length"], "2");
+        assert_eq!(out.headers["x-long"], "a b");
+        assert_eq!(out.headers.len(), 2);
+        assert_eq!(out.body, b"ok");
+
+        // Anything after the end of a response is left for the next one.
+        let pipelined = b"HTTP/1.1 204 No Content\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n0\r\n\r\nHTTP/1.1";
+        let (_, length) = parse_response(pipelined, false, false).unwrap().unwrap();
+        assert_eq!(length, 27);
+        let (out, length) = parse_response(&pipelined[27..], false, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(out.body, b"a");
+        assert_eq!(&pipelined[27 + length..], b"HTTP/1.1");
+
+        // Chunked bodies may have extensions, trailers and missing line endings.
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc2\r\nde\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let out = parse(chunked, false).unwrap();
+        assert_eq!(out.body, b"abcde");
+        assert_eq!(
+            out.chunks,
+            [
+                HTTPChunk {
+                    size: 3,
+                    extensions: b"x=y".to_vec(),
+                    data: b"abc".to_vec(),
+                },
+                HTTPChunk {
+                    size: 2,
+                    extensions: Vec::new(),
+                    data: b"de".to_vec(),
+                },
+                HTTPChunk {
+                    size: 0,
+                    extensions: Vec::new(),
+                    data: Vec::new(),
+                },
+            ]
+        );
+        assert_eq!(out.trailers["x-trailer"], "1");
+        assert!(!out.headers.contains_key("x-trailer"));
+        assert_eq!(parse(&chunked[..chunked.len() - 2], false), None);
+        assert_eq!(
+            parse(&chunked[..chunked.len() - 2], true).unwrap().body,
+            b"abcde"
+        );
+        // A chunk cut short keeps its declared size.
+        let out = parse(&chunked[..chunked.len() - 22], true).unwrap();
+        assert_eq!(out.body, b"abcd");
+        assert_eq!(out.chunks[1].size, 2);
+        assert_eq!(out.chunks[1].data, b"d");
+
+        // Bodies cut short are kept once the connection closes.
+        let short = b"HTTP/1.0 404 Not Found\r\nContent-Length: 10\r\n\r\nnope";
+        assert_eq!(parse(short, false), None);
+        let out = parse(short, true).unwrap();
+        assert_eq!(out.version, HTTPVersion::HTTP1_0);
+        assert_eq!(out.body, b"nope");
+
+        // Without a length, the body lasts until the connection closes.
+        assert_eq!(parse(b"HTTP/1.1 200 OK\r\n\r\nabc", false), None);
+        assert_eq!(
+            parse(b"HTTP/1.1 200 OK\r\n\r\nabc", true).unwrap().body,
+            b"abc"
+        );
+
+        let out = parse(
+            b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(out.status, StatusCode::CREATED);
+        assert_eq!(parse(b"HTTP/1.1 100 Continue\r\n\r\n", false), None);
+
+        let out = parse(b"<html>hi</html>", true).unwrap();
+        assert_eq!(out.version, HTTPVersion::HTTP0_9);
+        assert_eq!(out.body, b"<html>hi</html>");
+
+        // Responses to HEAD requests have no body whatever their headers say.
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n";
+        assert_eq!(
+            parse_response(head, false, true).unwrap().unwrap().0.body,
+            b""
+        );
+
+        assert!(parse_response(b"HTTP/1.1 OK\r\n\r\n", false, false).is_err());
+        assert!(parse_response(b"", true, false).is_err());
+    }
+
+    #[test]
+    fn add_content_length_test() {
+        assert_eq!(
+            add_content_length(b"POST / HTTP/1.1\r\nHost: a\r\n\r\nhello".to_vec()),
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello"
+        );
+        assert_eq!(
+            add_content_length(b"GET / HTTP/1.1\n\n".to_vec()),
+            b"GET / HTTP/1.1\nContent-Length: 0\n\n"
+        );
+        assert_eq!(
+            add_content_length(b"POST / HTTP/1.1\ncontent-length:9\n\nhello".to_vec()),
+            b"POST / HTTP/1.1\ncontent-length:9\n\nhello"
+        );
+        assert_eq!(
+            add_content_length(b"GET / HTTP/1.1".to_vec()),
+            b"GET / HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn target_test() {
+        assert_eq!(
+            target(b"GET https://example.com:8443/a HTTP/1.1\r\nHost: other\r\n\r\n").unwrap(),
+            ("example.com".to_owned(), Some(8443), true)
+        );
+        assert_eq!(
+            target(b"GET /a HTTP/1.1\r\nX: 1\r\nhost:  example.com:81 \r\n\r\n").unwrap(),
+            ("example.com".to_owned(), Some(81), false)
+        );
+        assert!(target(b"GET /a HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn raw_test() {
+        let (port, server) = raw_server(
+            b"HTTP/1.1 200 OK\nTransfer-Encoding: chunked\n\n2;last\nok\n0\nX-Sum: 2\n\n"
+                .as_slice(),
+        )
+        .await;
+        // The request goes out exactly as written, however malformed.
+        let plan = format!(
+            "http(raw=true, body=escaped) EOF\n\
+            get /a%zz HTTP/1.1\\r\nhost: 127.0.0.1:{port}\\r\nTransfer-Encoding : chunked\\r\nX:\\x01\\r\n\\r\n\nEOF"
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let out = Executor::new(&plan).next().await.unwrap();
+        let expected = format!(
+            "get /a%zz HTTP/1.1\r\nhost: 127.0.0.1:{port}\r\nTransfer-Encoding : chunked\r\nX:\x01\r\n\r\n"
+        );
+        assert_eq!(server.await.unwrap(), expected.as_bytes());
+        assert_eq!(out.raw_request, expected.as_bytes());
+        let StepParsedOutput::HTTP(parsed) = out.parsed else {
+            panic!("expected an HTTP response");
+        };
+        assert_eq!(parsed.status, StatusCode::OK);
+        assert_eq!(parsed.body, b"ok");
+        assert_eq!(parsed.chunks.len(), 2);
+        assert_eq!(parsed.trailers["x-sum"], "2");
+    }
+}
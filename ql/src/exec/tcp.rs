@@ -0,0 +1,177 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::body::render_payload;
+use super::tee::{Tee, Transcript};
+use super::{expr, StepInputs, StepOutput, StepParsedOutput};
+use crate::{TCPRead, TCPRequest};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TCPOutput {
+    /// Why reading the response stopped.
+    pub end: TCPReadEnd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TCPReadEnd {
+    Closed,
+    Delimiter,
+    Bytes,
+    Timeout,
+}
+
+impl Display for TCPReadEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Closed => f.write_str("connection closed"),
+            Self::Delimiter => f.write_str("delimiter"),
+            Self::Bytes => f.write_str("byte count"),
+            Self::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+pub(super) async fn execute(
+    step: &TCPRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let address = String::from_utf8(expr::render(&step.address, inputs.previous)?)?;
+    let body = render_payload(&step.body, step.options.charset, inputs)?;
+
+    let stream = TcpStream::connect(address).await?;
+    let (mut stream, transcript) = Tee::new(stream);
+    stream.write_all(&body).await?;
+
+    let response = read(&mut stream, &step.options.read);
+    let end = match step.options.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, response)
+            .await
+            .unwrap_or(Ok(TCPReadEnd::Timeout))?,
+        None => response.await?,
+    };
+    drop(stream);
+    let Transcript { reads, writes } = transcript.await?;
+
+    Ok(StepOutput {
+        raw_request: writes,
+        raw_response: reads,
+        tls: None,
+        http2: None,
+        quic: None,
+        connection: None,
+        duration: Duration::ZERO,
+        parsed: StepParsedOutput::TCP(TCPOutput { end }),
+    })
+}
+
+async fn read<T: AsyncRead + Unpin>(stream: &mut T, mode: &TCPRead) -> io::Result<TCPReadEnd> {
+    let mut buf = [0; 4096];
+    match mode {
+        TCPRead::Close => loop {
+            if stream.read(&mut buf).await? == 0 {
+                return Ok(TCPReadEnd::Closed);
+            }
+        },
+        TCPRead::Delimiter(delimiter) => {
+            // Read a byte at a time so nothing after the delimiter is consumed.
+            let mut received = Vec::new();
+            loop {
+                if stream.read(&mut buf[..1]).await? == 0 {
+                    return Ok(TCPReadEnd::Closed);
+                }
+                received.push(buf[0]);
+                if received.ends_with(delimiter) {
+                    return Ok(TCPReadEnd::Delimiter);
+                }
+            }
+        }
+        TCPRead::Bytes(count) => {
+            let mut remaining = *count;
+            while remaining > 0 {
+                let len = remaining.min(buf.len());
+                let n = stream.read(&mut buf[..len]).await?;
+                if n == 0 {
+                    return Ok(TCPReadEnd::Closed);
+                }
+                remaining -= n;
+            }
+            Ok(TCPReadEnd::Bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::exec::testutil::{line_server, TestInputs};
+    use crate::{Payload, TCPOptions, Template};
+
+    async fn run(address: &str, read: TCPRead, timeout: Option<Duration>) -> StepOutput {
+        execute(
+            &TCPRequest {
+                address: Template::literal(address),
+                body: Payload::Raw(Template::literal("PING\r\n")),
+                options: TCPOptions {
+                    read,
+                    timeout,
+                    ..TCPOptions::default()
+                },
+            },
+            &TestInputs::default().get(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tcp_test() {
+        let (port, server) = line_server(b"+PONG\r\nextra", true).await;
+        let address = format!("127.0.0.1:{}", port);
+        let out = run(&address, TCPRead::Close, None).await;
+        assert_eq!(server.await.unwrap(), b"PING\r\n");
+        assert_eq!(out.raw_request, b"PING\r\n");
+        assert_eq!(out.raw_response, b"+PONG\r\nextra");
+        assert_eq!(
+            out.parsed,
+            StepParsedOutput::TCP(TCPOutput {
+                end: TCPReadEnd::Closed
+            })
+        );
+
+        let (port, _) = line_server(b"+PONG\r\nextra", false).await;
+        let address = format!("127.0.0.1:{}", port);
+        let out = run(&address, TCPRead::Delimiter(b"\r\n".to_vec()), None).await;
+        assert_eq!(out.raw_response, b"+PONG\r\n");
+        assert_eq!(
+            out.parsed,
+            StepParsedOutput::TCP(TCPOutput {
+                end: TCPReadEnd::Delimiter
+            })
+        );
+
+        let (port, _) = line_server(b"+PONG\r\nextra", false).await;
+        let address = format!("127.0.0.1:{}", port);
+        let out = run(&address, TCPRead::Bytes(3), None).await;
+        assert_eq!(out.raw_response, b"+PO");
+    }
+
+    #[tokio::test]
+    async fn tcp_timeout_test() {
+        // The server never closes the connection, so reading stops at the timeout.
+        let (port, _) = line_server(b"+PONG\r\n", false).await;
+        let address = format!("127.0.0.1:{}", port);
+        let out = run(&address, TCPRead::Close, Some(Duration::from_millis(100))).await;
+        assert_eq!(out.raw_response, b"+PONG\r\n");
+        assert_eq!(
+            out.parsed,
+            StepParsedOutput::TCP(TCPOutput {
+                end: TCPReadEnd::Timeout
+            })
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::ops::DerefMut;
+use std::pin::Pin;
+
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::sync::oneshot;
+
+/// Records everything read from and written to a stream. The transcript is sent to the receiver
+/// returned by [`Tee::new`] when the Tee is dropped.
+pub(super) struct Tee<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> {
+    inner: T,
+    transcript: Transcript,
+    done: Option<oneshot::Sender<Transcript>>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Transcript {
+    pub reads: Vec<u8>,
+    pub writes: Vec<u8>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Tee<T> {
+    pub fn new(wrap: T) -> (Self, oneshot::Receiver<Transcript>) {
+        let (done, transcript) = oneshot::channel();
+        let tee = Tee {
+            inner: wrap,
+            transcript: Transcript::default(),
+            done: Some(done),
+        };
+        (tee, transcript)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Drop for Tee<T> {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            // The receiver may have given up on the transcript.
+            let _ = done.send(std::mem::take(&mut self.transcript));
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncRead for Tee<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let old_len = buf.filled().len();
+        let poll = Pin::new(&mut self.deref_mut().inner).poll_read(cx, buf);
+        self.transcript
+            .reads
+            .extend_from_slice(&buf.filled()[old_len..]);
+        poll
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> AsyncWrite for Tee<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, std::io::Error>> {
+        let poll = Pin::new(&mut self.deref_mut().inner).poll_write(cx, buf);
+        if poll.is_ready() {
+            self.get_mut().transcript.writes.extend_from_slice(buf);
+        }
+        poll
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.deref_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.deref_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,621 @@
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use base64::Engine;
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use http_body_util::{BodyExt, Full};
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::ServerConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+
+use super::connection::Connections;
+use super::expr::Scope;
+use super::http::TokioExecutor;
+use super::tls::ClientConfigs;
+use super::{StepInputs, WebSocketFrame, WebSocketOpcode};
+
+/// What a step sees when it's executed on its own: no earlier steps, no TLS sessions to resume
+/// and no open connections.
+#[derive(Default)]
+pub struct TestInputs {
+    previous: Scope<'static>,
+    tls_configs: ClientConfigs,
+    connections: tokio::sync::Mutex<Connections>,
+}
+
+impl TestInputs {
+    pub fn get(&self) -> StepInputs<'_> {
+        StepInputs {
+            previous: &self.previous,
+            tls_configs: &self.tls_configs,
+            connections: &self.connections,
+        }
+    }
+}
+
+/// Build a server config with a fresh self-signed certificate for localhost, offering the given
+/// ALPN protocols.
+fn self_signed_config(alpn: &[&[u8]]) -> (ServerConfig, rcgen::Certificate) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der())),
+            )
+            .unwrap();
+    config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+    (config, cert.cert)
+}
+
+/// Serve HTTPS requests on a local port using a fresh self-signed certificate, returning the
+/// port and the certificate. HTTP/2 is used when the client negotiates it with ALPN.
+pub async fn tls_server() -> (u16, rcgen::Certificate) {
+    tls_server_at("127.0.0.1:0").await
+}
+
+/// Like [`tls_server`], listening on the given local address.
+pub async fn tls_server_at(address: &str) -> (u16, rcgen::Certificate) {
+    let (config, cert) = self_signed_config(&[b"h2", b"http/1.1"]);
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind(address).await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let Ok(stream) = acceptor.accept(stream).await else {
+                continue;
+            };
+            let service = service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("secret"))))
+            });
+            if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                hyper::server::conn::http2::Builder::new(TokioExecutor)
+                    .serve_connection(stream, service)
+                    .await
+                    .unwrap();
+            } else {
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(stream, service)
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+    (port, cert)
+}
+
+/// Serve plain HTTP/2 requests on a local port for clients with prior knowledge, responding with
+/// the request path.
+pub async fn h2_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(
+                hyper::server::conn::http2::Builder::new(TokioExecutor).serve_connection(
+                    stream,
+                    service_fn(|req: Request<hyper::body::Incoming>| async move {
+                        let body = Bytes::copy_from_slice(req.uri().path().as_bytes());
+                        Ok::<_, Infallible>(Response::new(Full::new(body)))
+                    }),
+                ),
+            );
+        }
+    });
+    port
+}
+
+/// Serve plain HTTP requests on a local port, responding with "token123" to /login and
+/// echoing the authorization header otherwise.
+pub async fn http_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(
+                stream,
+                service_fn(|req: Request<hyper::body::Incoming>| async move {
+                    let body = if req.uri().path() == "/login" {
+                        Bytes::from("token123")
+                    } else {
+                        req.headers()
+                            .get(hyper::header::AUTHORIZATION)
+                            .map(|v| Bytes::copy_from_slice(v.as_bytes()))
+                            .unwrap_or_default()
+                    };
+                    Ok::<_, Infallible>(Response::new(Full::new(body)))
+                }),
+            ));
+        }
+    });
+    port
+}
+
+/// Serve plain HTTP requests on a local port, setting a cookie for requests to paths ending in
+/// `/login`, clearing it for `/logout`, and otherwise responding with the request's `Cookie`
+/// header.
+pub async fn cookie_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(
+                stream,
+                service_fn(|req: Request<hyper::body::Incoming>| async move {
+                    let set_cookie = match req.uri().path() {
+                        "/login" => Some("session=abc; Path=/"),
+                        "/admin/login" => Some("role=admin"),
+                        "/logout" => Some("session=; Path=/; Max-Age=0"),
+                        _ => None,
+                    };
+                    let body = req
+                        .headers()
+                        .get(hyper::header::COOKIE)
+                        .map(|v| Bytes::copy_from_slice(v.as_bytes()))
+                        .unwrap_or_default();
+                    let mut res = Response::new(Full::new(body));
+                    if let Some(set_cookie) = set_cookie {
+                        res.headers_mut()
+                            .insert(hyper::header::SET_COOKIE, set_cookie.parse().unwrap());
+                    }
+                    Ok::<_, Infallible>(res)
+                }),
+            ));
+        }
+    });
+    port
+}
+
+/// Serve plain HTTP requests on a local port, responding with the request's body and
+/// `Content-Type`.
+pub async fn echo_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            hyper::server::conn::http1::Builder::new()
+                .serve_connection(
+                    stream,
+                    service_fn(|req: Request<hyper::body::Incoming>| async move {
+                        let content_type = req.headers().get(hyper::header::CONTENT_TYPE).cloned();
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        let mut res = Response::new(Full::new(body));
+                        if let Some(content_type) = content_type {
+                            res.headers_mut()
+                                .insert(hyper::header::CONTENT_TYPE, content_type);
+                        }
+                        Ok::<_, Infallible>(res)
+                    }),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    port
+}
+
+/// Serve plain HTTP requests on a local port, responding with "token123" to /login and acting
+/// as a GraphQL endpoint otherwise. Queries containing "fail" get an error, and any other request
+/// gets its method and operation echoed back as the result's data.
+pub async fn graphql_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            hyper::server::conn::http1::Builder::new()
+                .serve_connection(
+                    stream,
+                    service_fn(|req: Request<hyper::body::Incoming>| async move {
+                        if req.uri().path() == "/login" {
+                            return Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(
+                                "token123",
+                            ))));
+                        }
+                        let method = req.method().to_string();
+                        let result = if method == "GET" {
+                            let query = req.uri().query().unwrap_or_default().to_owned();
+                            serde_json::json!({ "data": { "method": method, "query": query } })
+                        } else {
+                            let body = req.into_body().collect().await.unwrap().to_bytes();
+                            let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                            if request["query"].as_str().unwrap_or_default().contains("fail") {
+                                serde_json::json!({
+                                    "data": null,
+                                    "errors": [{
+                                        "message": "failed",
+                                        "locations": [{ "line": 1, "column": 3 }],
+                                        "path": ["fail", 0],
+                                        "extensions": { "code": "FAILED" },
+                                    }],
+                                })
+                            } else {
+                                serde_json::json!({ "data": { "method": method, "request": request } })
+                            }
+                        };
+                        Ok(Response::new(Full::new(Bytes::from(result.to_string()))))
+                    }),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    port
+}
+
+/// Start a QUIC endpoint on a local port using a fresh self-signed certificate.
+fn quic_endpoint(alpn: &[&[u8]]) -> (quinn::Endpoint, rcgen::Certificate) {
+    let (config, cert) = self_signed_config(alpn);
+    let config = quinn::crypto::rustls::QuicServerConfig::try_from(config).unwrap();
+    let endpoint = quinn::Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(config)),
+        (Ipv4Addr::LOCALHOST, 0).into(),
+    )
+    .unwrap();
+    (endpoint, cert)
+}
+
+/// Serve QUIC connections offering the "echo" ALPN protocol on a local port, returning the port
+/// and the certificate. Each bidirectional stream is read to the end and echoed back, except
+/// streams starting with "wait", which are held open without a reply. Streams starting with
+/// "push" are answered after the server sends "pushed" on a unidirectional stream of its own.
+/// Unidirectional streams are read and ignored.
+pub async fn quic_echo_server() -> (u16, rcgen::Certificate) {
+    let (endpoint, cert) = quic_endpoint(&[b"echo"]);
+    let port = endpoint.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let Ok(conn) = incoming.await else {
+                continue;
+            };
+            let uni = conn.clone();
+            tokio::spawn(async move {
+                while let Ok(mut recv) = uni.accept_uni().await {
+                    let _ = recv.read_to_end(usize::MAX).await;
+                }
+            });
+            tokio::spawn(async move {
+                while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+                    let conn = conn.clone();
+                    tokio::spawn(async move {
+                        let data = recv.read_to_end(usize::MAX).await.unwrap();
+                        if data.starts_with(b"push") {
+                            let mut push = conn.open_uni().await.unwrap();
+                            push.write_all(b"pushed").await.unwrap();
+                            push.finish().unwrap();
+                            // Answer once the client has read the pushed stream.
+                            let _ = push.stopped().await;
+                        }
+                        if data.starts_with(b"wait") {
+                            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                        }
+                        send.write_all(&data).await.unwrap();
+                        send.finish().unwrap();
+                        // Keep the stream until the client has read everything.
+                        let _ = send.stopped().await;
+                    });
+                }
+            });
+        }
+    });
+    (port, cert)
+}
+
+/// Serve HTTP/3 requests on a local port using a fresh self-signed certificate, returning the
+/// port and the certificate. Responses echo the request body and put the path in an `x-path`
+/// header.
+pub async fn h3_server() -> (u16, rcgen::Certificate) {
+    let (endpoint, cert) = quic_endpoint(&[b"h3"]);
+    let port = endpoint.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let Ok(conn) = incoming.await else {
+                continue;
+            };
+            tokio::spawn(async move {
+                let mut conn =
+                    h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn))
+                        .await
+                        .unwrap();
+                // Accepting fails once the client closes the connection.
+                while let Ok(Some(resolver)) = conn.accept().await {
+                    let (req, mut stream) = resolver.resolve_request().await.unwrap();
+                    let mut body = Vec::new();
+                    while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+                        use bytes::Buf;
+                        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+                    }
+                    let res = http1::Response::builder()
+                        .header("x-path", req.uri().path())
+                        .body(())
+                        .unwrap();
+                    stream.send_response(res).await.unwrap();
+                    stream.send_data(Bytes::from(body)).await.unwrap();
+                    stream.finish().await.unwrap();
+                }
+            });
+        }
+    });
+    (port, cert)
+}
+
+/// Accept a single connection on a local port, read a request head, then write `response` and
+/// close the connection. The handle resolves to the bytes that were read.
+pub async fn raw_server(response: impl AsRef<[u8]> + Send + 'static) -> (u16, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(response.as_ref()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        received
+    });
+    (port, handle)
+}
+
+/// Accept a single connection on a local port and switch it to HTTP/2 after the first request,
+/// answering that request on stream 1 with a 200 and the body "upgraded". The handle resolves to
+/// every byte the client sent.
+pub async fn h2c_upgrade_server() -> (u16, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !received.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        let mut response =
+            b"HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: h2c\r\n\r\n"
+                .to_vec();
+        // SETTINGS, then HEADERS with `:status: 200` from the HPACK static table, then DATA
+        // ending the stream.
+        response.extend_from_slice(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]);
+        response.extend_from_slice(&[0, 0, 1, 0x1, 0x4, 0, 0, 0, 1, 0x88]);
+        response.extend_from_slice(&[0, 0, 8, 0x0, 0x1, 0, 0, 0, 1]);
+        response.extend_from_slice(b"upgraded");
+        stream.write_all(&response).await.unwrap();
+        stream.read_to_end(&mut received).await.unwrap();
+        received
+    });
+    (port, handle)
+}
+
+/// Accept a single connection on a local port, read one line, then write `response`. The
+/// connection is closed after the response if `close` is set, otherwise the server waits for the
+/// client to close it. The handle resolves to the bytes that were read.
+pub async fn line_server(response: &'static [u8], close: bool) -> (u16, JoinHandle<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while !received.ends_with(b"\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(response).await.unwrap();
+        if close {
+            stream.shutdown().await.unwrap();
+        } else {
+            stream.read_to_end(&mut received).await.unwrap();
+        }
+        received
+    });
+    (port, handle)
+}
+
+/// Echo every datagram back to its sender, returning the server's address.
+pub async fn udp_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 65535];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            socket.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// Accept WebSocket upgrades on a local port, echoing text and binary frames, answering pings,
+/// and echoing a close frame before closing the connection.
+pub async fn websocket_echo_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let head = String::from_utf8(head).unwrap();
+                let key = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("sec-websocket-key: "))
+                    .unwrap();
+                let digest = ring::digest::digest(
+                    &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+                    format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key).as_bytes(),
+                );
+                let accept = base64::engine::general_purpose::STANDARD.encode(digest);
+                let response = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nconnection: Upgrade\r\nupgrade: websocket\r\nsec-websocket-accept: {}\r\n\r\n",
+                    accept
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+
+                while let Some(frame) = WebSocketFrame::read(&mut stream).await.unwrap() {
+                    let opcode = match frame.opcode {
+                        WebSocketOpcode::Ping => WebSocketOpcode::Pong,
+                        WebSocketOpcode::Pong => continue,
+                        opcode => opcode,
+                    };
+                    let reply = WebSocketFrame {
+                        fin: true,
+                        opcode,
+                        mask: None,
+                        payload: frame.payload,
+                    };
+                    stream.write_all(&reply.encode()).await.unwrap();
+                    if opcode == WebSocketOpcode::Close {
+                        stream.shutdown().await.unwrap();
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    port
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HelloRequest {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(int32, tag = "2")]
+    times: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HelloReply {
+    #[prost(string, tag = "1")]
+    message: String,
+}
+
+fn hello(name: &str) -> HelloReply {
+    HelloReply {
+        message: format!("Hello {}", name),
+    }
+}
+
+/// The `test.Greeter` service from `testdata/greeter.proto`, wired up by hand since the tests
+/// don't run a protobuf compiler.
+#[derive(Clone)]
+struct Greeter;
+
+impl tonic::server::NamedService for Greeter {
+    const NAME: &'static str = "test.Greeter";
+}
+
+impl tower::Service<hyper::http::Request<tonic::transport::Body>> for Greeter {
+    type Response = hyper::http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // tonic's handlers return its large `Status` as their error.
+    #[allow(clippy::result_large_err)]
+    fn call(&mut self, req: hyper::http::Request<tonic::transport::Body>) -> Self::Future {
+        Box::pin(async move {
+            let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::<
+                HelloReply,
+                HelloRequest,
+            >::default());
+            let res = match req.uri().path() {
+                "/test.Greeter/SayHello" => {
+                    let service =
+                        tower::service_fn(|req: tonic::Request<HelloRequest>| async move {
+                            let name = req.into_inner().name;
+                            if name == "error" {
+                                return Err(tonic::Status::invalid_argument(format!(
+                                    "bad name: \"{}\"",
+                                    name
+                                )));
+                            }
+                            Ok(tonic::Response::new(hello(&name)))
+                        });
+                    grpc.unary(service, req).await
+                }
+                "/test.Greeter/SayHelloToAll" => {
+                    let service = tower::service_fn(
+                        |req: tonic::Request<tonic::Streaming<HelloRequest>>| async move {
+                            let mut names = Vec::new();
+                            let mut stream = req.into_inner();
+                            while let Some(req) = stream.message().await? {
+                                names.push(req.name);
+                            }
+                            Ok(tonic::Response::new(hello(&names.join(", "))))
+                        },
+                    );
+                    grpc.client_streaming(service, req).await
+                }
+                "/test.Greeter/SayHelloRepeatedly" => {
+                    let service =
+                        tower::service_fn(|req: tonic::Request<HelloRequest>| async move {
+                            let req = req.into_inner();
+                            let replies = (1..=req.times)
+                                .map(|i| Ok(hello(&format!("{} #{}", req.name, i))))
+                                .collect::<Vec<_>>();
+                            Ok(tonic::Response::new(futures::stream::iter(replies)))
+                        });
+                    grpc.server_streaming(service, req).await
+                }
+                "/test.Greeter/Chat" => {
+                    let service = tower::service_fn(
+                        |req: tonic::Request<tonic::Streaming<HelloRequest>>| async move {
+                            let replies =
+                                req.into_inner().map(|req| req.map(|req| hello(&req.name)));
+                            Ok(tonic::Response::new(replies))
+                        },
+                    );
+                    grpc.streaming(service, req).await
+                }
+                _ => tonic::Status::unimplemented("no such method").to_http(),
+            };
+            Ok(res)
+        })
+    }
+}
+
+/// Serve the `test.Greeter` gRPC service on a local port.
+pub async fn grpc_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(Greeter)
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+    );
+    port
+}
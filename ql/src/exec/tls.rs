@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, HandshakeKind, ProtocolVersion, RootCertStore,
+    SignatureScheme,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::{TLSOptions, TLSVerify};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TLSOutput {
+    pub version: TLSVersion,
+    /// The IANA name of the negotiated cipher suite, like `TLS13_AES_128_GCM_SHA256`, or its
+    /// hex code if it has no name.
+    pub cipher_suite: String,
+    pub alpn: Option<Vec<u8>>,
+    /// The certificate chain presented by the server, starting with the end-entity certificate.
+    pub server_certificates: Vec<Certificate>,
+    pub sni: Option<String>,
+    pub resumed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub der: Vec<u8>,
+}
+
+impl Certificate {
+    pub fn pem(&self) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&self.der);
+        let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+        pem
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TLSVersion {
+    SSL2,
+    SSL3,
+    TLS1_0,
+    TLS1_1,
+    TLS1_2,
+    TLS1_3,
+    Unrecognized,
+}
+
+impl Display for TLSVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SSL2 => f.write_str("SSLv2"),
+            Self::SSL3 => f.write_str("SSLv3"),
+            Self::TLS1_0 => f.write_str("TLSv1.0"),
+            Self::TLS1_1 => f.write_str("TLSv1.1"),
+            Self::TLS1_2 => f.write_str("TLSv1.2"),
+            Self::TLS1_3 => f.write_str("TLSv1.3"),
+            Self::Unrecognized => f.write_str("unrecognized protocol"),
+        }
+    }
+}
+
+impl From<ProtocolVersion> for TLSVersion {
+    fn from(value: ProtocolVersion) -> Self {
+        match value {
+            ProtocolVersion::SSLv2 => Self::SSL2,
+            ProtocolVersion::SSLv3 => Self::SSL3,
+            ProtocolVersion::TLSv1_0 => Self::TLS1_0,
+            ProtocolVersion::TLSv1_1 => Self::TLS1_1,
+            ProtocolVersion::TLSv1_2 => Self::TLS1_2,
+            ProtocolVersion::TLSv1_3 => Self::TLS1_3,
+            _ => Self::Unrecognized,
+        }
+    }
+}
+
+/// Client configs built so far, keyed by how they verify the server. Reusing a config lets later
+/// steps resume sessions from earlier handshakes with the same server.
+#[derive(Default)]
+pub(super) struct ClientConfigs(Mutex<HashMap<ConfigKey, Arc<ClientConfig>>>);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConfigKey {
+    System,
+    CA(String),
+    Insecure,
+}
+
+impl ClientConfigs {
+    fn get(
+        &self,
+        options: &TLSOptions<'_>,
+    ) -> Result<Arc<ClientConfig>, Box<dyn std::error::Error + Send + Sync>> {
+        let key = match options.verify {
+            TLSVerify::System => ConfigKey::System,
+            TLSVerify::CA(path) => ConfigKey::CA(path.to_owned()),
+            TLSVerify::Insecure => ConfigKey::Insecure,
+        };
+        let mut configs = self.0.lock().unwrap();
+        if let Some(config) = configs.get(&key) {
+            return Ok(config.clone());
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let config = match &key {
+            ConfigKey::System => {
+                let mut roots = RootCertStore::empty();
+                let native = rustls_native_certs::load_native_certs();
+                if native.certs.is_empty() {
+                    if let Some(e) = native.errors.into_iter().next() {
+                        return Err(Box::new(e));
+                    }
+                }
+                roots.add_parsable_certificates(native.certs);
+                builder.with_root_certificates(roots)
+            }
+            ConfigKey::CA(path) => {
+                let mut roots = RootCertStore::empty();
+                let pem = std::fs::read(path)?;
+                for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                    roots.add(cert?)?;
+                }
+                builder.with_root_certificates(roots)
+            }
+            ConfigKey::Insecure => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier(provider))),
+        }
+        .with_no_client_auth();
+
+        let config = Arc::new(config);
+        configs.insert(key, config.clone());
+        Ok(config)
+    }
+}
+
+/// Get a client config for the options that offers the given ALPN protocols. The config shares
+/// its verifier and session store with the cached one, so resumption still works across steps
+/// offering different protocols.
+pub(super) fn client_config(
+    options: &TLSOptions<'_>,
+    alpn: &[&[u8]],
+    configs: &ClientConfigs,
+) -> Result<ClientConfig, Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = ClientConfig::clone(&*configs.get(options)?);
+    config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+    Ok(config)
+}
+
+/// Perform a TLS handshake over an established stream, sending the host as the SNI when it's a
+/// DNS name and offering the given ALPN protocols.
+pub(super) async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    stream: T,
+    host: &str,
+    alpn: &[&[u8]],
+    options: &TLSOptions<'_>,
+    configs: &ClientConfigs,
+) -> Result<(TlsStream<T>, TLSOutput), Box<dyn std::error::Error + Send + Sync>> {
+    let config = client_config(options, alpn, configs)?;
+    // IPv6 hosts in URIs are wrapped in brackets.
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let server_name = ServerName::try_from(host.to_owned())?;
+    let sni = match &server_name {
+        ServerName::DnsName(name) => Some(name.as_ref().to_owned()),
+        _ => None,
+    };
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await?;
+
+    let (_, conn) = stream.get_ref();
+    let output = TLSOutput {
+        version: conn
+            .protocol_version()
+            .map(TLSVersion::from)
+            .unwrap_or(TLSVersion::Unrecognized),
+        cipher_suite: conn
+            .negotiated_cipher_suite()
+            .map(|suite| match suite.suite().as_str() {
+                Some(name) => name.to_owned(),
+                None => format!("{:#06x}", u16::from(suite.suite())),
+            })
+            .unwrap_or_default(),
+        alpn: conn.alpn_protocol().map(<[u8]>::to_vec),
+        server_certificates: conn
+            .peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .map(|cert| Certificate {
+                der: cert.as_ref().to_vec(),
+            })
+            .collect(),
+        sni,
+        resumed: conn.handshake_kind() == Some(HandshakeKind::Resumed),
+    };
+    Ok((stream, output))
+}
+
+/// Accepts any certificate the server presents, while still checking that the handshake
+/// signatures are valid for that certificate.
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
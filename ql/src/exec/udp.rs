@@ -0,0 +1,201 @@
+use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use tokio::net::{lookup_host, UdpSocket};
+
+use super::{expr, StepInputs, StepOutput, StepParsedOutput};
+use crate::UDPRequest;
+
+/// Every datagram sent and received by a step, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UDPOutput {
+    pub datagrams: Vec<Datagram>,
+    /// Why waiting for replies stopped.
+    pub end: UDPReadEnd,
+}
+
+impl UDPOutput {
+    /// The datagrams received from any peer.
+    pub fn received(&self) -> impl Iterator<Item = &Datagram> {
+        self.datagrams
+            .iter()
+            .filter(|d| d.direction == Direction::Received)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    pub direction: Direction,
+    /// The address the datagram was sent to or received from.
+    pub peer: SocketAddr,
+    pub time: SystemTime,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// How long to wait for replies when a step has no `timeout` option, since a lost datagram would
+/// otherwise leave the step waiting forever.
+pub const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UDPReadEnd {
+    Count,
+    Timeout,
+}
+
+impl Display for UDPReadEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Count => f.write_str("datagram count"),
+            Self::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+pub(super) async fn execute(
+    step: &UDPRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let address = String::from_utf8(expr::render(&step.address, inputs.previous)?)?;
+    let peer = lookup_host(&address)
+        .await?
+        .next()
+        .ok_or_else(|| format!("no addresses found for {}", address))?;
+    let payloads = step
+        .datagrams
+        .iter()
+        .map(|datagram| expr::render_escaped(datagram, inputs.previous))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The socket isn't connected so replies from any peer are recorded.
+    let local: SocketAddr = if peer.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    let mut datagrams = Vec::new();
+    for payload in payloads {
+        socket.send_to(&payload, peer).await?;
+        datagrams.push(Datagram {
+            direction: Direction::Sent,
+            peer,
+            time: SystemTime::now(),
+            payload,
+        });
+    }
+
+    let count = step.options.count.unwrap_or(datagrams.len());
+    let receive = async {
+        // The largest possible UDP payload.
+        let mut buf = vec![0; 65535];
+        for _ in 0..count {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            datagrams.push(Datagram {
+                direction: Direction::Received,
+                peer: from,
+                time: SystemTime::now(),
+                payload: buf[..n].to_vec(),
+            });
+        }
+        Ok::<_, std::io::Error>(UDPReadEnd::Count)
+    };
+    let timeout = step.options.timeout.unwrap_or(DEFAULT_UDP_TIMEOUT);
+    let end = tokio::time::timeout(timeout, receive)
+        .await
+        .unwrap_or(Ok(UDPReadEnd::Timeout))?;
+
+    let concat = |direction| {
+        datagrams
+            .iter()
+            .filter(|d| d.direction == direction)
+            .flat_map(|d| d.payload.iter().copied())
+            .collect()
+    };
+    Ok(StepOutput {
+        raw_request: concat(Direction::Sent),
+        raw_response: concat(Direction::Received),
+        tls: None,
+        http2: None,
+        quic: None,
+        connection: None,
+        duration: Duration::ZERO,
+        parsed: StepParsedOutput::UDP(UDPOutput { datagrams, end }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::exec::testutil::{udp_echo_server, TestInputs};
+    use crate::{Template, UDPOptions};
+
+    async fn run(address: &str, datagrams: &[&'static str], options: UDPOptions) -> StepOutput {
+        execute(
+            &UDPRequest {
+                address: Template::literal(address),
+                datagrams: datagrams.iter().map(|d| Template::literal(d)).collect(),
+                options,
+            },
+            &TestInputs::default().get(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn udp_test() {
+        let server = udp_echo_server().await;
+        let address = server.to_string();
+        let out = run(&address, &["ping\\n", "\\x00\\xff"], UDPOptions::default()).await;
+        assert_eq!(out.raw_request, b"ping\n\x00\xff");
+        assert_eq!(out.raw_response, b"ping\n\x00\xff");
+        let StepParsedOutput::UDP(parsed) = out.parsed else {
+            panic!("expected UDP datagrams");
+        };
+        assert_eq!(parsed.end, UDPReadEnd::Count);
+        let summary: Vec<_> = parsed
+            .datagrams
+            .iter()
+            .map(|d| (d.direction, d.peer, d.payload.as_slice()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Direction::Sent, server, b"ping\n".as_slice()),
+                (Direction::Sent, server, b"\x00\xff".as_slice()),
+                (Direction::Received, server, b"ping\n".as_slice()),
+                (Direction::Received, server, b"\x00\xff".as_slice()),
+            ]
+        );
+        assert!(parsed.datagrams.windows(2).all(|d| d[0].time <= d[1].time));
+    }
+
+    #[tokio::test]
+    async fn udp_timeout_test() {
+        // Only one reply comes back, so waiting for a second one stops at the timeout.
+        let server = udp_echo_server().await;
+        let out = run(
+            &server.to_string(),
+            &["ping"],
+            UDPOptions {
+                count: Some(2),
+                timeout: Some(Duration::from_millis(100)),
+            },
+        )
+        .await;
+        let StepParsedOutput::UDP(parsed) = out.parsed else {
+            panic!("expected UDP datagrams");
+        };
+        assert_eq!(parsed.end, UDPReadEnd::Timeout);
+        assert_eq!(parsed.received().count(), 1);
+    }
+}
@@ -0,0 +1,521 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{HeaderName, CONNECTION, COOKIE, HOST, UPGRADE, USER_AGENT};
+use hyper::{HeaderMap, Request, StatusCode, Uri, Version};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::http::build_request;
+use super::tee::{Tee, Transcript};
+use super::{cookie, expr, tls, HTTPOutput, StepInputs, StepOutput, StepParsedOutput, TLSOutput};
+use crate::{TLSOptions, WebSocketMessage, WebSocketRequest};
+
+/// The largest frame that's read, so a frame header can't make the executor allocate without
+/// bound.
+const MAX_FRAME_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The largest message, across all of its frames, that's read.
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The response to the upgrade request, and every frame sent and received after the connection
+/// switched protocols.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebSocketOutput {
+    pub upgrade: HTTPOutput,
+    pub sent: Vec<WebSocketFrame>,
+    pub received: Vec<WebSocketFrame>,
+    /// Why reading frames stopped, or `None` if the server didn't switch protocols.
+    pub end: Option<WebSocketReadEnd>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketFrame {
+    pub fin: bool,
+    pub opcode: WebSocketOpcode,
+    /// The key the payload was masked with on the wire. Clients mask every frame they send.
+    pub mask: Option<[u8; 4]>,
+    /// The unmasked payload.
+    pub payload: Vec<u8>,
+}
+
+impl WebSocketFrame {
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 14);
+        out.push(u8::from(self.fin) << 7 | u8::from(self.opcode));
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match self.mask {
+            Some(mask) => {
+                out.extend_from_slice(&mask);
+                out.extend(apply_mask(&self.payload, mask));
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+
+    /// Read the next frame, or returns `None` if the connection closed between frames. Frames
+    /// longer than [`MAX_FRAME_SIZE`] are rejected before their payload is read.
+    pub(super) async fn read<T: AsyncRead + Unpin>(stream: &mut T) -> io::Result<Option<Self>> {
+        let mut head = [0; 2];
+        if stream.read(&mut head[..1]).await? == 0 {
+            return Ok(None);
+        }
+        stream.read_exact(&mut head[1..]).await?;
+        let len = match head[1] & 0x7f {
+            126 => stream.read_u16().await?.into(),
+            127 => stream.read_u64().await?,
+            len => len.into(),
+        };
+        let mask = if head[1] & 0x80 != 0 {
+            let mut mask = [0; 4];
+            stream.read_exact(&mut mask).await?;
+            Some(mask)
+        } else {
+            None
+        };
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "frame of {} bytes is larger than {} bytes",
+                    len, MAX_FRAME_SIZE
+                ),
+            ));
+        }
+        // The payload grows as it arrives rather than trusting the length up front.
+        let mut payload = Vec::new();
+        (&mut *stream).take(len).read_to_end(&mut payload).await?;
+        if payload.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(mask) = mask {
+            payload = apply_mask(&payload, mask);
+        }
+        Ok(Some(WebSocketFrame {
+            fin: head[0] & 0x80 != 0,
+            opcode: (head[0] & 0x0f).into(),
+            mask,
+            payload,
+        }))
+    }
+}
+
+fn apply_mask(payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    payload
+        .iter()
+        .zip(mask.iter().cycle())
+        .map(|(b, m)| b ^ m)
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketOpcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Display for WebSocketOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Continuation => f.write_str("continuation"),
+            Self::Text => f.write_str("text"),
+            Self::Binary => f.write_str("binary"),
+            Self::Close => f.write_str("close"),
+            Self::Ping => f.write_str("ping"),
+            Self::Pong => f.write_str("pong"),
+            Self::Reserved(opcode) => write!(f, "reserved({:#03x})", opcode),
+        }
+    }
+}
+
+impl From<u8> for WebSocketOpcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0x0 => Self::Continuation,
+            0x1 => Self::Text,
+            0x2 => Self::Binary,
+            0x8 => Self::Close,
+            0x9 => Self::Ping,
+            0xa => Self::Pong,
+            _ => Self::Reserved(value),
+        }
+    }
+}
+
+impl From<WebSocketOpcode> for u8 {
+    fn from(value: WebSocketOpcode) -> Self {
+        match value {
+            WebSocketOpcode::Continuation => 0x0,
+            WebSocketOpcode::Text => 0x1,
+            WebSocketOpcode::Binary => 0x2,
+            WebSocketOpcode::Close => 0x8,
+            WebSocketOpcode::Ping => 0x9,
+            WebSocketOpcode::Pong => 0xa,
+            WebSocketOpcode::Reserved(opcode) => opcode,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSocketReadEnd {
+    /// The server sent a close frame.
+    Close,
+    /// The connection closed without a close frame.
+    Closed,
+    Timeout,
+}
+
+impl Display for WebSocketReadEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Close => f.write_str("close frame"),
+            Self::Closed => f.write_str("connection closed"),
+            Self::Timeout => f.write_str("timeout"),
+        }
+    }
+}
+
+pub(super) async fn execute(
+    step: &WebSocketRequest<'_>,
+    inputs: &StepInputs<'_>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let upgrade = &step.upgrade;
+    let endpoint: Uri =
+        String::from_utf8(expr::render(&upgrade.endpoint, inputs.previous)?)?.parse()?;
+    let tls = upgrade.tls.clone().or_else(|| {
+        matches!(endpoint.scheme_str(), Some("wss" | "https")).then(TLSOptions::default)
+    });
+    let host = endpoint.host().ok_or("uri has no host")?;
+    let port = endpoint
+        .port_u16()
+        .unwrap_or(if tls.is_some() { 443 } else { 80 });
+    let address = format!("{}:{}", host, port);
+
+    let random = SystemRandom::new();
+    let mut key = [0; 16];
+    random
+        .fill(&mut key)
+        .map_err(|_| "failed to generate a key")?;
+    let key = base64::engine::general_purpose::STANDARD.encode(key);
+    let authority = endpoint.authority().ok_or("request missing host")?.clone();
+    let cookies = cookie::header(inputs, &endpoint, tls.is_some());
+    let mut default_headers = vec![(HOST, authority.as_str()), (USER_AGENT, "courier/0.1.0")];
+    if let Some(cookies) = &cookies {
+        default_headers.push((COOKIE, cookies));
+    }
+    default_headers.extend([
+        (CONNECTION, "Upgrade"),
+        (UPGRADE, "websocket"),
+        (HeaderName::from_static("sec-websocket-version"), "13"),
+        (HeaderName::from_static("sec-websocket-key"), key.as_str()),
+    ]);
+    // The ws and wss schemes aren't understood by HTTP servers, so the request line only has the
+    // path.
+    let target: Uri = endpoint
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .parse()?;
+    let req =
+        build_request(upgrade, target, Version::HTTP_11, default_headers, inputs)?.into_hyper()?;
+
+    let mut frames = Vec::with_capacity(step.messages.len());
+    for message in &step.messages {
+        let (opcode, payload) = match message {
+            WebSocketMessage::Text(payload) => (WebSocketOpcode::Text, payload),
+            WebSocketMessage::Binary(payload) => (WebSocketOpcode::Binary, payload),
+            WebSocketMessage::Ping(payload) => (WebSocketOpcode::Ping, payload),
+            WebSocketMessage::Pong(payload) => (WebSocketOpcode::Pong, payload),
+            WebSocketMessage::Close(_, reason) => (WebSocketOpcode::Close, reason),
+        };
+        let mut body = expr::render_escaped(payload, inputs.previous)?;
+        if let WebSocketMessage::Close(Some(code), _) = message {
+            body.splice(0..0, code.to_be_bytes());
+        }
+        let mut mask = [0; 4];
+        random
+            .fill(&mut mask)
+            .map_err(|_| "failed to generate a mask")?;
+        frames.push(WebSocketFrame {
+            fin: true,
+            opcode,
+            mask: Some(mask),
+            payload: body,
+        });
+    }
+
+    let stream = TcpStream::connect(address).await?;
+    let out = match &tls {
+        Some(options) => {
+            let (stream, tls) =
+                tls::handshake(stream, host, &[b"http/1.1"], options, inputs.tls_configs).await?;
+            send(stream, req, frames, step.options.timeout, Some(tls)).await?
+        }
+        None => send(stream, req, frames, step.options.timeout, None).await?,
+    };
+    cookie::store(inputs, &endpoint, tls.is_some(), &out);
+    Ok(out)
+}
+
+async fn send<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: T,
+    req: Request<Full<Bytes>>,
+    frames: Vec<WebSocketFrame>,
+    timeout: Option<Duration>,
+    tls: Option<TLSOutput>,
+) -> Result<StepOutput, Box<dyn std::error::Error + Send + Sync>> {
+    let (stream, transcript) = Tee::new(stream);
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(stream).await?;
+    // The connection finishes once it hands the stream over to the upgrade, or once the sender is
+    // dropped if the server doesn't switch protocols.
+    let conn = tokio::spawn(conn);
+
+    let mut res = sender.send_request(req).await?;
+    let mut received = Vec::new();
+    let (head, body, sent, end) = if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+        let upgraded = hyper::upgrade::on(&mut res).await?;
+        conn.await??;
+        let end = exchange(upgraded, &frames, &mut received, timeout).await?;
+        (res.into_parts().0, Vec::new(), frames, Some(end))
+    } else {
+        let (head, body) = res.into_parts();
+        let body = body.collect().await?.to_bytes().to_vec();
+        drop(sender);
+        conn.await??;
+        (head, body, Vec::new(), None)
+    };
+    // Wait for the connection to drop the stream so the transcript is complete.
+    let Transcript { reads, writes } = transcript.await?;
+
+    Ok(StepOutput {
+        raw_request: writes,
+        raw_response: reads,
+        tls,
+        http2: None,
+        quic: None,
+        connection: None,
+        duration: Duration::ZERO,
+        parsed: StepParsedOutput::WebSocket(WebSocketOutput {
+            upgrade: HTTPOutput {
+                version: head.version.into(),
+                status: head.status,
+                headers: head.headers,
+                body,
+                raw_body: None,
+                decode_error: None,
+                parsed_body: None,
+                chunks: Vec::new(),
+                trailers: HeaderMap::new(),
+            },
+            sent,
+            received,
+            end,
+        }),
+    })
+}
+
+/// Send every frame while reading the server's frames until it sends a close frame, closes the
+/// connection or the timeout runs out.
+async fn exchange<T: AsyncRead + AsyncWrite>(
+    stream: T,
+    frames: &[WebSocketFrame],
+    received: &mut Vec<WebSocketFrame>,
+    timeout: Option<Duration>,
+) -> io::Result<WebSocketReadEnd> {
+    let (mut reader, mut writer) = io::split(stream);
+    let write = async {
+        for frame in frames {
+            writer.write_all(&frame.encode()).await?;
+        }
+        writer.flush().await
+    };
+    let read = async {
+        // The size of the data message being received, which may span several frames.
+        let mut message = 0;
+        while let Some(frame) = WebSocketFrame::read(&mut reader).await? {
+            if matches!(
+                frame.opcode,
+                WebSocketOpcode::Text | WebSocketOpcode::Binary | WebSocketOpcode::Continuation
+            ) {
+                message += frame.payload.len() as u64;
+                if message > MAX_MESSAGE_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("message is larger than {} bytes", MAX_MESSAGE_SIZE),
+                    ));
+                }
+                if frame.fin {
+                    message = 0;
+                }
+            }
+            let close = frame.opcode == WebSocketOpcode::Close;
+            received.push(frame);
+            if close {
+                return Ok(WebSocketReadEnd::Close);
+            }
+        }
+        Ok(WebSocketReadEnd::Closed)
+    };
+    let read = async {
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read)
+                .await
+                .unwrap_or(Ok(WebSocketReadEnd::Timeout)),
+            None => read.await,
+        }
+    };
+    let (_, end) = futures::try_join!(write, read)?;
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::exec::testutil::{raw_server, websocket_echo_server, TestInputs};
+    use crate::{Plan, Step, StepBody};
+
+    async fn run(plan: &str) -> (StepOutput, WebSocketOutput) {
+        let (_, Step { body, .. }) = Step::parse(plan).unwrap();
+        let StepBody::WebSocket(req) = body else {
+            panic!("expected a websocket step");
+        };
+        let out = execute(&req, &TestInputs::default().get()).await.unwrap();
+        let StepParsedOutput::WebSocket(parsed) = out.parsed.clone() else {
+            panic!("expected a websocket exchange");
+        };
+        (out, parsed)
+    }
+
+    #[tokio::test]
+    async fn websocket_test() {
+        let port = websocket_echo_server().await;
+        let (out, parsed) = run(&format!(
+            "websocket EOF\nGET ws://127.0.0.1:{}/chat\n\ntext hello\nbinary \\x00\\xff\nping\nclose 1000 bye\nEOF",
+            port
+        ))
+        .await;
+
+        assert!(out.raw_request.starts_with(b"GET /chat HTTP/1.1\r\n"));
+        assert!(out
+            .raw_response
+            .starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert_eq!(parsed.upgrade.status, StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(parsed.end, Some(WebSocketReadEnd::Close));
+
+        assert!(parsed
+            .sent
+            .iter()
+            .all(|frame| frame.fin && frame.mask.is_some()));
+        let opcodes: Vec<_> = parsed.sent.iter().map(|frame| frame.opcode).collect();
+        assert_eq!(
+            opcodes,
+            vec![
+                WebSocketOpcode::Text,
+                WebSocketOpcode::Binary,
+                WebSocketOpcode::Ping,
+                WebSocketOpcode::Close,
+            ]
+        );
+        assert_eq!(parsed.sent[3].payload, b"\x03\xe8bye");
+
+        // The server echoes data frames, answers the ping and the close, and never masks.
+        let received: Vec<_> = parsed
+            .received
+            .iter()
+            .map(|frame| (frame.opcode, frame.mask, frame.payload.as_slice()))
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                (WebSocketOpcode::Text, None, b"hello".as_slice()),
+                (WebSocketOpcode::Binary, None, b"\x00\xff".as_slice()),
+                (WebSocketOpcode::Pong, None, b"".as_slice()),
+                (WebSocketOpcode::Close, None, b"\x03\xe8bye".as_slice()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_declined_test() {
+        let (port, _) =
+            raw_server(b"HTTP/1.1 404 Not Found\r\ncontent-length: 4\r\n\r\nnope").await;
+        let (_, parsed) = run(&format!(
+            "websocket EOF\nGET ws://127.0.0.1:{}/\n\ntext hello\nEOF",
+            port
+        ))
+        .await;
+        assert_eq!(parsed.upgrade.status, StatusCode::NOT_FOUND);
+        assert_eq!(parsed.upgrade.body, b"nope");
+        assert_eq!(parsed.sent, Vec::new());
+        assert_eq!(parsed.end, None);
+    }
+
+    #[tokio::test]
+    async fn websocket_timeout_test() {
+        // Without a close frame the echo server keeps the connection open.
+        let port = websocket_echo_server().await;
+        let plan = format!(
+            "websocket(timeout=100ms) EOF\nGET ws://127.0.0.1:{}/\n\ntext hello\nEOF",
+            port
+        );
+        let plan = Plan::parse(&plan).unwrap();
+        let mut executor = crate::exec::Executor::new(&plan);
+        let StepParsedOutput::WebSocket(parsed) = executor.next().await.unwrap().parsed else {
+            panic!("expected a websocket exchange");
+        };
+        assert_eq!(parsed.end, Some(WebSocketReadEnd::Timeout));
+        assert_eq!(parsed.received.len(), 1);
+    }
+
+    #[test]
+    fn frame_test() {
+        let frame = WebSocketFrame {
+            fin: true,
+            opcode: WebSocketOpcode::Text,
+            mask: Some([1, 2, 3, 4]),
+            payload: b"hi".to_vec(),
+        };
+        assert_eq!(frame.encode(), [0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2]);
+
+        let long = WebSocketFrame {
+            fin: false,
+            opcode: WebSocketOpcode::Binary,
+            mask: None,
+            payload: vec![7; 300],
+        };
+        let encoded = long.encode();
+        assert_eq!(encoded[..4], [0x02, 126, 0x01, 0x2c]);
+        let decoded = futures::executor::block_on(WebSocketFrame::read(&mut encoded.as_slice()));
+        assert_eq!(decoded.unwrap(), Some(long));
+        assert_eq!(WebSocketOpcode::from(0x3).to_string(), "reserved(0x3)");
+
+        // Lengths past the limit are rejected without allocating, as are truncated payloads.
+        let mut huge: &[u8] = &[0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        let e = futures::executor::block_on(WebSocketFrame::read(&mut huge)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let mut short: &[u8] = &[0x82, 5, b'a'];
+        let e = futures::executor::block_on(WebSocketFrame::read(&mut short)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,139 @@
+use nom::IResult;
+
+use crate::tls::option_error;
+use crate::util::{block, boolean};
+use crate::{HTTPRequest, Template};
+
+/// A GraphQL operation sent over HTTP. The request line and headers are written like an `http`
+/// step, followed by the query document and an optional `variables` line holding a JSON object.
+#[derive(Debug, PartialEq)]
+pub struct GraphQLRequest<'a> {
+    /// The request line and headers. A `POST` sends the operation as a JSON body, while a `GET`
+    /// sends it in the query string.
+    pub http: HTTPRequest<'a>,
+    pub query: Template<'a>,
+    pub variables: Option<Template<'a>>,
+    pub options: GraphQLOptions<'a>,
+}
+
+impl<'a> GraphQLRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        let (input, http) = HTTPRequest::parse_head(input)?;
+        let (input, body) = block(input, eof)?;
+        let (query, variables) = split_variables(body);
+        Ok((
+            input,
+            GraphQLRequest {
+                http,
+                query: Template::parse(query)?.1,
+                variables: variables
+                    .map(|variables| Ok(Template::parse(variables)?.1))
+                    .transpose()?,
+                options: GraphQLOptions::default(),
+            },
+        ))
+    }
+}
+
+/// Split the query document from the variables, which start at the first line beginning with
+/// `variables `. That can never start a definition in a GraphQL document.
+fn split_variables(body: &str) -> (&str, Option<&str>) {
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        if line.starts_with("variables ") {
+            let variables = &body[offset + "variables ".len()..];
+            return (body[..offset].trim_end(), Some(variables));
+        }
+        offset += line.len();
+    }
+    (body, None)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GraphQLOptions<'a> {
+    /// The operation to run when the document defines more than one.
+    pub operation: Option<&'a str>,
+    /// Send the SHA-256 hash of the query as a persisted query extension instead of the query
+    /// itself.
+    pub persisted: bool,
+}
+
+impl<'a> GraphQLOptions<'a> {
+    pub fn parse(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = Self::default();
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "operation" => result.operation = Some(val),
+                "persisted" => result.persisted = boolean(val).ok_or_else(|| option_error(val))?,
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HTTPBody, HTTPOptions, Payload, Protocol};
+
+    #[test]
+    fn graphql_test() {
+        assert_eq!(
+            GraphQLRequest::parse(
+                "POST http://localhost/graphql\nAuthorization: token\n\nquery User($id: ID!) {\n  user(id: $id) { name }\n}\nvariables {\"id\": \"${login.response.body}\"}\nEOF",
+                "EOF"
+            ),
+            Ok((
+                "",
+                GraphQLRequest {
+                    http: HTTPRequest {
+                        method: "POST",
+                        endpoint: Template::literal("http://localhost/graphql"),
+                        version: Protocol::HTTP1_1,
+                        headers: vec![("Authorization", Template::literal("token"))],
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::default())),
+                        tls: None,
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
+                    },
+                    query: Template::literal("query User($id: ID!) {\n  user(id: $id) { name }\n}"),
+                    variables: Some(Template::parse("{\"id\": \"${login.response.body}\"}").unwrap().1),
+                    options: GraphQLOptions::default(),
+                }
+            ))
+        );
+        let req = GraphQLRequest::parse(
+            "GET http://localhost/graphql\n\n{ me { name } }\nEOF",
+            "EOF",
+        )
+        .unwrap()
+        .1;
+        assert_eq!(req.query, Template::literal("{ me { name } }"));
+        assert_eq!(req.variables, None);
+    }
+
+    #[test]
+    fn graphql_options_test() {
+        let mut options = vec![
+            ("operation", "User"),
+            ("persisted", "true"),
+            ("verify", "insecure"),
+        ];
+        assert_eq!(
+            GraphQLOptions::parse(&mut options),
+            Ok(GraphQLOptions {
+                operation: Some("User"),
+                persisted: true,
+            })
+        );
+        assert_eq!(options, vec![("verify", "insecure")]);
+        assert!(GraphQLOptions::parse(&mut vec![("persisted", "yes")]).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use nom::{
+    bytes::complete::{is_not, tag},
+    character::complete::{line_ending, not_line_ending, space1},
+    multi::many_till,
+    sequence::{separated_pair, terminated},
+    IResult,
+};
+
+use crate::http::header;
+use crate::util::block;
+use crate::{TLSOptions, Template};
+
+/// A call to a gRPC method, written as `package.Service/Method endpoint` followed by metadata
+/// headers and the request messages.
+#[derive(Debug, PartialEq)]
+pub struct GRPCRequest<'a> {
+    /// The fully qualified service name, like `helloworld.Greeter`.
+    pub service: &'a str,
+    pub method: &'a str,
+    pub endpoint: Template<'a>,
+    pub metadata: Vec<(&'a str, Template<'a>)>,
+    /// The request messages in the protobuf text format or JSON. A single message may be written
+    /// bare, while streaming calls wrap each message in braces.
+    pub body: Template<'a>,
+    pub tls: Option<TLSOptions<'a>>,
+    pub descriptors: GRPCDescriptors<'a>,
+}
+
+impl<'a> GRPCRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        let (input, ((service, method), endpoint)) = terminated(
+            separated_pair(
+                separated_pair(is_not("/ \t\r\n"), tag("/"), is_not(" \t\r\n")),
+                space1,
+                not_line_ending,
+            ),
+            line_ending,
+        )(input)?;
+        let (input, (metadata, _)) =
+            many_till(terminated(header, line_ending), line_ending)(input)?;
+        let (input, body) = block(input, eof)?;
+
+        Ok((
+            input,
+            GRPCRequest {
+                service,
+                method,
+                endpoint: Template::parse(endpoint.trim_end())?.1,
+                metadata: metadata
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, Template::parse(v)?.1)))
+                    .collect::<Result<_, _>>()?,
+                body: Template::parse(body)?.1,
+                tls: None,
+                descriptors: GRPCDescriptors::default(),
+            },
+        ))
+    }
+}
+
+/// Where to find the message and service definitions. Each option may be given more than once.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GRPCDescriptors<'a> {
+    /// `.proto` files to parse.
+    pub protos: Vec<&'a str>,
+    /// Directories to resolve imports from. Defaults to the directory of each `.proto` file.
+    pub includes: Vec<&'a str>,
+    /// Files holding a serialized `FileDescriptorSet`, like the output of
+    /// `protoc --descriptor_set_out`.
+    pub sets: Vec<&'a str>,
+}
+
+impl<'a> GRPCDescriptors<'a> {
+    pub fn parse(options: &mut Vec<(&'a str, &'a str)>) -> Self {
+        let mut result = Self::default();
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "proto" => result.protos.push(val),
+                "include" => result.includes.push(val),
+                "descriptors" => result.sets.push(val),
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grpc_test() {
+        assert_eq!(
+            GRPCRequest::parse(
+                "helloworld.Greeter/SayHello http://localhost:50051\nx-token: ${login.response.body}\n\nname: \"world\"\nEOF",
+                "EOF"
+            ),
+            Ok((
+                "",
+                GRPCRequest {
+                    service: "helloworld.Greeter",
+                    method: "SayHello",
+                    endpoint: Template::literal("http://localhost:50051"),
+                    metadata: vec![("x-token", Template::parse("${login.response.body}").unwrap().1)],
+                    body: Template::literal("name: \"world\""),
+                    tls: None,
+                    descriptors: GRPCDescriptors::default(),
+                }
+            ))
+        );
+        assert!(GRPCRequest::parse("SayHello http://localhost:50051\n\nEOF", "EOF").is_err());
+    }
+
+    #[test]
+    fn grpc_descriptors_test() {
+        let mut options = vec![
+            ("proto", "a.proto"),
+            ("verify", "insecure"),
+            ("proto", "b.proto"),
+            ("include", "protos"),
+            ("descriptors", "set.bin"),
+        ];
+        assert_eq!(
+            GRPCDescriptors::parse(&mut options),
+            GRPCDescriptors {
+                protos: vec!["a.proto", "b.proto"],
+                includes: vec!["protos"],
+                sets: vec!["set.bin"],
+            }
+        );
+        assert_eq!(options, vec![("verify", "insecure")]);
+    }
+}
@@ -2,57 +2,250 @@ use std::fmt::Display;
 
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take_until},
+    bytes::complete::{is_not, tag, take_till1},
     character::complete::{alpha1, line_ending, not_line_ending, space0, space1},
+    combinator::{all_consuming, map, opt, peek, value},
     multi::many_till,
-    sequence::{pair, separated_pair, terminated},
+    sequence::{pair, preceded, separated_pair, terminated},
     IResult,
 };
 
+use crate::tls::option_error;
+use crate::util::{block, boolean};
+use crate::{
+    BodyFormat, Charset, GRPCDescriptors, HTTPBody, Payload, PayloadFormat, TLSOptions, Template,
+};
+
 #[derive(Debug, PartialEq)]
 pub struct HTTPRequest<'a> {
     pub method: &'a str,
-    pub endpoint: hyper::Uri,
+    pub endpoint: Template<'a>,
     pub version: Protocol,
-    pub headers: Vec<(&'a str, &'a str)>,
-    pub body: &'a str,
+    pub headers: Vec<(&'a str, Template<'a>)>,
+    /// Where the headers added by courier go among the headers above, or which to leave out.
+    pub directives: Vec<HeaderDirective<'a>>,
+    pub body: HTTPBody<'a>,
+    pub tls: Option<TLSOptions<'a>>,
+    pub h2c: Option<H2C>,
+    /// The named connection to send the request over, set with the `connection` option.
+    pub connection: Option<ConnectionOptions<'a>>,
+    pub options: HTTPOptions<'a>,
 }
 
 impl<'a> HTTPRequest<'a> {
     pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        Self::parse_as(input, eof, BodyFormat::default())
+    }
+
+    /// Parse a request whose body is written in the given format.
+    pub fn parse_as(input: &'a str, eof: &str, format: BodyFormat) -> IResult<&'a str, Self> {
+        let (input, mut req) = Self::parse_head(input)?;
+        let (input, body) = block(input, eof)?;
+        req.body = HTTPBody::parse(body, format)?.1;
+        Ok((input, req))
+    }
+
+    /// Parse the request line and headers up to the blank line before the body, leaving the body
+    /// empty.
+    pub fn parse_head(input: &'a str) -> IResult<&'a str, Self> {
         // Read the connection details.
-        let (input, (method, endpoint)) =
+        let (input, (method, target)) =
             terminated(separated_pair(alpha1, space1, not_line_ending), line_ending)(input)?;
 
-        // Read the headers.
-        let (input, (headers, _)) = many_till(terminated(header, line_ending), line_ending)(input)?;
+        // The protocol version is optional and defaults to HTTP/1.1.
+        let (endpoint, version) = match target.rsplit_once(' ') {
+            Some((endpoint, version)) if version.starts_with("HTTP/") => {
+                let (_, version) = all_consuming(Protocol::parse)(version)?;
+                (endpoint.trim_end(), version)
+            }
+            _ => (target, Protocol::HTTP1_1),
+        };
+
+        // Read the headers, noting where any directives come between them.
+        let (input, (lines, _)) =
+            many_till(terminated(header_line, line_ending), line_ending)(input)?;
+        let mut headers = Vec::new();
+        let mut directives = Vec::new();
+        for line in lines {
+            match line {
+                HeaderLine::Header(key, val) => headers.push((key, Template::parse(val)?.1)),
+                HeaderLine::Default(name) => directives.push(HeaderDirective::Default {
+                    position: headers.len(),
+                    name,
+                }),
+                HeaderLine::Omit(name) => directives.push(HeaderDirective::Omit(name)),
+            }
+        }
 
-        // Read the body, allowing either line ending before the eof token.
-        let eof = format!("\r\n{}", eof);
-        let (input, body) = alt((
-            terminated(take_until(eof.as_str()), tag(eof.as_str())),
-            terminated(take_until(&eof[1..]), tag(&eof[1..])),
-        ))(input)?;
+        let (_, endpoint_template) = Template::parse(endpoint)?;
+        if !endpoint_template.validate_literal(|literal| literal.parse::<hyper::Uri>().is_ok()) {
+            return Err(nom::Err::Error(nom::error::Error {
+                input: endpoint,
+                code: nom::error::ErrorKind::Tag,
+            }));
+        }
 
         Ok((
             input,
             HTTPRequest {
                 method,
-                endpoint: endpoint.parse::<hyper::Uri>().map_err(|e| {
-                    nom::Err::Error(nom::error::Error {
-                        input: endpoint,
-                        code: nom::error::ErrorKind::Tag,
-                    })
-                })?,
-                version: Protocol::HTTP1_1,
+                endpoint: endpoint_template,
+                version,
                 headers,
-                body,
+                directives,
+                body: HTTPBody::default(),
+                tls: None,
+                h2c: None,
+                connection: None,
+                options: HTTPOptions::default(),
             },
         ))
     }
 }
 
-fn header(input: &str) -> IResult<&str, (&str, &str)> {
+/// An HTTP/1 request sent byte for byte as written, without the checks and rewriting an HTTP
+/// client would do. This is useful for testing request smuggling and how servers handle requests
+/// that are malformed or ambiguous.
+#[derive(Debug, PartialEq)]
+pub struct RawHTTPRequest<'a> {
+    /// The request line, headers and body.
+    pub request: Payload<'a>,
+    pub tls: Option<TLSOptions<'a>>,
+    pub raw: RawHTTP,
+    pub connection: Option<ConnectionOptions<'a>>,
+    pub options: HTTPOptions<'a>,
+}
+
+impl<'a> RawHTTPRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str, format: PayloadFormat) -> IResult<&'a str, Self> {
+        let (input, request) = block(input, eof)?;
+        Ok((
+            input,
+            RawHTTPRequest {
+                request: Payload::parse(request, format)?,
+                tls: None,
+                raw: RawHTTP::default(),
+                connection: None,
+                options: HTTPOptions::default(),
+            },
+        ))
+    }
+}
+
+/// Keeps an HTTP/1 connection open so later steps can send requests over it. Steps with the same
+/// connection name share a connection, which the first of them opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionOptions<'a> {
+    pub name: &'a str,
+    /// Send the request without waiting for its response. The response is read by the next step
+    /// on the connection that doesn't pipeline.
+    pub pipeline: bool,
+}
+
+impl<'a> ConnectionOptions<'a> {
+    /// Returns `None` unless the `connection` option is set.
+    pub fn parse(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Option<Self>, nom::Err<nom::error::Error<&'a str>>> {
+        let mut name = None;
+        let mut pipeline = None;
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "connection" => name = Some(val),
+                "pipeline" => pipeline = Some(boolean(val).ok_or_else(|| option_error(val))?),
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        match (name, pipeline) {
+            (None, Some(_)) => Err(option_error("pipeline")),
+            (None, None) => Ok(None),
+            (Some(name), pipeline) => Ok(Some(Self {
+                name,
+                pipeline: pipeline.unwrap_or_default(),
+            })),
+        }
+    }
+}
+
+/// Settings for requests sent with the `raw` option.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RawHTTP {
+    /// Add a `Content-Length` header for the body unless the request already has one.
+    pub content_length: bool,
+}
+
+impl RawHTTP {
+    /// Returns `None` unless the `raw` option is set.
+    pub fn parse<'a>(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Option<Self>, nom::Err<nom::error::Error<&'a str>>> {
+        let mut raw = false;
+        let mut content_length = None;
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "raw" => raw = boolean(val).ok_or_else(|| option_error(val))?,
+                "content_length" => {
+                    content_length = Some(boolean(val).ok_or_else(|| option_error(val))?)
+                }
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        match (raw, content_length) {
+            (false, Some(_)) => Err(option_error("content_length")),
+            (false, None) => Ok(None),
+            (true, content_length) => Ok(Some(Self {
+                content_length: content_length.unwrap_or_default(),
+            })),
+        }
+    }
+}
+
+/// A header line that controls the headers courier adds to a request, like `Host`,
+/// `User-Agent` and `Content-Length`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderDirective<'a> {
+    /// `@default [name]`: send the named default header, or every one not placed by another
+    /// directive, just before the step's header at this position. Otherwise `Host` and
+    /// `User-Agent` go before the step's headers, and the rest go after.
+    Default {
+        position: usize,
+        name: Option<&'a str>,
+    },
+    /// `@omit [name]`: don't send the named default header, or any of them.
+    Omit(Option<&'a str>),
+}
+
+enum HeaderLine<'a> {
+    Header(&'a str, &'a str),
+    Default(Option<&'a str>),
+    Omit(Option<&'a str>),
+}
+
+fn header_line(input: &str) -> IResult<&str, HeaderLine<'_>> {
+    alt((
+        map(
+            preceded(tag("@default"), directive_name),
+            HeaderLine::Default,
+        ),
+        map(preceded(tag("@omit"), directive_name), HeaderLine::Omit),
+        map(header, |(key, val)| HeaderLine::Header(key, val)),
+    ))(input)
+}
+
+/// Match the optional header name after a directive, which must be followed by the end of the
+/// line.
+fn directive_name(input: &str) -> IResult<&str, Option<&str>> {
+    terminated(
+        opt(preceded(space1, take_till1(|c: char| c.is_whitespace()))),
+        pair(space0, peek(line_ending)),
+    )(input)
+}
+
+pub(crate) fn header(input: &str) -> IResult<&str, (&str, &str)> {
     separated_pair(header_key, pair(tag(":"), space0), header_val)(input)
 }
 
@@ -63,15 +256,129 @@ pub fn header_key(input: &str) -> IResult<&str, &str> {
 pub fn header_val(input: &str) -> IResult<&str, &str> {
     not_line_ending(input)
 }
-#[derive(Debug, PartialEq, Eq)]
+/// How message bodies are written and read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HTTPOptions<'a> {
+    /// Decode response bodies according to their `Content-Encoding`, keeping the bytes as they
+    /// arrived alongside the decoded body.
+    pub decode: bool,
+    /// Encode the request body with the codings named by the step's `Content-Encoding` header,
+    /// so the plan can hold it as plain text.
+    pub encode: bool,
+    pub body: BodyFormat,
+    /// The protobuf message type of a `protobuf` request body.
+    pub message: Option<&'a str>,
+    /// Decode the response body as this protobuf message type.
+    pub response_message: Option<&'a str>,
+    /// Where to find the protobuf message types.
+    pub descriptors: GRPCDescriptors<'a>,
+    /// The encoding to send a raw body's text in, instead of UTF-8.
+    pub charset: Option<Charset>,
+}
+
+impl Default for HTTPOptions<'_> {
+    fn default() -> Self {
+        Self {
+            decode: true,
+            encode: true,
+            body: BodyFormat::default(),
+            message: None,
+            response_message: None,
+            descriptors: GRPCDescriptors::default(),
+            charset: None,
+        }
+    }
+}
+
+impl<'a> HTTPOptions<'a> {
+    pub fn parse(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = Self {
+            descriptors: GRPCDescriptors::parse(options),
+            ..Self::default()
+        };
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "decode" => result.decode = boolean(val).ok_or_else(|| option_error(val))?,
+                "encode" => result.encode = boolean(val).ok_or_else(|| option_error(val))?,
+                "body" => result.body = BodyFormat::parse(val).ok_or_else(|| option_error(val))?,
+                "message" => result.message = Some(val),
+                "response_message" => result.response_message = Some(val),
+                "charset" => {
+                    result.charset = Some(Charset::parse(val).ok_or_else(|| option_error(val))?)
+                }
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        if result.body == BodyFormat::Protobuf && result.message.is_none() {
+            return Err(option_error("body"));
+        }
+        Ok(result)
+    }
+}
+
+/// How an HTTP/2 connection without TLS is started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H2C {
+    /// Send the HTTP/2 connection preface straight away.
+    PriorKnowledge,
+    /// Send the request as HTTP/1.1 with an `Upgrade: h2c` header, switching to HTTP/2 if the
+    /// server agrees.
+    Upgrade,
+}
+
+impl H2C {
+    pub fn parse<'a>(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Option<Self>, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = None;
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "h2c" => {
+                    result = Some(match val {
+                        "prior-knowledge" => Self::PriorKnowledge,
+                        "upgrade" => Self::Upgrade,
+                        _ => return Err(option_error(val)),
+                    })
+                }
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Protocol {
+    HTTP1_0,
     HTTP1_1,
+    HTTP2,
+    HTTP3,
+}
+
+impl Protocol {
+    pub fn parse(input: &str) -> IResult<&str, Self> {
+        alt((
+            value(Self::HTTP1_0, tag("HTTP/1.0")),
+            value(Self::HTTP1_1, tag("HTTP/1.1")),
+            value(Self::HTTP2, tag("HTTP/2")),
+            value(Self::HTTP3, tag("HTTP/3")),
+        ))(input)
+    }
 }
 
 impl Display for Protocol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::HTTP1_0 => f.write_str("HTTP/1.0"),
             Self::HTTP1_1 => f.write_str("HTTP/1.1"),
+            Self::HTTP2 => f.write_str("HTTP/2"),
+            Self::HTTP3 => f.write_str("HTTP/3"),
         }
     }
 }
@@ -79,6 +386,7 @@ impl Display for Protocol {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{Accessor, Expr, Payload, Reference, TemplatePart};
 
     #[test]
     fn http_test() {
@@ -92,9 +400,14 @@ mod tests {
                 HTTPRequest {
                     method: "POST",
                     version: Protocol::HTTP1_1,
-                    endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
-                    headers: vec![("Content-Type", "text/plain")],
-                    body: "test body",
+                    endpoint: Template::literal("example.com"),
+                    headers: vec![("Content-Type", Template::literal("text/plain"))],
+                    directives: Vec::new(),
+                    body: HTTPBody::Payload(Payload::Raw(Template::literal("test body"))),
+                    tls: None,
+                    h2c: None,
+                    connection: None,
+                    options: HTTPOptions::default(),
                 },
             ))
         );
@@ -108,9 +421,14 @@ mod tests {
                 HTTPRequest {
                     method: "POST",
                     version: Protocol::HTTP1_1,
-                    endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
-                    headers: vec![("Content-Type", "text/plain")],
-                    body: "test body",
+                    endpoint: Template::literal("example.com"),
+                    headers: vec![("Content-Type", Template::literal("text/plain"))],
+                    directives: Vec::new(),
+                    body: HTTPBody::Payload(Payload::Raw(Template::literal("test body"))),
+                    tls: None,
+                    h2c: None,
+                    connection: None,
+                    options: HTTPOptions::default(),
                 },
             ))
         );
@@ -124,5 +442,188 @@ mod tests {
                 nom::error::ErrorKind::Space,
             )))
         );
+        assert_eq!(
+            HTTPRequest::parse("GET ${host.response.body}/path\n\n\nEOF", "EOF"),
+            Ok((
+                "",
+                HTTPRequest {
+                    method: "GET",
+                    version: Protocol::HTTP1_1,
+                    endpoint: Template {
+                        parts: vec![
+                            TemplatePart::Expr(Expr::Reference(Reference {
+                                step: "host",
+                                path: vec![Accessor::Field("response"), Accessor::Field("body")],
+                            })),
+                            TemplatePart::Literal("/path"),
+                        ],
+                    },
+                    headers: Vec::new(),
+                    directives: Vec::new(),
+                    body: HTTPBody::Payload(Payload::Raw(Template::default())),
+                    tls: None,
+                    h2c: None,
+                    connection: None,
+                    options: HTTPOptions::default(),
+                },
+            ))
+        );
+    }
+
+    #[test]
+    fn http_version_test() {
+        let parse =
+            |text| HTTPRequest::parse(text, "EOF").map(|(_, req)| (req.endpoint, req.version));
+        assert_eq!(
+            parse("GET example.com HTTP/1.0\n\n\nEOF"),
+            Ok((Template::literal("example.com"), Protocol::HTTP1_0))
+        );
+        assert_eq!(
+            parse("GET example.com HTTP/1.1\n\n\nEOF"),
+            Ok((Template::literal("example.com"), Protocol::HTTP1_1))
+        );
+        assert_eq!(
+            parse("GET example.com HTTP/2\n\n\nEOF"),
+            Ok((Template::literal("example.com"), Protocol::HTTP2))
+        );
+        assert_eq!(
+            parse("GET example.com HTTP/3\n\n\nEOF"),
+            Ok((Template::literal("example.com"), Protocol::HTTP3))
+        );
+        assert_eq!(
+            parse("GET example.com\n\n\nEOF"),
+            Ok((Template::literal("example.com"), Protocol::HTTP1_1))
+        );
+        assert_eq!(
+            parse("GET example.com HTTP/1.2\n\n\nEOF"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "HTTP/1.2",
+                nom::error::ErrorKind::Tag,
+            )))
+        );
+        assert_eq!(
+            parse("GET example.com HTTP/1.0junk\n\n\nEOF"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "junk",
+                nom::error::ErrorKind::Eof,
+            )))
+        );
+    }
+
+    #[test]
+    fn header_directives_test() {
+        let (_, req) = HTTPRequest::parse(
+            "GET example.com\nX-A: 1\n@default Host\nx-b: 2\n@omit  user-agent \n@default\n@defaults: 3\n\n\nEOF",
+            "EOF",
+        )
+        .unwrap();
+        assert_eq!(
+            req.headers,
+            vec![
+                ("X-A", Template::literal("1")),
+                ("x-b", Template::literal("2")),
+                ("@defaults", Template::literal("3")),
+            ]
+        );
+        assert_eq!(
+            req.directives,
+            vec![
+                HeaderDirective::Default {
+                    position: 1,
+                    name: Some("Host")
+                },
+                HeaderDirective::Omit(Some("user-agent")),
+                HeaderDirective::Default {
+                    position: 2,
+                    name: None
+                },
+            ]
+        );
+        assert!(HTTPRequest::parse("GET example.com\n@omit a b\n\n\nEOF", "EOF").is_err());
+    }
+
+    #[test]
+    fn http_options_test() {
+        let mut options = vec![
+            ("decode", "false"),
+            ("verify", "insecure"),
+            ("body", "protobuf"),
+            ("message", "test.HelloRequest"),
+            ("proto", "greeter.proto"),
+        ];
+        assert_eq!(
+            HTTPOptions::parse(&mut options),
+            Ok(HTTPOptions {
+                decode: false,
+                body: BodyFormat::Protobuf,
+                message: Some("test.HelloRequest"),
+                descriptors: GRPCDescriptors {
+                    protos: vec!["greeter.proto"],
+                    ..GRPCDescriptors::default()
+                },
+                ..HTTPOptions::default()
+            })
+        );
+        assert_eq!(options, vec![("verify", "insecure")]);
+        assert!(HTTPOptions::parse(&mut vec![("encode", "gzip")]).is_err());
+        assert!(HTTPOptions::parse(&mut vec![("body", "xml")]).is_err());
+        // Protobuf bodies need a message type.
+        assert!(HTTPOptions::parse(&mut vec![("body", "protobuf")]).is_err());
+    }
+
+    #[test]
+    fn raw_http_test() {
+        assert_eq!(
+            RawHTTPRequest::parse(
+                "GET  /a b HTTP/1.1\\r\nHOST:x\n\nEOF",
+                "EOF",
+                PayloadFormat::Escaped
+            ),
+            Ok((
+                "",
+                RawHTTPRequest {
+                    request: Payload::Escaped(Template::literal("GET  /a b HTTP/1.1\\r\nHOST:x\n")),
+                    tls: None,
+                    raw: RawHTTP::default(),
+                    connection: None,
+                    options: HTTPOptions::default(),
+                }
+            ))
+        );
+
+        let mut options = vec![("raw", "true"), ("content_length", "true"), ("ca", "a.pem")];
+        assert_eq!(
+            RawHTTP::parse(&mut options),
+            Ok(Some(RawHTTP {
+                content_length: true
+            }))
+        );
+        assert_eq!(options, vec![("ca", "a.pem")]);
+        assert_eq!(RawHTTP::parse(&mut vec![("raw", "false")]), Ok(None));
+        assert_eq!(
+            RawHTTP::parse(&mut vec![("content_length", "true")]),
+            Err(option_error("content_length"))
+        );
+    }
+
+    #[test]
+    fn connection_options_test() {
+        let mut options = vec![("connection", "a"), ("tls", "true"), ("pipeline", "true")];
+        assert_eq!(
+            ConnectionOptions::parse(&mut options),
+            Ok(Some(ConnectionOptions {
+                name: "a",
+                pipeline: true
+            }))
+        );
+        assert_eq!(options, vec![("tls", "true")]);
+        assert_eq!(ConnectionOptions::parse(&mut Vec::new()), Ok(None));
+        assert_eq!(
+            ConnectionOptions::parse(&mut vec![("pipeline", "true")]),
+            Err(option_error("pipeline"))
+        );
+        assert!(
+            ConnectionOptions::parse(&mut vec![("connection", "a"), ("pipeline", "x")]).is_err()
+        );
     }
 }
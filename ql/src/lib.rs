@@ -1,9 +1,31 @@
+mod body;
 pub mod exec;
+mod graphql;
+mod grpc;
 mod http;
+mod payload;
 mod plan;
+mod quic;
+mod statement;
 mod step;
+mod tcp;
+mod template;
+mod tls;
+mod udp;
 mod util;
+mod websocket;
 
+pub use body::*;
+pub use graphql::*;
+pub use grpc::*;
 pub use http::*;
+pub use payload::*;
 pub use plan::*;
+pub use quic::*;
+pub use statement::*;
 pub use step::*;
+pub use tcp::*;
+pub use template::*;
+pub use tls::*;
+pub use udp::*;
+pub use websocket::*;
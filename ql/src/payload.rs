@@ -0,0 +1,206 @@
+use base64::Engine;
+use encoding_rs::Encoding;
+
+use crate::Template;
+
+/// How a block of bytes is written in the plan, chosen with a step's `body` option.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadFormat {
+    /// Text sent as written.
+    #[default]
+    Raw,
+    /// Text which may use `\r`, `\n`, `\t`, `\0`, `\\` and `\xNN` escapes.
+    Escaped,
+    /// Base64, ignoring whitespace.
+    Base64,
+    /// Pairs of hex digits, ignoring whitespace.
+    Hex,
+    /// The path of a file whose contents are sent.
+    File,
+}
+
+impl PayloadFormat {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input {
+            "raw" => Some(Self::Raw),
+            "escaped" => Some(Self::Escaped),
+            "base64" => Some(Self::Base64),
+            "hex" => Some(Self::Hex),
+            "file" => Some(Self::File),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Payload<'a> {
+    Raw(Template<'a>),
+    Escaped(Template<'a>),
+    Base64(Template<'a>),
+    Hex(Template<'a>),
+    File(Template<'a>),
+}
+
+impl Default for Payload<'_> {
+    fn default() -> Self {
+        Self::Raw(Template::default())
+    }
+}
+
+impl<'a> Payload<'a> {
+    pub fn parse(
+        input: &'a str,
+        format: PayloadFormat,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let invalid = || {
+            nom::Err::Error(nom::error::Error {
+                input,
+                code: nom::error::ErrorKind::Verify,
+            })
+        };
+        Ok(match format {
+            PayloadFormat::Raw => Self::Raw(Template::parse(input)?.1),
+            PayloadFormat::Escaped => Self::Escaped(Template::parse_escaped(input)?.1),
+            PayloadFormat::Base64 => {
+                let template = Template::parse(input)?.1;
+                if !template.validate_literal(|literal| decode_base64(literal).is_some()) {
+                    return Err(invalid());
+                }
+                Self::Base64(template)
+            }
+            PayloadFormat::Hex => {
+                let template = Template::parse(input)?.1;
+                if !template.validate_literal(|literal| decode_hex(literal).is_some()) {
+                    return Err(invalid());
+                }
+                Self::Hex(template)
+            }
+            PayloadFormat::File => Self::File(Template::parse(input.trim())?.1),
+        })
+    }
+}
+
+/// Decode base64 written over any number of lines.
+pub fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let input: String = input.split_whitespace().collect();
+    base64::engine::general_purpose::STANDARD.decode(input).ok()
+}
+
+/// Decode hex digits, which may be split up with whitespace between bytes.
+pub fn decode_hex(input: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = input.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            // from_str_radix also accepts a sign, like "+f".
+            if !pair.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()
+        })
+        .collect()
+}
+
+/// The character encoding text is sent in, chosen with a step's `charset` option. Plans are
+/// always UTF-8, so text is converted before sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    UTF16LE,
+    UTF16BE,
+    UTF32LE,
+    UTF32BE,
+    /// Any other encoding with a WHATWG label, like `gb18030` or `shift_jis`.
+    Other(&'static Encoding),
+}
+
+impl Charset {
+    pub fn parse(input: &str) -> Option<Self> {
+        match input.to_ascii_lowercase().as_str() {
+            "utf-32" | "utf-32le" => return Some(Self::UTF32LE),
+            "utf-32be" => return Some(Self::UTF32BE),
+            _ => {}
+        }
+        // encoding_rs can only decode UTF-16, so it's encoded by hand like UTF-32.
+        let encoding = Encoding::for_label_no_replacement(input.as_bytes())?;
+        Some(if encoding == encoding_rs::UTF_16LE {
+            Self::UTF16LE
+        } else if encoding == encoding_rs::UTF_16BE {
+            Self::UTF16BE
+        } else {
+            Self::Other(encoding)
+        })
+    }
+
+    /// Convert text to this encoding, or return `None` if it has characters the encoding can't
+    /// represent.
+    pub fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        Some(match self {
+            Self::UTF16LE => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Self::UTF16BE => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            Self::UTF32LE => text
+                .chars()
+                .flat_map(|c| u32::from(c).to_le_bytes())
+                .collect(),
+            Self::UTF32BE => text
+                .chars()
+                .flat_map(|c| u32::from(c).to_be_bytes())
+                .collect(),
+            Self::Other(encoding) => {
+                let (bytes, _, unmappable) = encoding.encode(text);
+                if unmappable {
+                    return None;
+                }
+                bytes.into_owned()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_test() {
+        assert_eq!(
+            Payload::parse("a\\x00${x.raw_response}", PayloadFormat::Escaped),
+            Ok(Payload::Escaped(
+                Template::parse("a\\x00${x.raw_response}").unwrap().1
+            ))
+        );
+        assert!(Payload::parse("\\q", PayloadFormat::Escaped).is_err());
+        assert_eq!(
+            Payload::parse("aGVs\nbG8=", PayloadFormat::Base64),
+            Ok(Payload::Base64(Template::literal("aGVs\nbG8=")))
+        );
+        assert!(Payload::parse("aGVs!", PayloadFormat::Base64).is_err());
+        assert!(Payload::parse("0a 1", PayloadFormat::Hex).is_err());
+        assert_eq!(
+            Payload::parse("./data.bin\n", PayloadFormat::File),
+            Ok(Payload::File(Template::literal("./data.bin")))
+        );
+    }
+
+    #[test]
+    fn decode_test() {
+        assert_eq!(decode_base64(" aGVs\r\nbG8=\n"), Some(b"hello".to_vec()));
+        assert_eq!(decode_hex("00ff\n 7F"), Some(vec![0x00, 0xff, 0x7f]));
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("+f"), None);
+    }
+
+    #[test]
+    fn charset_test() {
+        let encode = |label, text| Charset::parse(label).unwrap().encode(text);
+        assert_eq!(encode("UTF-16LE", "hé"), Some(vec![0x68, 0, 0xe9, 0]));
+        assert_eq!(encode("utf-16be", "hé"), Some(vec![0, 0x68, 0, 0xe9]));
+        assert_eq!(encode("utf-32", "h"), Some(vec![0x68, 0, 0, 0]));
+        assert_eq!(encode("utf-32be", "h"), Some(vec![0, 0, 0, 0x68]));
+        assert_eq!(encode("gb18030", "中"), Some(vec![0xd6, 0xd0]));
+        assert_eq!(encode("latin1", "中"), None);
+        assert_eq!(Charset::parse("klingon"), None);
+    }
+}
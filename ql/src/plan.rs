@@ -1,32 +1,61 @@
 use nom::combinator::all_consuming;
 use nom::{character::complete::multispace0, multi::many0, sequence::terminated, IResult};
 
-use super::Step;
+use super::{LoopStep, Statement, Step};
 
 #[derive(Debug)]
 pub struct Plan<'a> {
-    pub steps: Vec<Step<'a>>,
+    pub statements: Vec<Statement<'a>>,
 }
 
 impl<'a> Plan<'a> {
     pub fn parse(input: &'a str) -> Result<Self, String> {
         let (_, result) = all_consuming(Self::parse_partial)(input).map_err(|e| e.to_string())?;
+        // Loops can only send steps defined somewhere in the plan.
+        for statement in &result.statements {
+            if let Statement::For { step, .. } | Statement::While { step, .. } = statement {
+                if result.step(step).is_none() {
+                    let LoopStep::Defined(name) = step else {
+                        unreachable!("loops with their own step always have one");
+                    };
+                    return Err(format!("no step named `{name}` is defined"));
+                }
+            }
+        }
         Ok(result)
     }
 
-    pub fn parse_partial(input: &'a str) -> IResult<&str, Self> {
-        // Step over whitespace before the first step.
+    /// The step defined with `define` under `name`.
+    pub fn definition(&self, name: &str) -> Option<&Step<'a>> {
+        self.statements
+            .iter()
+            .find_map(|statement| match statement {
+                Statement::Define(step) if step.name == Some(name) => Some(step),
+                _ => None,
+            })
+    }
+
+    /// The step a loop sends.
+    pub fn step<'b>(&'b self, step: &'b LoopStep<'a>) -> Option<&'b Step<'a>> {
+        match step {
+            LoopStep::Step(step) => Some(step),
+            LoopStep::Defined(name) => self.definition(name),
+        }
+    }
+
+    pub fn parse_partial(input: &'a str) -> IResult<&'a str, Self> {
+        // Step over whitespace before the first statement.
         let (input, _) = multispace0(input)?;
 
-        let (input, steps) = many0(terminated(Step::parse, multispace0))(input)?;
-        Ok((input, Plan { steps }))
+        let (input, statements) = many0(terminated(Statement::parse, multispace0))(input)?;
+        Ok((input, Plan { statements }))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{HTTPRequest, Protocol, Step, StepBody};
+    use crate::{HTTPBody, HTTPOptions, HTTPRequest, Payload, Protocol, Step, StepBody, Template};
 
     #[test]
     fn plan_test() {
@@ -44,17 +73,22 @@ mod tests {
             )
             .unwrap()
             .1
-            .steps[0],
-            Step {
+            .statements[0],
+            Statement::Step(Step {
                 name: None,
                 body: StepBody::HTTP(HTTPRequest {
                     method: "POST",
                     version: Protocol::HTTP1_1,
-                    endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
-                    headers: vec![("Content-Type", "text/plain")],
-                    body: "test body",
+                    endpoint: Template::literal("example.com"),
+                    headers: vec![("Content-Type", Template::literal("text/plain"))],
+                    directives: Vec::new(),
+                    body: HTTPBody::Payload(Payload::Raw(Template::literal("test body"))),
+                    tls: None,
+                    h2c: None,
+                    connection: None,
+                    options: HTTPOptions::default(),
                 }),
-            },
+            }),
         );
         assert_eq!(
             Plan::parse("http EOF\nPOSt example.com\nContent-Type:text/plain\n\ntest body\nEOFa")
@@ -64,32 +98,54 @@ mod tests {
         assert_eq!(
             Plan::parse("http EOF\nPOST example.com\n\ntest body\nEOF")
                 .unwrap()
-                .steps[0],
-            Step {
+                .statements[0],
+            Statement::Step(Step {
                 name: None,
                 body: StepBody::HTTP(HTTPRequest {
                     method: "POST",
                     version: Protocol::HTTP1_1,
-                    endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
+                    endpoint: Template::literal("example.com"),
                     headers: Vec::new(),
-                    body: "test body",
+                    directives: Vec::new(),
+                    body: HTTPBody::Payload(Payload::Raw(Template::literal("test body"))),
+                    tls: None,
+                    h2c: None,
+                    connection: None,
+                    options: HTTPOptions::default(),
                 }),
-            },
+            }),
         );
         assert_eq!(
             Plan::parse("http EOF\nPOST example.com\n\nbody\nEOF")
                 .unwrap()
-                .steps[0],
-            Step {
+                .statements[0],
+            Statement::Step(Step {
                 name: None,
                 body: StepBody::HTTP(HTTPRequest {
                     method: "POST",
                     version: Protocol::HTTP1_1,
-                    endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
+                    endpoint: Template::literal("example.com"),
                     headers: Vec::new(),
-                    body: "body",
+                    directives: Vec::new(),
+                    body: HTTPBody::Payload(Payload::Raw(Template::literal("body"))),
+                    tls: None,
+                    h2c: None,
+                    connection: None,
+                    options: HTTPOptions::default(),
                 }),
-            },
+            }),
+        );
+        let plan = Plan::parse(
+            "for id in [1, 2] user\ndefine http user EOF\nGET example.com/${id}\n\n\nEOF\n\
+            while user.response.status != 200 user",
+        )
+        .unwrap();
+        assert_eq!(plan.statements.len(), 3);
+        assert!(plan.definition("user").is_some());
+        assert!(plan.definition("id").is_none());
+        assert_eq!(
+            Plan::parse("for id in [1, 2] user").unwrap_err(),
+            "no step named `user` is defined"
         );
     }
 }
@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{line_ending, not_line_ending},
+    combinator::{all_consuming, eof, map, value},
+    sequence::{pair, preceded, terminated},
+    IResult,
+};
+
+use crate::tls::option_error;
+use crate::util::{block, duration};
+use crate::{TLSOptions, Template};
+
+/// A QUIC connection to an address, written on the first line, followed by one line per stream
+/// to open.
+#[derive(Debug, PartialEq)]
+pub struct QUICRequest<'a> {
+    pub address: Template<'a>,
+    pub streams: Vec<QUICStreamRequest<'a>>,
+    /// QUIC always uses TLS, so these only change how the server is verified.
+    pub tls: TLSOptions<'a>,
+    pub options: QUICOptions<'a>,
+}
+
+impl<'a> QUICRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        let (input, address) = terminated(not_line_ending, line_ending)(input)?;
+        let (input, script) = block(input, eof)?;
+        let streams = script
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(all_consuming(QUICStreamRequest::parse)(line)?.1))
+            .collect::<Result<_, _>>()?;
+        Ok((
+            input,
+            QUICRequest {
+                address: Template::parse(address.trim_end())?.1,
+                streams,
+                tls: TLSOptions::default(),
+                options: QUICOptions::default(),
+            },
+        ))
+    }
+}
+
+/// A stream to open, written as a line like `bidi hello` or `uni \x00`. Payloads are templates
+/// which may use the same escapes as UDP datagrams. The send side of each stream is finished once
+/// its payload is written.
+#[derive(Debug, PartialEq)]
+pub enum QUICStreamRequest<'a> {
+    /// A bidirectional stream, whose response is read until the server finishes it.
+    Bidi(Template<'a>),
+    /// A unidirectional stream which only sends.
+    Uni(Template<'a>),
+}
+
+impl<'a> QUICStreamRequest<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(pair(tag("bidi"), payload), |(_, p)| Self::Bidi(p)),
+            map(pair(tag("uni"), payload), |(_, p)| Self::Uni(p)),
+        ))(input)
+    }
+}
+
+/// An optional payload after a single space.
+fn payload(input: &str) -> IResult<&str, Template<'_>> {
+    alt((
+        preceded(tag(" "), Template::parse_escaped),
+        value(Template::default(), eof),
+    ))(input)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QUICOptions<'a> {
+    /// ALPN protocols to offer. The option can be given more than once.
+    pub alpn: Vec<&'a str>,
+    /// Stop reading streams after this long, keeping whatever was read so far.
+    pub timeout: Option<Duration>,
+}
+
+impl<'a> QUICOptions<'a> {
+    pub fn parse(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = Self::default();
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "alpn" => result.alpn.push(val),
+                "timeout" => result.timeout = Some(duration(val).ok_or_else(|| option_error(val))?),
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quic_test() {
+        assert_eq!(
+            QUICRequest::parse(
+                "localhost:4433\nbidi GET /\\r\\n\n\nuni ${login.response.body}\nbidi\nEOF",
+                "EOF"
+            ),
+            Ok((
+                "",
+                QUICRequest {
+                    address: Template::literal("localhost:4433"),
+                    streams: vec![
+                        QUICStreamRequest::Bidi(Template::literal("GET /\\r\\n")),
+                        QUICStreamRequest::Uni(
+                            Template::parse_escaped("${login.response.body}").unwrap().1
+                        ),
+                        QUICStreamRequest::Bidi(Template::default()),
+                    ],
+                    tls: TLSOptions::default(),
+                    options: QUICOptions::default(),
+                }
+            ))
+        );
+        assert!(QUICRequest::parse("localhost:4433\nstream hi\nEOF", "EOF").is_err());
+        assert!(QUICRequest::parse("localhost:4433\nbidis\nEOF", "EOF").is_err());
+    }
+
+    #[test]
+    fn quic_options_test() {
+        let mut options = vec![
+            ("alpn", "hq-interop"),
+            ("verify", "insecure"),
+            ("alpn", "echo"),
+            ("timeout", "1s"),
+        ];
+        assert_eq!(
+            QUICOptions::parse(&mut options),
+            Ok(QUICOptions {
+                alpn: vec!["hq-interop", "echo"],
+                timeout: Some(Duration::from_secs(1)),
+            })
+        );
+        assert_eq!(options, vec![("verify", "insecure")]);
+    }
+}
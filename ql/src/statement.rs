@@ -0,0 +1,380 @@
+use std::fmt::Display;
+use std::ops::Range;
+
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, line_ending, space0, space1},
+    combinator::{eof, map, map_res, verify},
+    multi::separated_list0,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+use crate::util::ident;
+use crate::{Expr, Step};
+
+/// A top-level item of a plan: a step to send, or a command that controls how steps are sent.
+#[derive(Debug, PartialEq)]
+pub enum Statement<'a> {
+    /// Send a step once.
+    Step(Step<'a>),
+    /// Declare a named step without sending it, so loops can send it by name.
+    Define(Step<'a>),
+    /// Send a step once for each value, with the value in a variable.
+    For {
+        variable: &'a str,
+        values: Values<'a>,
+        step: LoopStep<'a>,
+    },
+    /// Send a step, then send it again for as long as the condition holds.
+    While {
+        condition: Condition<'a>,
+        step: LoopStep<'a>,
+    },
+    /// Check a condition, failing the plan's run if it doesn't hold.
+    Expect(Condition<'a>),
+    /// Change the cookie jar, enabling it if it isn't already.
+    Cookies(CookieCommand<'a>),
+}
+
+impl<'a> Statement<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(
+                preceded(
+                    pair(tag("define"), space1),
+                    verify(Step::parse, |step: &Step| step.name.is_some()),
+                ),
+                Self::Define,
+            ),
+            map(
+                tuple((
+                    tag("for"),
+                    space1,
+                    ident,
+                    delimited(space1, tag("in"), space1),
+                    Values::parse,
+                    space1,
+                    LoopStep::parse,
+                )),
+                |(_, _, variable, _, values, _, step)| Self::For {
+                    variable,
+                    values,
+                    step,
+                },
+            ),
+            map(
+                separated_pair(
+                    preceded(pair(tag("while"), space1), Condition::parse),
+                    space1,
+                    LoopStep::parse,
+                ),
+                |(condition, step)| Self::While { condition, step },
+            ),
+            map(
+                preceded(pair(tag("expect"), space1), Condition::parse),
+                Self::Expect,
+            ),
+            map(
+                preceded(pair(tag("cookies"), space1), CookieCommand::parse),
+                Self::Cookies,
+            ),
+            map(Step::parse, Self::Step),
+        ))(input)
+    }
+}
+
+/// The step a loop sends, either written in the loop or named by a `define`.
+#[derive(Debug, PartialEq)]
+pub enum LoopStep<'a> {
+    Step(Box<Step<'a>>),
+    Defined(&'a str),
+}
+
+impl<'a> LoopStep<'a> {
+    fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(map(Step::parse, Box::new), Self::Step),
+            map(
+                terminated(ident, pair(space0, alt((line_ending, eof)))),
+                Self::Defined,
+            ),
+        ))(input)
+    }
+}
+
+/// What a `cookies` command does to the cookie jar: `enable` it without changing it, `clear` it,
+/// or `set` a cookie as if it came in a `Set-Cookie` header from a URL, as in
+/// `cookies set "https://example.com/" "session=abc; Secure"`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CookieCommand<'a> {
+    Enable,
+    Clear,
+    Set { url: Expr<'a>, cookie: Expr<'a> },
+}
+
+impl<'a> CookieCommand<'a> {
+    fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(tag("enable"), |_| Self::Enable),
+            map(tag("clear"), |_| Self::Clear),
+            map(
+                preceded(
+                    pair(tag("set"), space1),
+                    separated_pair(Expr::parse, space1, Expr::parse),
+                ),
+                |(url, cookie)| Self::Set { url, cookie },
+            ),
+        ))(input)
+    }
+}
+
+/// The values a `for` loop goes through: a list of expressions like `[1, "a", login.response.body]`,
+/// or a range of integers like `1..5`, which leaves out the end, or `1..=5`, which includes it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Values<'a> {
+    List(Vec<Expr<'a>>),
+    Range(Range<i64>),
+}
+
+impl<'a> Values<'a> {
+    fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(
+                delimited(
+                    pair(char('['), space0),
+                    separated_list0(delimited(space0, char(','), space0), Expr::parse),
+                    pair(space0, char(']')),
+                ),
+                Self::List,
+            ),
+            map_res(
+                tuple((int, alt((tag("..="), tag(".."))), int)),
+                |(start, op, end)| {
+                    let end = if op == "..=" {
+                        end.checked_add(1)
+                    } else {
+                        Some(end)
+                    };
+                    end.map(|end| Self::Range(start..end))
+                        .ok_or("range too large")
+                },
+            ),
+        ))(input)
+    }
+}
+
+/// A check on the outputs of earlier steps: a comparison between two expressions, like
+/// `poll.response.status != 200`, or `<expr> exists`, which holds when the expression has a value.
+/// Values that are both integers are compared as numbers, and anything else byte by byte.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition<'a> {
+    Compare {
+        left: Expr<'a>,
+        op: Comparison,
+        right: Expr<'a>,
+    },
+    Exists(Expr<'a>),
+}
+
+impl<'a> Condition<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(
+                tuple((
+                    Expr::parse,
+                    delimited(space0, Comparison::parse, space0),
+                    Expr::parse,
+                )),
+                |(left, op, right)| Self::Compare { left, op, right },
+            ),
+            map(
+                terminated(Expr::parse, pair(space0, tag("exists"))),
+                Self::Exists,
+            ),
+        ))(input)
+    }
+}
+
+impl Display for Condition<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Compare { left, op, right } => write!(f, "{} {} {}", left, op, right),
+            Self::Exists(expr) => write!(f, "{} exists", expr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    /// Whether the left value matches the regular expression on the right, written `matches`.
+    Matches,
+}
+
+impl Comparison {
+    fn parse(input: &str) -> IResult<&str, Self> {
+        alt((
+            map(tag("=="), |_| Self::Equal),
+            map(tag("!="), |_| Self::NotEqual),
+            map(tag("<="), |_| Self::LessOrEqual),
+            map(tag(">="), |_| Self::GreaterOrEqual),
+            map(tag("<"), |_| Self::Less),
+            map(tag(">"), |_| Self::Greater),
+            map(tag("matches"), |_| Self::Matches),
+        ))(input)
+    }
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Equal => "==",
+            Self::NotEqual => "!=",
+            Self::Less => "<",
+            Self::LessOrEqual => "<=",
+            Self::Greater => ">",
+            Self::GreaterOrEqual => ">=",
+            Self::Matches => "matches",
+        })
+    }
+}
+
+fn int(input: &str) -> IResult<&str, i64> {
+    match Expr::parse(input)? {
+        (rest, Expr::Int(i)) => Ok((rest, i)),
+        _ => Err(nom::Err::Error(nom::error::Error {
+            input,
+            code: nom::error::ErrorKind::Digit,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Accessor, Reference, StepBody};
+
+    #[test]
+    fn statement_test() {
+        let (rest, statement) =
+            Statement::parse("for id in 1..=3 http user EOF\nGET example.com/${id}\n\n\nEOF")
+                .unwrap();
+        assert_eq!(rest, "");
+        let Statement::For {
+            variable,
+            values,
+            step: LoopStep::Step(step),
+        } = statement
+        else {
+            panic!("expected a for loop with its own step");
+        };
+        assert_eq!(variable, "id");
+        assert_eq!(values, Values::Range(1..4));
+        assert_eq!(step.name, Some("user"));
+        assert!(matches!(step.body, StepBody::HTTP(_)));
+
+        assert_eq!(
+            Statement::parse("for name in [\"a\", 2 ,login.response.body] user\nhttp"),
+            Ok((
+                "http",
+                Statement::For {
+                    variable: "name",
+                    values: Values::List(vec![
+                        Expr::String("a"),
+                        Expr::Int(2),
+                        Expr::Reference(Reference {
+                            step: "login",
+                            path: vec![Accessor::Field("response"), Accessor::Field("body")],
+                        }),
+                    ]),
+                    step: LoopStep::Defined("user"),
+                }
+            ))
+        );
+        assert_eq!(
+            Statement::parse("while poll.response.status!=200 poll"),
+            Ok((
+                "",
+                Statement::While {
+                    condition: Condition::Compare {
+                        left: Expr::Reference(Reference {
+                            step: "poll",
+                            path: vec![Accessor::Field("response"), Accessor::Field("status")],
+                        }),
+                        op: Comparison::NotEqual,
+                        right: Expr::Int(200),
+                    },
+                    step: LoopStep::Defined("poll"),
+                }
+            ))
+        );
+
+        assert_eq!(
+            Statement::parse("expect login.response.headers[\"x-id\"] exists\nexpect"),
+            Ok((
+                "\nexpect",
+                Statement::Expect(Condition::Exists(Expr::Reference(Reference {
+                    step: "login",
+                    path: vec![
+                        Accessor::Field("response"),
+                        Accessor::Field("headers"),
+                        Accessor::Key("x-id"),
+                    ],
+                })))
+            ))
+        );
+        assert_eq!(
+            Statement::parse("expect login.response.body matches \"^tok\""),
+            Ok((
+                "",
+                Statement::Expect(Condition::Compare {
+                    left: Expr::Reference(Reference {
+                        step: "login",
+                        path: vec![Accessor::Field("response"), Accessor::Field("body")],
+                    }),
+                    op: Comparison::Matches,
+                    right: Expr::String("^tok"),
+                })
+            ))
+        );
+
+        assert_eq!(
+            Statement::parse("cookies set \"https://example.com\" login.response.body"),
+            Ok((
+                "",
+                Statement::Cookies(CookieCommand::Set {
+                    url: Expr::String("https://example.com"),
+                    cookie: Expr::Reference(Reference {
+                        step: "login",
+                        path: vec![Accessor::Field("response"), Accessor::Field("body")],
+                    }),
+                })
+            ))
+        );
+        assert_eq!(
+            Statement::parse("cookies clear\nhttp"),
+            Ok(("\nhttp", Statement::Cookies(CookieCommand::Clear)))
+        );
+
+        let (_, statement) =
+            Statement::parse("define tcp ping EOF\nlocalhost:80\nping\nEOF").unwrap();
+        assert!(matches!(
+            statement,
+            Statement::Define(Step {
+                name: Some("ping"),
+                ..
+            })
+        ));
+        // Only named steps can be defined.
+        assert!(Statement::parse("define tcp EOF\nlocalhost:80\nping\nEOF").is_err());
+        assert!(Statement::parse("for i in 1..x poll").is_err());
+        assert!(Statement::parse("while poll.response.status poll").is_err());
+    }
+}
@@ -1,15 +1,28 @@
 use nom::character::complete::not_line_ending;
 use nom::character::streaming::line_ending;
-use nom::sequence::{separated_pair, terminated};
+use nom::combinator::opt;
+use nom::sequence::{pair, separated_pair, terminated};
 use nom::{branch::alt, character::complete::space1, error::ErrorKind, sequence::Tuple, IResult};
 
-use super::util::ident;
-use super::HTTPRequest;
+use super::util::{ident, options};
+use super::{
+    BodyFormat, ConnectionOptions, GRPCDescriptors, GRPCRequest, GraphQLOptions, GraphQLRequest,
+    HTTPOptions, HTTPRequest, Protocol, QUICOptions, QUICRequest, RawHTTP, RawHTTPRequest,
+    TCPOptions, TCPRequest, TLSOptions, UDPOptions, UDPRequest, WebSocketOptions, WebSocketRequest,
+    H2C,
+};
+use crate::tls::option_error;
 
 #[derive(Debug, PartialEq)]
 pub enum StepBody<'a> {
     HTTP(HTTPRequest<'a>),
-    //GraphQL(GraphQLRequest, GraphQLResponse, HTTPRequest, HTTPResponse),
+    RawHTTP(RawHTTPRequest<'a>),
+    TCP(TCPRequest<'a>),
+    UDP(UDPRequest<'a>),
+    WebSocket(WebSocketRequest<'a>),
+    GRPC(GRPCRequest<'a>),
+    GraphQL(GraphQLRequest<'a>),
+    QUIC(QUICRequest<'a>),
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,14 +32,16 @@ pub struct Step<'a> {
 }
 
 impl<'a> Step<'a> {
-    pub fn parse(input: &'a str) -> IResult<&str, Self> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
         alt((Self::named, Self::unnamed))(input)
     }
 
-    fn named(input: &'a str) -> IResult<&str, Step> {
-        let (input, (kind, _, name, _, eof)) =
-            (ident, space1, ident, space1, not_line_ending).parse(input)?;
-        let (input, body) = Self::body(input, kind, eof)?;
+    fn named(input: &'a str) -> IResult<&'a str, Step<'a>> {
+        let (input, (kind, options, _, name, _, eof)) = terminated(
+            |i| (ident, opt(options), space1, ident, space1, not_line_ending).parse(i),
+            line_ending,
+        )(input)?;
+        let (input, body) = Self::body(input, kind, options.unwrap_or_default(), eof)?;
         Ok((
             input,
             Self {
@@ -36,19 +51,158 @@ impl<'a> Step<'a> {
         ))
     }
 
-    fn unnamed(input: &'a str) -> IResult<&str, Step> {
-        let (input, (kind, eof)) =
-            terminated(separated_pair(ident, space1, not_line_ending), line_ending)(input)?;
-        let (input, body) = Self::body(input, kind, eof)?;
+    fn unnamed(input: &'a str) -> IResult<&'a str, Step<'a>> {
+        let (input, ((kind, options), eof)) = terminated(
+            separated_pair(pair(ident, opt(options)), space1, not_line_ending),
+            line_ending,
+        )(input)?;
+        let (input, body) = Self::body(input, kind, options.unwrap_or_default(), eof)?;
         Ok((input, Self { name: None, body }))
     }
 
-    fn body(input: &'a str, kind: &str, eof: &str) -> IResult<&'a str, StepBody<'a>> {
+    /// Each options type's `parse` takes the options it knows out of `options`, so whatever is
+    /// left once they've all run is unknown to the step.
+    fn body(
+        input: &'a str,
+        kind: &str,
+        mut options: Vec<(&'a str, &'a str)>,
+        eof: &str,
+    ) -> IResult<&'a str, StepBody<'a>> {
         match kind {
-            "http" => {
-                let (input, req) = HTTPRequest::parse(input, eof)?;
+            "http" | "https" | "http2" | "http3" => {
+                let tls = TLSOptions::parse(&mut options)?;
+                let h2c = if kind == "http2" {
+                    H2C::parse(&mut options)?
+                } else {
+                    None
+                };
+                // Raw requests are written as HTTP/1, so they can't be sent with other versions,
+                // and only HTTP/1 connections are kept open for later steps.
+                let (raw, connection) = if kind == "http" || kind == "https" {
+                    (
+                        RawHTTP::parse(&mut options)?,
+                        ConnectionOptions::parse(&mut options)?,
+                    )
+                } else {
+                    (None, None)
+                };
+                let http = HTTPOptions::parse(&mut options)?;
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                if let Some(raw) = raw {
+                    let BodyFormat::Payload(format) = http.body else {
+                        return Err(option_error("body"));
+                    };
+                    let (input, mut req) = RawHTTPRequest::parse(input, eof, format)?;
+                    req.tls = if kind == "https" {
+                        Some(tls.unwrap_or_default())
+                    } else {
+                        tls
+                    };
+                    req.raw = raw;
+                    req.connection = connection;
+                    req.options = http;
+                    return Ok((input, StepBody::RawHTTP(req)));
+                }
+                let (input, mut req) = HTTPRequest::parse_as(input, eof, http.body)?;
+                if kind == "http2" {
+                    req.version = Protocol::HTTP2;
+                    req.h2c = h2c;
+                }
+                if kind == "http3" {
+                    req.version = Protocol::HTTP3;
+                }
+                // The request line can ask for a version too, so these are checked against the
+                // version the request ends up with. Chunks can only be written, and connections
+                // only shared, over HTTP/1.
+                if matches!(req.version, Protocol::HTTP2 | Protocol::HTTP3) {
+                    if http.body == BodyFormat::Chunked {
+                        return Err(option_error("body"));
+                    }
+                    if connection.is_some() {
+                        return Err(option_error("connection"));
+                    }
+                }
+                req.connection = connection;
+                req.options = http;
+                // https and http3 steps always use TLS, while other http steps only use it when
+                // the endpoint has an https scheme or any TLS options are set.
+                req.tls = if kind == "https" || kind == "http3" {
+                    Some(tls.unwrap_or_default())
+                } else {
+                    tls
+                };
                 Ok((input, StepBody::HTTP(req)))
             }
+            "tcp" => {
+                let tcp = TCPOptions::parse(&mut options)?;
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                let (input, mut req) = TCPRequest::parse_as(input, eof, tcp.body)?;
+                req.options = tcp;
+                Ok((input, StepBody::TCP(req)))
+            }
+            "udp" => {
+                let udp = UDPOptions::parse(&mut options)?;
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                let (input, mut req) = UDPRequest::parse(input, eof)?;
+                req.options = udp;
+                Ok((input, StepBody::UDP(req)))
+            }
+            "websocket" => {
+                let tls = TLSOptions::parse(&mut options)?;
+                let websocket = WebSocketOptions::parse(&mut options)?;
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                let (input, mut req) = WebSocketRequest::parse(input, eof)?;
+                req.upgrade.tls = tls;
+                req.options = websocket;
+                Ok((input, StepBody::WebSocket(req)))
+            }
+            "grpc" => {
+                let tls = TLSOptions::parse(&mut options)?;
+                let descriptors = GRPCDescriptors::parse(&mut options);
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                let (input, mut req) = GRPCRequest::parse(input, eof)?;
+                req.tls = tls;
+                req.descriptors = descriptors;
+                Ok((input, StepBody::GRPC(req)))
+            }
+            "graphql" => {
+                let tls = TLSOptions::parse(&mut options)?;
+                let http = HTTPOptions::parse(&mut options)?;
+                let graphql = GraphQLOptions::parse(&mut options)?;
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                // The body is always the GraphQL operation.
+                if http.body != BodyFormat::default() || http.message.is_some() {
+                    return Err(option_error("body"));
+                }
+                let (input, mut req) = GraphQLRequest::parse(input, eof)?;
+                req.http.tls = tls;
+                req.http.options = http;
+                req.options = graphql;
+                Ok((input, StepBody::GraphQL(req)))
+            }
+            "quic" => {
+                let tls = TLSOptions::parse(&mut options)?;
+                let quic = QUICOptions::parse(&mut options)?;
+                if let Some((key, _)) = options.first() {
+                    return Err(option_error(key));
+                }
+                let (input, mut req) = QUICRequest::parse(input, eof)?;
+                req.tls = tls.unwrap_or_default();
+                req.options = quic;
+                Ok((input, StepBody::QUIC(req)))
+            }
             _ => Err(nom::Err::Error(nom::error::Error {
                 input,
                 code: ErrorKind::Switch,
@@ -60,8 +214,173 @@ impl<'a> Step<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::HTTPBody;
     use crate::HTTPRequest;
+    use crate::Payload;
+    use crate::PayloadFormat;
     use crate::Protocol;
+    use crate::TCPRead;
+    use crate::TLSVerify;
+    use crate::Template;
+
+    #[test]
+    fn step_options_test() {
+        assert_eq!(
+            Step::parse("https(verify=insecure) get_user EOF\nGET example.com\n\nbody\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: Some("get_user"),
+                    body: StepBody::HTTP(HTTPRequest {
+                        method: "GET",
+                        version: Protocol::HTTP1_1,
+                        endpoint: Template::literal("example.com"),
+                        headers: Vec::new(),
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::literal("body"))),
+                        tls: Some(TLSOptions {
+                            verify: TLSVerify::Insecure
+                        }),
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
+                    })
+                }
+            ))
+        );
+        assert_eq!(
+            Step::parse("http(bogus=1) EOF\nGET example.com\n\nbody\nEOF"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "bogus",
+                nom::error::ErrorKind::Tag,
+            )))
+        );
+        assert_eq!(
+            Step::parse("http2(h2c=upgrade, decode=false) EOF\nGET example.com\n\n\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: None,
+                    body: StepBody::HTTP(HTTPRequest {
+                        method: "GET",
+                        version: Protocol::HTTP2,
+                        endpoint: Template::literal("example.com"),
+                        headers: Vec::new(),
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::default())),
+                        tls: None,
+                        h2c: Some(H2C::Upgrade),
+                        connection: None,
+                        options: HTTPOptions {
+                            decode: false,
+                            ..HTTPOptions::default()
+                        },
+                    })
+                }
+            ))
+        );
+        assert_eq!(
+            Step::parse("https(raw=true, content_length=true, verify=insecure) EOF\nget / HTTP/1.1\nHost: x\n\n\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: None,
+                    body: StepBody::RawHTTP(RawHTTPRequest {
+                        request: Payload::Raw(Template::literal("get / HTTP/1.1\nHost: x\n\n")),
+                        tls: Some(TLSOptions {
+                            verify: TLSVerify::Insecure
+                        }),
+                        raw: RawHTTP {
+                            content_length: true
+                        },
+                        connection: None,
+                        options: HTTPOptions::default(),
+                    })
+                }
+            ))
+        );
+        // Raw requests are always HTTP/1 and can only use the binary body formats.
+        assert!(Step::parse("http2(raw=true) EOF\nGET / HTTP/1.1\n\nEOF").is_err());
+        assert!(Step::parse("http(raw=true, body=json) EOF\nGET / HTTP/1.1\n\nEOF").is_err());
+        // Only HTTP/1 connections are shared between steps.
+        assert!(Step::parse("http2(connection=a) EOF\nGET http://x/\n\n\nEOF").is_err());
+        // Chunks are only written over HTTP/1.
+        assert!(Step::parse("http2(body=chunked) EOF\nPOST http://x/\n\nchunk a\nEOF").is_err());
+        // Neither is possible when the request line asks for HTTP/2 or HTTP/3.
+        assert!(
+            Step::parse("http(body=chunked) EOF\nPOST http://x/ HTTP/2\n\nchunk a\nEOF").is_err()
+        );
+        assert!(Step::parse("http(connection=a) EOF\nGET http://x/ HTTP/2\n\n\nEOF").is_err());
+        assert!(Step::parse("http EOF\nGET http://x/ HTTP/3\n\n\nEOF").is_ok());
+        assert!(Step::parse("https(body=chunked) EOF\nPOST https://x/\n\nchunk a\nEOF").is_ok());
+        assert!(Step::parse("http(connection=a) EOF\nGET http://x/ HTTP/3\n\n\nEOF").is_err());
+        assert!(
+            Step::parse("https(body=chunked) EOF\nPOST https://x/ HTTP/3\n\nchunk a\nEOF").is_err()
+        );
+        assert_eq!(
+            Step::parse("tcp(until=\"\\r\\n\", timeout=2s, body=hex) redis EOF\nlocalhost:6379\n50494e47\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: Some("redis"),
+                    body: StepBody::TCP(TCPRequest {
+                        address: Template::literal("localhost:6379"),
+                        body: Payload::Hex(Template::literal("50494e47")),
+                        options: TCPOptions {
+                            read: TCPRead::Delimiter(b"\r\n".to_vec()),
+                            timeout: Some(std::time::Duration::from_secs(2)),
+                            body: PayloadFormat::Hex,
+                            charset: None,
+                        },
+                    })
+                }
+            ))
+        );
+        assert_eq!(
+            Step::parse("udp(count=2, timeout=1s) EOF\n127.0.0.1:53\nhello\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: None,
+                    body: StepBody::UDP(UDPRequest {
+                        address: Template::literal("127.0.0.1:53"),
+                        datagrams: vec![Template::literal("hello")],
+                        options: UDPOptions {
+                            count: Some(2),
+                            timeout: Some(std::time::Duration::from_secs(1)),
+                        },
+                    })
+                }
+            ))
+        );
+        assert_eq!(
+            Step::parse("quic(alpn=h3, verify=insecure) EOF\nlocalhost:443\nbidi hi\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: None,
+                    body: StepBody::QUIC(QUICRequest {
+                        address: Template::literal("localhost:443"),
+                        streams: vec![crate::QUICStreamRequest::Bidi(Template::literal("hi"))],
+                        tls: TLSOptions {
+                            verify: TLSVerify::Insecure
+                        },
+                        options: QUICOptions {
+                            alpn: vec!["h3"],
+                            timeout: None,
+                        },
+                    })
+                }
+            ))
+        );
+        assert_eq!(
+            Step::parse("http(h2c=upgrade) EOF\nGET example.com\n\n\nEOF"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "h2c",
+                nom::error::ErrorKind::Tag,
+            )))
+        );
+    }
 
     #[test]
     fn step_test() {
@@ -74,9 +393,14 @@ mod tests {
                     body: StepBody::HTTP(HTTPRequest {
                         method: "POST",
                         version: Protocol::HTTP1_1,
-                        endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
-                        headers: vec![("Content-Type", "text/plain")],
-                        body: "test body",
+                        endpoint: Template::literal("example.com"),
+                        headers: vec![("Content-Type", Template::literal("text/plain"))],
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::literal("test body"))),
+                        tls: None,
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
                     })
                 }
             ))
@@ -97,9 +421,14 @@ mod tests {
                     body: StepBody::HTTP(HTTPRequest {
                         method: "POST",
                         version: Protocol::HTTP1_1,
-                        endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
+                        endpoint: Template::literal("example.com"),
                         headers: Vec::new(),
-                        body: "test body",
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::literal("test body"))),
+                        tls: None,
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
                     })
                 }
             ))
@@ -113,9 +442,35 @@ mod tests {
                     body: StepBody::HTTP(HTTPRequest {
                         method: "POST",
                         version: Protocol::HTTP1_1,
-                        endpoint: "example.com".parse::<hyper::Uri>().unwrap(),
+                        endpoint: Template::literal("example.com"),
+                        headers: Vec::new(),
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::literal("body"))),
+                        tls: None,
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
+                    })
+                }
+            ))
+        );
+        assert_eq!(
+            Step::parse("http2 EOF\nGET example.com\n\n\nEOF"),
+            Ok((
+                "",
+                Step {
+                    name: None,
+                    body: StepBody::HTTP(HTTPRequest {
+                        method: "GET",
+                        version: Protocol::HTTP2,
+                        endpoint: Template::literal("example.com"),
                         headers: Vec::new(),
-                        body: "body",
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::default())),
+                        tls: None,
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
                     })
                 }
             ))
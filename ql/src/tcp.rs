@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use nom::{
+    character::complete::{line_ending, not_line_ending},
+    sequence::terminated,
+    IResult,
+};
+
+use crate::tls::option_error;
+use crate::util::{block, duration, unescape};
+use crate::{Charset, Payload, PayloadFormat, Template};
+
+/// Bytes sent as-is over a TCP connection.
+#[derive(Debug, PartialEq)]
+pub struct TCPRequest<'a> {
+    /// The `host:port` to connect to.
+    pub address: Template<'a>,
+    pub body: Payload<'a>,
+    pub options: TCPOptions,
+}
+
+impl<'a> TCPRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        Self::parse_as(input, eof, PayloadFormat::Raw)
+    }
+
+    /// Parse a request whose body is written in the given format.
+    pub fn parse_as(input: &'a str, eof: &str, format: PayloadFormat) -> IResult<&'a str, Self> {
+        let (input, address) = terminated(not_line_ending, line_ending)(input)?;
+
+        let (input, body) = block(input, eof)?;
+
+        Ok((
+            input,
+            TCPRequest {
+                address: Template::parse(address)?.1,
+                body: Payload::parse(body, format)?,
+                options: TCPOptions::default(),
+            },
+        ))
+    }
+}
+
+/// How the request is written and when to stop reading the response.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TCPOptions {
+    pub read: TCPRead,
+    /// Stop reading after this long, keeping whatever was read so far.
+    pub timeout: Option<Duration>,
+    pub body: PayloadFormat,
+    /// Convert a raw text body to this encoding before sending.
+    pub charset: Option<Charset>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum TCPRead {
+    /// Read until the server closes the connection.
+    #[default]
+    Close,
+    /// Read until the bytes end with the delimiter.
+    Delimiter(Vec<u8>),
+    /// Read exactly this many bytes.
+    Bytes(usize),
+}
+
+impl TCPOptions {
+    pub fn parse<'a>(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = Self::default();
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "until" if val == "close" => result.read = TCPRead::Close,
+                "until" => match unescape(val) {
+                    Some(delimiter) if !delimiter.is_empty() => {
+                        result.read = TCPRead::Delimiter(delimiter)
+                    }
+                    _ => return Err(option_error(val)),
+                },
+                "bytes" => {
+                    result.read = TCPRead::Bytes(val.parse().map_err(|_| option_error(val))?)
+                }
+                "timeout" => result.timeout = Some(duration(val).ok_or_else(|| option_error(val))?),
+                "body" => {
+                    result.body = PayloadFormat::parse(val).ok_or_else(|| option_error(val))?
+                }
+                "charset" => {
+                    result.charset = Some(Charset::parse(val).ok_or_else(|| option_error(val))?)
+                }
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_test() {
+        assert_eq!(
+            TCPRequest::parse("localhost:6379\nPING\r\n\nEOF", "EOF"),
+            Ok((
+                "",
+                TCPRequest {
+                    address: Template::literal("localhost:6379"),
+                    body: Payload::Raw(Template::literal("PING\r\n")),
+                    options: TCPOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            TCPRequest::parse("localhost:7\nEOF", "EOF"),
+            Ok((
+                "",
+                TCPRequest {
+                    address: Template::literal("localhost:7"),
+                    body: Payload::default(),
+                    options: TCPOptions::default(),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn tcp_options_test() {
+        let mut options = vec![
+            ("until", "\\r\\n"),
+            ("timeout", "500ms"),
+            ("verify", "system"),
+        ];
+        assert_eq!(
+            TCPOptions::parse(&mut options),
+            Ok(TCPOptions {
+                read: TCPRead::Delimiter(b"\r\n".to_vec()),
+                timeout: Some(Duration::from_millis(500)),
+                ..TCPOptions::default()
+            })
+        );
+        assert_eq!(options, vec![("verify", "system")]);
+        assert_eq!(
+            TCPOptions::parse(&mut vec![("bytes", "12")]),
+            Ok(TCPOptions {
+                read: TCPRead::Bytes(12),
+                ..TCPOptions::default()
+            })
+        );
+        assert_eq!(
+            TCPOptions::parse(&mut vec![("bytes", "-1")]),
+            Err(option_error("-1"))
+        );
+        assert_eq!(
+            TCPOptions::parse(&mut vec![("timeout", "soon")]),
+            Err(option_error("soon"))
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use std::fmt::Display;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag},
+    character::complete::{char, digit1, space0},
+    combinator::{all_consuming, map, map_res, opt},
+    error::ErrorKind,
+    multi::{many0, separated_list0},
+    sequence::{delimited, preceded},
+    IResult,
+};
+
+use crate::util::{ident, unescape};
+
+/// Text which may include expressions to be evaluated at run time, written as `${expr}`. A
+/// literal `${` can be written as `$${`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Template<'a> {
+    pub parts: Vec<TemplatePart<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart<'a> {
+    Literal(&'a str),
+    Expr(Expr<'a>),
+}
+
+impl<'a> Template<'a> {
+    /// A template with no expressions.
+    pub fn literal(text: &'a str) -> Self {
+        Template {
+            parts: if text.is_empty() {
+                Vec::new()
+            } else {
+                vec![TemplatePart::Literal(text)]
+            },
+        }
+    }
+
+    /// Parse a full string as a template.
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        let mut parts = Vec::new();
+        let mut rest = input;
+        while let Some(i) = rest.find("${") {
+            // An escaped `${` keeps the first `$` as part of the literal and drops the second.
+            if i > 0 && rest.as_bytes()[i - 1] == b'$' {
+                parts.push(TemplatePart::Literal(&rest[..i]));
+                rest = &rest[i + 1..];
+                continue;
+            }
+            if i > 0 {
+                parts.push(TemplatePart::Literal(&rest[..i]));
+            }
+            let (after, expr) =
+                delimited(tag("${"), delimited(space0, Expr::parse, space0), tag("}"))(&rest[i..])?;
+            parts.push(TemplatePart::Expr(expr));
+            rest = after;
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest));
+        }
+        Ok(("", Template { parts }))
+    }
+
+    /// Parse a full string as a template whose literal text may use `\r`, `\n`, `\t`, `\0`, `\\`
+    /// and `\xNN` escapes. The escapes are only checked here, and are replaced when the template
+    /// is rendered.
+    pub fn parse_escaped(input: &'a str) -> IResult<&'a str, Self> {
+        let (rest, template) = Self::parse(input)?;
+        for part in &template.parts {
+            if let TemplatePart::Literal(text) = part {
+                if unescape(text).is_none() {
+                    return Err(nom::Err::Error(nom::error::Error {
+                        input: text,
+                        code: ErrorKind::Escaped,
+                    }));
+                }
+            }
+        }
+        Ok((rest, template))
+    }
+
+    /// Returns the template's text if it has no expressions.
+    pub fn as_literal(&self) -> Option<String> {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(text) => Some(*text),
+                TemplatePart::Expr(_) => None,
+            })
+            .collect()
+    }
+
+    /// Check the template's text with `valid` if it has no expressions, so mistakes in literal
+    /// text are caught when the plan is parsed rather than when the step runs. Templates with
+    /// expressions are only checked once they're rendered.
+    pub fn validate_literal(&self, valid: impl FnOnce(&str) -> bool) -> bool {
+        self.as_literal().is_none_or(|literal| valid(&literal))
+    }
+}
+
+/// An expression referencing the output of a named step, like `login.response.body` or
+/// `login.response.headers["set-cookie"]`, or calling a function, like
+/// `json(login.response.body, "user.id")`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<'a> {
+    String(&'a str),
+    Int(i64),
+    Reference(Reference<'a>),
+    Call(Call<'a>),
+}
+
+impl<'a> Expr<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(string, Self::String),
+            map(int, Self::Int),
+            map(Call::parse, Self::Call),
+            map(Reference::parse, Self::Reference),
+        ))(input)
+    }
+
+    /// Parse a full string as an expression.
+    pub fn parse_all(input: &'a str) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let (_, expr) = all_consuming(Self::parse)(input)?;
+        Ok(expr)
+    }
+}
+
+impl Display for Expr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "\"{}\"", s),
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Reference(r) => write!(f, "{}", r),
+            Self::Call(call) => write!(f, "{}", call),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call<'a> {
+    pub function: &'a str,
+    pub args: Vec<Expr<'a>>,
+}
+
+impl<'a> Call<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        let (input, function) = ident(input)?;
+        let (input, args) = delimited(
+            pair_char('('),
+            separated_list0(pair_char(','), Expr::parse),
+            pair_char(')'),
+        )(input)?;
+        Ok((input, Call { function, args }))
+    }
+}
+
+impl Display for Call<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}(", self.function)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", arg)?;
+        }
+        f.write_str(")")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference<'a> {
+    pub step: &'a str,
+    pub path: Vec<Accessor<'a>>,
+}
+
+impl<'a> Reference<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        let (input, step) = ident(input)?;
+        let (input, path) = many0(Accessor::parse)(input)?;
+        Ok((input, Reference { step, path }))
+    }
+}
+
+impl Display for Reference<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.step)?;
+        for accessor in &self.path {
+            write!(f, "{}", accessor)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Accessor<'a> {
+    /// `.name`
+    Field(&'a str),
+    /// `["key"]`
+    Key(&'a str),
+    /// `[0]`
+    Index(usize),
+}
+
+impl<'a> Accessor<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(preceded(char('.'), ident), Self::Field),
+            delimited(
+                pair_char('['),
+                alt((
+                    map(string, Self::Key),
+                    map_res(digit1, |i: &str| i.parse().map(Self::Index)),
+                )),
+                pair_char(']'),
+            ),
+        ))(input)
+    }
+}
+
+impl Display for Accessor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Field(name) => write!(f, ".{}", name),
+            Self::Key(key) => write!(f, "[\"{}\"]", key),
+            Self::Index(i) => write!(f, "[{}]", i),
+        }
+    }
+}
+
+fn string(input: &str) -> IResult<&str, &str> {
+    map(
+        delimited(char('"'), opt(is_not("\"")), char('"')),
+        Option::unwrap_or_default,
+    )(input)
+}
+
+fn int(input: &str) -> IResult<&str, i64> {
+    map_res(
+        nom::combinator::recognize(preceded(opt(char('-')), digit1)),
+        str::parse,
+    )(input)
+}
+
+/// Match a character with optional surrounding spaces.
+fn pair_char<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+    delimited(space0, char(c), space0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_test() {
+        assert_eq!(Template::parse(""), Ok(("", Template::literal(""))));
+        assert_eq!(
+            Template::parse("no expressions"),
+            Ok(("", Template::literal("no expressions")))
+        );
+        assert_eq!(
+            Template::parse("Bearer ${ login.response.headers[\"x-token\"] }!"),
+            Ok((
+                "",
+                Template {
+                    parts: vec![
+                        TemplatePart::Literal("Bearer "),
+                        TemplatePart::Expr(Expr::Reference(Reference {
+                            step: "login",
+                            path: vec![
+                                Accessor::Field("response"),
+                                Accessor::Field("headers"),
+                                Accessor::Key("x-token"),
+                            ],
+                        })),
+                        TemplatePart::Literal("!"),
+                    ],
+                }
+            ))
+        );
+        assert_eq!(
+            Template::parse("cost: $${price}"),
+            Ok((
+                "",
+                Template {
+                    parts: vec![
+                        TemplatePart::Literal("cost: $"),
+                        TemplatePart::Literal("{price}"),
+                    ],
+                }
+            ))
+        );
+        assert_eq!(
+            Template::parse("${login.}"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                ".}",
+                nom::error::ErrorKind::Tag,
+            )))
+        );
+
+        // Only text without expressions is checked.
+        let is_number = |text: &str| text.parse::<i64>().is_ok();
+        assert!(Template::literal("12").validate_literal(is_number));
+        assert!(!Template::literal("1${2").validate_literal(is_number));
+        assert!(Template::parse("1${x}")
+            .unwrap()
+            .1
+            .validate_literal(is_number));
+    }
+
+    #[test]
+    fn escaped_template_test() {
+        assert_eq!(
+            Template::parse_escaped("\\x00${step.body}\\r\\n"),
+            Ok((
+                "",
+                Template {
+                    parts: vec![
+                        TemplatePart::Literal("\\x00"),
+                        TemplatePart::Expr(Expr::Reference(Reference {
+                            step: "step",
+                            path: vec![Accessor::Field("body")],
+                        })),
+                        TemplatePart::Literal("\\r\\n"),
+                    ],
+                }
+            ))
+        );
+        assert_eq!(
+            Template::parse_escaped("\\x0"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "\\x0",
+                ErrorKind::Escaped,
+            )))
+        );
+    }
+
+    #[test]
+    fn expr_test() {
+        assert_eq!(Expr::parse_all("\"text\""), Ok(Expr::String("text")));
+        assert_eq!(Expr::parse_all("-12"), Ok(Expr::Int(-12)));
+        assert_eq!(
+            Expr::parse_all("steps[2].body"),
+            Ok(Expr::Reference(Reference {
+                step: "steps",
+                path: vec![Accessor::Index(2), Accessor::Field("body")],
+            }))
+        );
+        assert_eq!(
+            Expr::parse_all("login.response.headers[\"set-cookie\"]")
+                .unwrap()
+                .to_string(),
+            "login.response.headers[\"set-cookie\"]"
+        );
+        assert_eq!(
+            Expr::parse_all("regex( login.response.body , \"id=(\\d+)\", 1)"),
+            Ok(Expr::Call(Call {
+                function: "regex",
+                args: vec![
+                    Expr::Reference(Reference {
+                        step: "login",
+                        path: vec![Accessor::Field("response"), Accessor::Field("body")],
+                    }),
+                    Expr::String("id=(\\d+)"),
+                    Expr::Int(1),
+                ],
+            }))
+        );
+        assert_eq!(
+            Expr::parse_all("sha256(base64(login))")
+                .unwrap()
+                .to_string(),
+            "sha256(base64(login))"
+        );
+        assert!(Expr::parse_all("json(login,)").is_err());
+    }
+}
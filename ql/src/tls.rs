@@ -0,0 +1,83 @@
+use nom::error::ErrorKind;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TLSOptions<'a> {
+    pub verify: TLSVerify<'a>,
+}
+
+impl<'a> TLSOptions<'a> {
+    pub fn parse(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Option<Self>, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = None;
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "verify" => {
+                    let tls = result.get_or_insert_with(Self::default);
+                    tls.verify = match val {
+                        "system" => TLSVerify::System,
+                        "insecure" => TLSVerify::Insecure,
+                        _ => return Err(option_error(val)),
+                    };
+                }
+                "ca" => {
+                    let tls = result.get_or_insert_with(Self::default);
+                    tls.verify = TLSVerify::CA(val);
+                }
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum TLSVerify<'a> {
+    /// Verify the server against the operating system's trusted roots.
+    #[default]
+    System,
+    /// Verify the server against the PEM encoded certificates in a file.
+    CA(&'a str),
+    /// Don't verify the server's certificate at all.
+    Insecure,
+}
+
+pub(crate) fn option_error(input: &str) -> nom::Err<nom::error::Error<&str>> {
+    nom::Err::Error(nom::error::Error {
+        input,
+        code: ErrorKind::Tag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_options_test() {
+        assert_eq!(TLSOptions::parse(&mut Vec::new()), Ok(None));
+        let mut options = vec![("verify", "insecure"), ("other", "val")];
+        assert_eq!(
+            TLSOptions::parse(&mut options),
+            Ok(Some(TLSOptions {
+                verify: TLSVerify::Insecure
+            }))
+        );
+        assert_eq!(options, vec![("other", "val")]);
+        assert_eq!(
+            TLSOptions::parse(&mut vec![("ca", "./ca.pem")]),
+            Ok(Some(TLSOptions {
+                verify: TLSVerify::CA("./ca.pem")
+            }))
+        );
+        assert_eq!(
+            TLSOptions::parse(&mut vec![("verify", "maybe")]),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "maybe",
+                ErrorKind::Tag
+            )))
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use nom::{
+    character::complete::{line_ending, not_line_ending},
+    sequence::terminated,
+    IResult,
+};
+
+use crate::tls::option_error;
+use crate::util::{block, duration};
+use crate::Template;
+
+/// Datagrams sent to a UDP address, one per line of the step's body.
+#[derive(Debug, PartialEq)]
+pub struct UDPRequest<'a> {
+    /// The `host:port` to send to.
+    pub address: Template<'a>,
+    /// The payload of each datagram. Literal text may use `\r`, `\n`, `\t`, `\0`, `\\` and `\xNN`
+    /// escapes, so a datagram can hold line breaks and binary data.
+    pub datagrams: Vec<Template<'a>>,
+    pub options: UDPOptions,
+}
+
+impl<'a> UDPRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        let (input, address) = terminated(not_line_ending, line_ending)(input)?;
+        let (input, body) = block(input, eof)?;
+
+        // Blank lines are skipped rather than sent as empty datagrams.
+        let mut datagrams = Vec::new();
+        for line in body.lines().filter(|line| !line.is_empty()) {
+            datagrams.push(Template::parse_escaped(line)?.1);
+        }
+
+        Ok((
+            input,
+            UDPRequest {
+                address: Template::parse(address)?.1,
+                datagrams,
+                options: UDPOptions::default(),
+            },
+        ))
+    }
+}
+
+/// When to stop waiting for replies.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UDPOptions {
+    /// The number of datagrams to receive. Defaults to one for each datagram sent.
+    pub count: Option<usize>,
+    /// Stop waiting after this long, keeping whatever was received so far.
+    pub timeout: Option<Duration>,
+}
+
+impl UDPOptions {
+    pub fn parse<'a>(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = Self::default();
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "count" => result.count = Some(val.parse().map_err(|_| option_error(val))?),
+                "timeout" => result.timeout = Some(duration(val).ok_or_else(|| option_error(val))?),
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::error::ErrorKind;
+
+    use super::*;
+
+    #[test]
+    fn udp_test() {
+        assert_eq!(
+            UDPRequest::parse("127.0.0.1:53\nfirst\\r\\n\n\n\\x00\\x01\nEOF", "EOF"),
+            Ok((
+                "",
+                UDPRequest {
+                    address: Template::literal("127.0.0.1:53"),
+                    datagrams: vec![
+                        Template::literal("first\\r\\n"),
+                        Template::literal("\\x00\\x01"),
+                    ],
+                    options: UDPOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            UDPRequest::parse("127.0.0.1:53\nEOF", "EOF"),
+            Ok((
+                "",
+                UDPRequest {
+                    address: Template::literal("127.0.0.1:53"),
+                    datagrams: Vec::new(),
+                    options: UDPOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            UDPRequest::parse("127.0.0.1:53\nbad \\q\nEOF", "EOF"),
+            Err(nom::Err::Error(nom::error::Error {
+                input: "bad \\q",
+                code: ErrorKind::Escaped,
+            }))
+        );
+    }
+
+    #[test]
+    fn udp_options_test() {
+        let mut options = vec![("count", "2"), ("timeout", "1s"), ("until", "close")];
+        assert_eq!(
+            UDPOptions::parse(&mut options),
+            Ok(UDPOptions {
+                count: Some(2),
+                timeout: Some(Duration::from_secs(1)),
+            })
+        );
+        assert_eq!(options, vec![("until", "close")]);
+        assert_eq!(
+            UDPOptions::parse(&mut vec![("count", "many")]),
+            Err(option_error("many"))
+        );
+    }
+}
@@ -1,5 +1,157 @@
-use nom::{bytes::complete::take_while1, IResult};
+use std::time::Duration;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag, take_until, take_while1},
+    character::complete::space0,
+    combinator::value,
+    multi::separated_list0,
+    sequence::{delimited, pair, separated_pair, terminated, tuple},
+    IResult,
+};
 
 pub fn ident(input: &str) -> IResult<&str, &str> {
     take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)
 }
+
+/// Parse a parenthesized list of step options, like `(verify=insecure, ca="./ca.pem")`.
+pub fn options(input: &str) -> IResult<&str, Vec<(&str, &str)>> {
+    delimited(
+        pair(tag("("), space0),
+        separated_list0(pair_tag(","), option),
+        pair(space0, tag(")")),
+    )(input)
+}
+
+fn option(input: &str) -> IResult<&str, (&str, &str)> {
+    separated_pair(option_key, pair_tag("="), option_val)(input)
+}
+
+fn option_key(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')(input)
+}
+
+fn option_val(input: &str) -> IResult<&str, &str> {
+    alt((
+        delimited(tag("\""), is_not("\"\r\n"), tag("\"")),
+        is_not(",) \t\r\n"),
+    ))(input)
+}
+
+/// Replace backslash escapes in an option value: `\r`, `\n`, `\t`, `\0`, `\\` and `\xNN` for a
+/// byte in hex. Returns `None` for an unknown or incomplete escape.
+pub fn unescape(input: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut bytes = input.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'r' => b'\r',
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'0' => b'\0',
+            b'\\' => b'\\',
+            b'x' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// Parse a duration with a unit, like `500ms`, `2s` or `1m`.
+pub fn duration(input: &str) -> Option<Duration> {
+    let split = input.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = input.split_at(split);
+    let count = count.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(count)),
+        "s" => Some(Duration::from_secs(count)),
+        "m" => Some(Duration::from_secs(count.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+/// Parse `true` or `false`.
+pub fn boolean(input: &str) -> Option<bool> {
+    match input {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Take the lines up to the eof token on its own line, allowing either line ending before it.
+/// The block is empty when the eof token comes first.
+pub fn block<'a>(input: &'a str, eof: &str) -> IResult<&'a str, &'a str> {
+    let crlf_eof = format!("\r\n{}", eof);
+    let result = alt((
+        value("", tag(eof)),
+        terminated(take_until(crlf_eof.as_str()), tag(crlf_eof.as_str())),
+        terminated(take_until(&crlf_eof[1..]), tag(&crlf_eof[1..])),
+    ))(input);
+    result
+}
+
+/// Match a tag with optional surrounding spaces.
+fn pair_tag<'a>(t: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |input| {
+        let (input, (_, t, _)) = tuple((space0, tag(t), space0))(input)?;
+        Ok((input, t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_test() {
+        assert_eq!(options("()"), Ok(("", Vec::new())));
+        assert_eq!(
+            options("(verify=insecure, ca=\"./my ca.pem\") rest"),
+            Ok((" rest", vec![("verify", "insecure"), ("ca", "./my ca.pem")]))
+        );
+        assert_eq!(
+            options("(verify)"),
+            Err(nom::Err::Error(nom::error::Error::new(
+                "verify)",
+                nom::error::ErrorKind::Tag,
+            )))
+        );
+    }
+
+    #[test]
+    fn block_test() {
+        assert_eq!(block("a\nb\r\nEOF\nrest", "EOF"), Ok(("\nrest", "a\nb")));
+        assert_eq!(block("EOF", "EOF"), Ok(("", "")));
+        assert!(block("a\nb", "EOF").is_err());
+    }
+
+    #[test]
+    fn unescape_test() {
+        assert_eq!(
+            unescape("a\\r\\n\\x00\\xfF\\\\"),
+            Some(b"a\r\n\0\xff\\".to_vec())
+        );
+        assert_eq!(unescape("\\q"), None);
+        assert_eq!(unescape("\\x4"), None);
+    }
+
+    #[test]
+    fn duration_test() {
+        assert_eq!(duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(duration("2s"), Some(Duration::from_secs(2)));
+        assert_eq!(duration("1m"), Some(Duration::from_secs(60)));
+        assert_eq!(duration("5"), None);
+        assert_eq!(duration("s"), None);
+    }
+}
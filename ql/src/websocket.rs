@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{digit1, space1},
+    combinator::{all_consuming, eof, map, map_res, opt, value},
+    sequence::{pair, preceded},
+    IResult,
+};
+
+use crate::tls::option_error;
+use crate::util::{block, duration};
+use crate::{HTTPRequest, Template};
+
+/// An HTTP/1.1 upgrade request followed by a script of messages to send once the connection has
+/// switched to WebSockets.
+#[derive(Debug, PartialEq)]
+pub struct WebSocketRequest<'a> {
+    pub upgrade: HTTPRequest<'a>,
+    pub messages: Vec<WebSocketMessage<'a>>,
+    pub options: WebSocketOptions,
+}
+
+impl<'a> WebSocketRequest<'a> {
+    pub fn parse(input: &'a str, eof: &str) -> IResult<&'a str, Self> {
+        let (input, upgrade) = HTTPRequest::parse_head(input)?;
+        let (input, script) = block(input, eof)?;
+        let messages = script
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| Ok(all_consuming(WebSocketMessage::parse)(line)?.1))
+            .collect::<Result<_, _>>()?;
+        Ok((
+            input,
+            WebSocketRequest {
+                upgrade,
+                messages,
+                options: WebSocketOptions::default(),
+            },
+        ))
+    }
+}
+
+/// A frame to send, written as a line like `text hello`, `ping` or `close 1000 done`. Payloads
+/// are templates which may use the same escapes as UDP datagrams.
+#[derive(Debug, PartialEq)]
+pub enum WebSocketMessage<'a> {
+    Text(Template<'a>),
+    Binary(Template<'a>),
+    Ping(Template<'a>),
+    Pong(Template<'a>),
+    /// A close frame with an optional status code and reason.
+    Close(Option<u16>, Template<'a>),
+}
+
+impl<'a> WebSocketMessage<'a> {
+    pub fn parse(input: &'a str) -> IResult<&'a str, Self> {
+        alt((
+            map(pair(tag("text"), payload), |(_, p)| Self::Text(p)),
+            map(pair(tag("binary"), payload), |(_, p)| Self::Binary(p)),
+            map(pair(tag("ping"), payload), |(_, p)| Self::Ping(p)),
+            map(pair(tag("pong"), payload), |(_, p)| Self::Pong(p)),
+            map(
+                preceded(
+                    tag("close"),
+                    opt(pair(preceded(space1, map_res(digit1, str::parse)), payload)),
+                ),
+                |close| match close {
+                    Some((code, reason)) => Self::Close(Some(code), reason),
+                    None => Self::Close(None, Template::default()),
+                },
+            ),
+        ))(input)
+    }
+}
+
+/// An optional payload after a single space.
+fn payload(input: &str) -> IResult<&str, Template<'_>> {
+    alt((
+        preceded(tag(" "), Template::parse_escaped),
+        value(Template::default(), eof),
+    ))(input)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WebSocketOptions {
+    /// Stop reading frames after this long, keeping whatever was read so far.
+    pub timeout: Option<Duration>,
+}
+
+impl WebSocketOptions {
+    pub fn parse<'a>(
+        options: &mut Vec<(&'a str, &'a str)>,
+    ) -> Result<Self, nom::Err<nom::error::Error<&'a str>>> {
+        let mut result = Self::default();
+        let mut rest = Vec::new();
+        for (key, val) in options.drain(..) {
+            match key {
+                "timeout" => result.timeout = Some(duration(val).ok_or_else(|| option_error(val))?),
+                _ => rest.push((key, val)),
+            }
+        }
+        *options = rest;
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HTTPBody, HTTPOptions, Payload, Protocol};
+
+    #[test]
+    fn websocket_test() {
+        assert_eq!(
+            WebSocketRequest::parse(
+                "GET ws://localhost/chat\nOrigin: example.com\n\ntext hi there\nbinary \\x00\\x01\n\nping\nclose 1000 bye\nEOF",
+                "EOF"
+            ),
+            Ok((
+                "",
+                WebSocketRequest {
+                    upgrade: HTTPRequest {
+                        method: "GET",
+                        endpoint: Template::literal("ws://localhost/chat"),
+                        version: Protocol::HTTP1_1,
+                        headers: vec![("Origin", Template::literal("example.com"))],
+                        directives: Vec::new(),
+                        body: HTTPBody::Payload(Payload::Raw(Template::default())),
+                        tls: None,
+                        h2c: None,
+                        connection: None,
+                        options: HTTPOptions::default(),
+                    },
+                    messages: vec![
+                        WebSocketMessage::Text(Template::literal("hi there")),
+                        WebSocketMessage::Binary(Template::literal("\\x00\\x01")),
+                        WebSocketMessage::Ping(Template::default()),
+                        WebSocketMessage::Close(Some(1000), Template::literal("bye")),
+                    ],
+                    options: WebSocketOptions::default(),
+                }
+            ))
+        );
+        assert_eq!(
+            WebSocketRequest::parse("GET ws://localhost/\n\nEOF", "EOF")
+                .unwrap()
+                .1
+                .messages,
+            Vec::new()
+        );
+        assert!(WebSocketRequest::parse("GET ws://localhost/\n\nshout hi\nEOF", "EOF").is_err());
+    }
+
+    #[test]
+    fn websocket_message_test() {
+        assert_eq!(
+            WebSocketMessage::parse("close"),
+            Ok(("", WebSocketMessage::Close(None, Template::default())))
+        );
+        assert_eq!(
+            WebSocketMessage::parse("close 1001"),
+            Ok(("", WebSocketMessage::Close(Some(1001), Template::default())))
+        );
+        assert_eq!(
+            WebSocketMessage::parse("pong \\xff"),
+            Ok(("", WebSocketMessage::Pong(Template::literal("\\xff"))))
+        );
+        assert!(all_consuming(WebSocketMessage::parse)("texts").is_err());
+        assert!(WebSocketMessage::parse("text \\q").is_err());
+    }
+}